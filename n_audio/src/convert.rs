@@ -0,0 +1,494 @@
+//! Conversion of decoded audio to the format of the output device
+
+use std::f32::consts::FRAC_1_SQRT_2;
+
+use symphonia::core::audio::{AudioBuffer, Channels, Signal, SignalSpec};
+
+/// Zero crossings of the sinc on each side of the interpolated sample
+const HALF_TAPS: usize = 16;
+/// Number of precomputed fractional positions between two input samples
+const PHASES: usize = 256;
+
+/// Returns the channel layout usually associated with the given channel count
+pub fn channels_for_count(count: usize) -> Channels {
+    match count {
+        1 => Channels::FRONT_LEFT,
+        2 => Channels::FRONT_LEFT | Channels::FRONT_RIGHT,
+        3 => Channels::FRONT_LEFT | Channels::FRONT_RIGHT | Channels::FRONT_CENTRE,
+        4 => {
+            Channels::FRONT_LEFT
+                | Channels::FRONT_RIGHT
+                | Channels::REAR_LEFT
+                | Channels::REAR_RIGHT
+        }
+        5 => {
+            Channels::FRONT_LEFT
+                | Channels::FRONT_RIGHT
+                | Channels::FRONT_CENTRE
+                | Channels::REAR_LEFT
+                | Channels::REAR_RIGHT
+        }
+        6 => {
+            Channels::FRONT_LEFT
+                | Channels::FRONT_RIGHT
+                | Channels::FRONT_CENTRE
+                | Channels::LFE1
+                | Channels::REAR_LEFT
+                | Channels::REAR_RIGHT
+        }
+        7 => {
+            Channels::FRONT_LEFT
+                | Channels::FRONT_RIGHT
+                | Channels::FRONT_CENTRE
+                | Channels::LFE1
+                | Channels::REAR_CENTRE
+                | Channels::SIDE_LEFT
+                | Channels::SIDE_RIGHT
+        }
+        8 => {
+            Channels::FRONT_LEFT
+                | Channels::FRONT_RIGHT
+                | Channels::FRONT_CENTRE
+                | Channels::LFE1
+                | Channels::REAR_LEFT
+                | Channels::REAR_RIGHT
+                | Channels::SIDE_LEFT
+                | Channels::SIDE_RIGHT
+        }
+        _ => Channels::from_bits_truncate(((1u64 << count) - 1) as u32),
+    }
+}
+
+/// Band-limited resampler for a single channel, based on a windowed sinc interpolation
+pub struct Resampler {
+    /// Input rate divided by output rate
+    step: f64,
+    /// Filter coefficients for every phase, `2 * HALF_TAPS` each
+    table: Vec<f32>,
+    cutoff: f64,
+    /// Input samples still needed by the filter
+    history: Vec<f32>,
+    /// Position of the next output sample, relative to the start of `history`
+    time: f64,
+}
+
+impl Resampler {
+    pub fn new(in_rate: u32, out_rate: u32) -> Self {
+        let mut resampler = Resampler {
+            step: 1.0,
+            table: vec![],
+            cutoff: 0.0,
+            // Start with silence, so that the first samples can be interpolated as well
+            history: vec![0.0; HALF_TAPS - 1],
+            time: (HALF_TAPS - 1) as f64,
+        };
+        resampler.set_rates(in_rate, out_rate);
+        resampler
+    }
+
+    /// Changes the conversion ratio while keeping the signal continuous
+    pub fn set_rates(&mut self, in_rate: u32, out_rate: u32) {
+        self.step = in_rate as f64 / out_rate as f64;
+
+        // When downsampling everything above the new Nyquist frequency must be filtered out
+        let cutoff = (1.0 / self.step).min(1.0) * 0.95;
+        if cutoff != self.cutoff {
+            self.cutoff = cutoff;
+            self.table = Self::build_table(cutoff);
+        }
+    }
+
+    fn build_table(cutoff: f64) -> Vec<f32> {
+        let mut table = Vec::with_capacity((PHASES + 1) * 2 * HALF_TAPS);
+        for phase in 0..=PHASES {
+            let frac = phase as f64 / PHASES as f64;
+            for tap in 0..2 * HALF_TAPS {
+                let x = tap as f64 - (HALF_TAPS - 1) as f64 - frac;
+                let sinc = if x == 0.0 {
+                    1.0
+                } else {
+                    let arg = std::f64::consts::PI * x * cutoff;
+                    arg.sin() / arg
+                };
+                // Blackman window over the whole filter length
+                let n = (x / HALF_TAPS as f64 + 1.0) / 2.0;
+                let window = if (0.0..=1.0).contains(&n) {
+                    0.42 - 0.5 * (2.0 * std::f64::consts::PI * n).cos()
+                        + 0.08 * (4.0 * std::f64::consts::PI * n).cos()
+                } else {
+                    0.0
+                };
+                table.push((sinc * cutoff * window) as f32);
+            }
+        }
+        table
+    }
+
+    /// Resamples `input`, appending to `output` every sample that can be produced so far
+    pub fn process(&mut self, input: &[f32], output: &mut Vec<f32>) {
+        self.history.extend_from_slice(input);
+
+        let taps = 2 * HALF_TAPS;
+        while (self.time as usize) + HALF_TAPS < self.history.len() {
+            let index = self.time as usize;
+            let position = (self.time - index as f64) * PHASES as f64;
+            let phase = position as usize;
+            let weight = (position - phase as f64) as f32;
+
+            let samples = &self.history[index + 1 - HALF_TAPS..=index + HALF_TAPS];
+            let first = &self.table[phase * taps..(phase + 1) * taps];
+            let second = &self.table[(phase + 1) * taps..(phase + 2) * taps];
+
+            let mut sample = 0.0;
+            for ((x, a), b) in samples.iter().zip(first).zip(second) {
+                sample += x * (a + (b - a) * weight);
+            }
+            output.push(sample);
+
+            self.time += self.step;
+        }
+
+        // Keep only what the filter still needs
+        let used = (self.time as usize + 1).saturating_sub(HALF_TAPS);
+        self.history.drain(..used);
+        self.time -= used as f64;
+    }
+}
+
+/// Converts planar audio of any rate and channel count to interleaved audio for the output
+pub struct Converter {
+    out_spec: SignalSpec,
+    in_rate: u32,
+    in_channels: usize,
+    resamplers: Vec<Resampler>,
+    resampled: Vec<Vec<f32>>,
+    /// The input layout `mix` was made for
+    mix_from: Channels,
+    mix: Vec<Vec<f32>>,
+}
+
+impl Converter {
+    pub fn new(out_spec: SignalSpec) -> Self {
+        Converter {
+            out_spec,
+            in_rate: 0,
+            in_channels: 0,
+            resamplers: vec![],
+            resampled: vec![],
+            mix_from: Channels::empty(),
+            mix: vec![],
+        }
+    }
+
+    /// Drops the samples kept by the resamplers, used when the input isn't continuous anymore
+    pub fn reset(&mut self) {
+        self.in_channels = 0;
+    }
+
+    /// Converts `input`, which should be played back at `rate`, appending the interleaved samples to `output`
+    pub fn convert(&mut self, input: &AudioBuffer<f32>, rate: u32, output: &mut Vec<f32>) {
+        let channels = input.spec().channels.count();
+        // The resamplers aren't fed while the rates match, so what they kept from before isn't continuous anymore
+        let bypass_changed = (rate == self.out_spec.rate) != (self.in_rate == self.out_spec.rate);
+        if channels != self.in_channels || bypass_changed {
+            self.in_channels = channels;
+            self.in_rate = rate;
+            self.resamplers = (0..channels)
+                .map(|_| Resampler::new(rate, self.out_spec.rate))
+                .collect();
+            self.resampled = vec![vec![]; channels];
+        } else if rate != self.in_rate {
+            self.in_rate = rate;
+            for resampler in self.resamplers.iter_mut() {
+                resampler.set_rates(rate, self.out_spec.rate);
+            }
+        }
+
+        for (ch, (resampler, resampled)) in self
+            .resamplers
+            .iter_mut()
+            .zip(self.resampled.iter_mut())
+            .enumerate()
+        {
+            resampled.clear();
+            if rate == self.out_spec.rate {
+                resampled.extend_from_slice(input.chan(ch));
+            } else {
+                resampler.process(input.chan(ch), resampled);
+            }
+        }
+
+        let from = input.spec().channels;
+        if from == self.out_spec.channels {
+            interleave(&self.resampled, output);
+        } else {
+            if from != self.mix_from || self.mix.is_empty() {
+                self.mix_from = from;
+                self.mix = mix_matrix(from, self.out_spec.channels);
+            }
+            remix(&self.resampled, &self.mix, output);
+        }
+    }
+}
+
+/// Interleaves `input` into `output` as it is
+fn interleave(input: &[Vec<f32>], output: &mut Vec<f32>) {
+    let frames = input.iter().map(Vec::len).min().unwrap_or(0);
+    output.reserve(frames * input.len());
+    for i in 0..frames {
+        output.extend(input.iter().map(|ch| ch[i]));
+    }
+}
+
+/// Interleaves `input` into `output`, where each output channel is the sum of the input ones weighted by its row of `mix`
+fn remix(input: &[Vec<f32>], mix: &[Vec<f32>], output: &mut Vec<f32>) {
+    let frames = input.iter().map(Vec::len).min().unwrap_or(0);
+    output.reserve(frames * mix.len());
+    for i in 0..frames {
+        output.extend(mix.iter().map(|gains| {
+            gains
+                .iter()
+                .zip(input)
+                .map(|(gain, ch)| gain * ch[i])
+                .sum::<f32>()
+        }));
+    }
+}
+
+/// Where a channel the output doesn't have goes, in order of preference
+/// Each choice is used only when the output has all of its channels, the gain is applied to each of them
+fn fallbacks(channel: Channels) -> &'static [(Channels, f32)] {
+    const FRONT: Channels = Channels::FRONT_LEFT.union(Channels::FRONT_RIGHT);
+    const REAR: Channels = Channels::REAR_LEFT.union(Channels::REAR_RIGHT);
+    const SIDE: Channels = Channels::SIDE_LEFT.union(Channels::SIDE_RIGHT);
+    const HALF: f32 = 0.5;
+    match channel {
+        Channels::FRONT_LEFT | Channels::FRONT_RIGHT => &[(Channels::FRONT_CENTRE, 1.0)],
+        Channels::FRONT_CENTRE | Channels::FRONT_CENTRE_HIGH | Channels::TOP_FRONT_CENTRE => {
+            &[(Channels::FRONT_CENTRE, 1.0), (FRONT, FRAC_1_SQRT_2)]
+        }
+        Channels::TOP_CENTRE => &[(FRONT, FRAC_1_SQRT_2)],
+        Channels::LFE1 => &[(Channels::LFE2, 1.0)],
+        Channels::LFE2 => &[(Channels::LFE1, 1.0)],
+        Channels::FRONT_LEFT_CENTRE
+        | Channels::FRONT_LEFT_WIDE
+        | Channels::FRONT_LEFT_HIGH
+        | Channels::TOP_FRONT_LEFT => &[(Channels::FRONT_LEFT, 1.0)],
+        Channels::FRONT_RIGHT_CENTRE
+        | Channels::FRONT_RIGHT_WIDE
+        | Channels::FRONT_RIGHT_HIGH
+        | Channels::TOP_FRONT_RIGHT => &[(Channels::FRONT_RIGHT, 1.0)],
+        Channels::REAR_LEFT | Channels::REAR_LEFT_CENTRE | Channels::TOP_REAR_LEFT => &[
+            (Channels::REAR_LEFT, 1.0),
+            (Channels::SIDE_LEFT, 1.0),
+            (Channels::FRONT_LEFT, FRAC_1_SQRT_2),
+        ],
+        Channels::REAR_RIGHT | Channels::REAR_RIGHT_CENTRE | Channels::TOP_REAR_RIGHT => &[
+            (Channels::REAR_RIGHT, 1.0),
+            (Channels::SIDE_RIGHT, 1.0),
+            (Channels::FRONT_RIGHT, FRAC_1_SQRT_2),
+        ],
+        Channels::SIDE_LEFT => &[
+            (Channels::REAR_LEFT, 1.0),
+            (Channels::FRONT_LEFT, FRAC_1_SQRT_2),
+        ],
+        Channels::SIDE_RIGHT => &[
+            (Channels::REAR_RIGHT, 1.0),
+            (Channels::FRONT_RIGHT, FRAC_1_SQRT_2),
+        ],
+        Channels::REAR_CENTRE | Channels::TOP_REAR_CENTRE => &[
+            (Channels::REAR_CENTRE, 1.0),
+            (REAR, FRAC_1_SQRT_2),
+            (SIDE, FRAC_1_SQRT_2),
+            (FRONT, HALF),
+        ],
+        _ => &[],
+    }
+}
+
+/// Returns how much of every channel of `from` goes into every channel of `to`, indexed by output and then input channel
+///
+/// Channels in common are kept as they are, the others are folded onto the nearest ones following ITU-R BS.775,
+/// without the LFE; the output channels that would get louder than their inputs are scaled down
+fn mix_matrix(from: Channels, to: Channels) -> Vec<Vec<f32>> {
+    let outputs: Vec<Channels> = to.iter().collect();
+    let mut mix = vec![vec![0.0; from.count()]; outputs.len()];
+    let send = |mix: &mut Vec<Vec<f32>>, input: usize, channels: Channels, gain: f32| {
+        for (output, _) in outputs
+            .iter()
+            .enumerate()
+            .filter(|(_, output)| channels.contains(**output))
+        {
+            mix[output][input] += gain;
+        }
+    };
+
+    for (input, channel) in from.iter().enumerate() {
+        if outputs.len() == 1 {
+            // Everything but the LFE goes to the only speaker there is
+            if !channel.intersects(Channels::LFE1 | Channels::LFE2) || from.count() == 1 {
+                mix[0][input] = 1.0;
+            }
+        } else if from.count() == 1 {
+            // Mono goes to both front speakers, or to the centre one if that's all there is
+            let front = Channels::FRONT_LEFT | Channels::FRONT_RIGHT;
+            let target = if to.contains(front) {
+                front
+            } else {
+                Channels::FRONT_CENTRE
+            };
+            send(&mut mix, input, target, 1.0);
+        } else if to.contains(channel) {
+            send(&mut mix, input, channel, 1.0);
+        } else if let Some(&(target, gain)) = fallbacks(channel)
+            .iter()
+            .find(|(target, _)| to.contains(*target))
+        {
+            send(&mut mix, input, target, gain);
+        }
+    }
+
+    for gains in &mut mix {
+        let sum = gains.iter().sum::<f32>();
+        if sum > 1.0 {
+            gains.iter_mut().for_each(|gain| *gain /= sum);
+        }
+    }
+    mix
+}
+
+/// TPDF dither, added to the samples before they're quantized to fewer bits than they have
+/// so that the rounding error becomes a steady noise floor instead of distortion
+pub struct Dither {
+    /// One step of the quantized samples
+    step: f32,
+    state: u32,
+}
+
+impl Dither {
+    /// Returns `None` when samples quantized to `bits` don't lose any precision
+    pub fn for_bits(bits: u32) -> Option<Self> {
+        (bits < f32::MANTISSA_DIGITS).then(|| Dither {
+            step: 1.0 / (1u64 << (bits - 1)) as f32,
+            state: 0x9e37_79b9,
+        })
+    }
+
+    /// Returns the noise to add to the next sample, between minus and plus one step
+    pub fn noise(&mut self) -> f32 {
+        (self.random() - self.random()) * self.step
+    }
+
+    /// Xorshift, between `0.0` and `1.0`
+    fn random(&mut self) -> f32 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 17;
+        self.state ^= self.state << 5;
+        (self.state >> 8) as f32 / (1 << 24) as f32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use symphonia::core::audio::Layout;
+
+    /// Converts 1s of a 1kHz sine on every input channel, fed in packets of 1152 frames, to `out_spec`
+    fn convert_sine(in_rate: u32, in_layout: Layout, out_spec: SignalSpec) -> Vec<f32> {
+        let spec = SignalSpec::new_with_layout(in_rate, in_layout);
+        let mut converter = Converter::new(out_spec);
+        let mut output = vec![];
+        for packet in 0..in_rate as usize / 1152 {
+            let mut input = AudioBuffer::<f32>::new(1152, spec);
+            input.render_reserved(Some(1152));
+            for ch in 0..spec.channels.count() {
+                for (i, sample) in input.chan_mut(ch).iter_mut().enumerate() {
+                    let t = (packet * 1152 + i) as f32 / in_rate as f32;
+                    *sample = (t * 1000.0 * std::f32::consts::TAU).sin() * 0.5;
+                }
+            }
+            converter.convert(&input, in_rate, &mut output);
+        }
+        output
+    }
+
+    /// Returns the frequency of a sine from the time between its first and last rising zero crossings
+    fn frequency(samples: &[f32], rate: u32) -> f64 {
+        let crossings: Vec<f64> = samples
+            .windows(2)
+            .enumerate()
+            .filter(|(_, pair)| pair[0] < 0.0 && pair[1] >= 0.0)
+            .map(|(i, pair)| i as f64 + (pair[0] / (pair[0] - pair[1])) as f64)
+            .collect();
+        let periods = (crossings.len() - 1) as f64;
+        periods * rate as f64 / (crossings[crossings.len() - 1] - crossings[0])
+    }
+
+    #[test]
+    fn resampling_keeps_duration_and_pitch() {
+        let out_spec = SignalSpec::new_with_layout(48000, Layout::Stereo);
+        let output = convert_sine(44100, Layout::Stereo, out_spec);
+
+        let in_frames = 44100 / 1152 * 1152;
+        let expected = in_frames as f64 * 48000.0 / 44100.0;
+        let frames = output.len() / 2;
+        // The resampler holds back the samples its filter still needs
+        assert!(
+            (frames as f64 - expected).abs() <= 2.0 * HALF_TAPS as f64,
+            "{frames} frames instead of {expected}"
+        );
+
+        // The start is skipped, as the filter begins from silence
+        let left: Vec<f32> = output
+            .iter()
+            .step_by(2)
+            .skip(2 * HALF_TAPS)
+            .copied()
+            .collect();
+        let frequency = frequency(&left, 48000);
+        assert!(
+            (frequency - 1000.0).abs() < 1.0,
+            "{frequency}Hz instead of 1000Hz"
+        );
+    }
+
+    #[test]
+    fn mono_goes_equally_to_both_front_channels() {
+        for layout in [Layout::Stereo, Layout::FivePointOne] {
+            let out_spec = SignalSpec::new_with_layout(44100, layout);
+            let channels = out_spec.channels.count();
+            let output = convert_sine(44100, Layout::Mono, out_spec);
+            let input = convert_sine(
+                44100,
+                Layout::Mono,
+                SignalSpec::new_with_layout(44100, Layout::Mono),
+            );
+
+            assert_eq!(output.len() / channels, input.len());
+            for (frame, sample) in output.chunks(channels).zip(input) {
+                // Both front channels come first, in the order of `Channels`
+                assert_eq!(frame[0], sample);
+                assert_eq!(frame[1], sample);
+                assert!(frame[2..].iter().all(|&other| other == 0.0));
+            }
+        }
+    }
+
+    #[test]
+    fn resampling_again_after_a_bypass_starts_afresh() {
+        let spec = SignalSpec::new_with_layout(48000, Layout::Mono);
+        let mut converter = Converter::new(spec);
+        let mut output = vec![];
+        // As when the tape speed goes from 1.25x to 1.0x and back, each time with a different level
+        for (rate, level) in [(60000, 1.0), (48000, 0.5), (60000, 0.25)] {
+            let mut input = AudioBuffer::<f32>::new(1152, spec);
+            input.render_reserved(Some(1152));
+            input.chan_mut(0).fill(level);
+            output.clear();
+            converter.convert(&input, rate, &mut output);
+        }
+
+        // Nothing from before the bypass comes out, only the last level (with some ringing)
+        assert!(output.iter().all(|&sample| sample < 0.3), "{output:?}");
+    }
+}
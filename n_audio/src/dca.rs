@@ -1,19 +1,39 @@
-use audiopus::SampleRate;
+use audiopus::coder::Encoder;
+use audiopus::{
+    Application, Bitrate, Channels as OpusChannels, Error as OpusError, ErrorCode, SampleRate,
+};
 use serde::{Deserialize, Serialize};
-use std::io::{Seek, SeekFrom};
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
 use symphonia::core::{
-    codecs::{CodecParameters, CODEC_TYPE_OPUS},
+    audio::{AudioBuffer, Channels, Signal, SignalSpec},
+    codecs::{CodecParameters, DecoderOptions, CODEC_TYPE_OPUS},
     errors::{self as symph_err, Error as SymphError, Result as SymphResult, SeekErrorKind},
     formats::prelude::*,
     io::{MediaSource, MediaSourceStream, ReadBytes, SeekBuffered},
-    meta::{Metadata as SymphMetadata, MetadataBuilder, MetadataLog, StandardTagKey, Tag, Value},
+    meta::{
+        Metadata as SymphMetadata, MetadataBuilder, MetadataLog, StandardTagKey, StandardVisualKey,
+        Tag, Value, Visual,
+    },
     probe::{Descriptor, Instantiate, QueryDescriptor},
     sample::SampleFormat,
     units::TimeStamp,
 };
 
+use crate::convert::{channels_for_count, Converter};
+use crate::music_track::MusicTrack;
+use crate::{NError, CODEC_REGISTRY};
+
 // Original code from the Songbird project
 
+/// DCA files are always at 48kHz, as Opus
+const RATE: u32 = 48000;
+/// The biggest packet libopus can make, as recommended by its docs
+const MAX_PACKET: usize = 4000;
+/// How long a packet is taken to be when the one before it isn't known, 20ms
+const DEFAULT_DUR: u64 = 960;
+/// The durations an Opus packet can have, from 2.5ms to 60ms
+const FRAME_SIZES: [usize; 6] = [120, 240, 480, 960, 1920, 2880];
+
 #[derive(Debug, Deserialize, Serialize)]
 pub struct DcaMetadata {
     pub dca: DcaInfo,
@@ -66,6 +86,131 @@ pub struct Origin {
     pub url: Option<String>,
 }
 
+/// Returns how many frames `packet` holds, or `last_dur` (the duration of the packet before it) if it's empty or corrupt
+/// The first two bytes of the packet are enough
+fn packet_dur(packet: &[u8], last_dur: u64) -> u64 {
+    audiopus::packet::Packet::try_from(packet)
+        .and_then(|packet| audiopus::packet::nb_samples(packet, SampleRate::Hz48000))
+        .map_or(last_dur, |sample_ct| sample_ct as u64)
+}
+
+/// Walks the frame headers from the current position until the end, filling `seek_accel`, and returns how many frames there are
+/// A packet cut short by the end of the file isn't counted, as it can't be read either
+fn scan(source: &mut MediaSourceStream, seek_accel: &mut SeekAccel) -> SymphResult<u64> {
+    let mut ts = 0;
+    let mut last_dur = DEFAULT_DUR;
+    loop {
+        let frame_pos = source.pos();
+        let head = source.read_u16().and_then(|len| {
+            if (len as i16) < 0 {
+                return Ok(None);
+            }
+            // Only the TOC byte and the frame count are needed, the rest is skipped
+            let head = source.read_boxed_slice_exact(len.min(2) as usize)?;
+            source.ignore_bytes(len as u64 - head.len() as u64)?;
+            Ok(Some(head))
+        });
+        let head = match head {
+            Ok(Some(head)) => head,
+            Ok(None) => return symph_err::decode_error("DCA frame header had a negative length."),
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(err) => return Err(err.into()),
+        };
+
+        last_dur = packet_dur(&head, last_dur);
+        seek_accel.update(ts, frame_pos);
+        ts += last_dur;
+    }
+    Ok(ts)
+}
+
+/// Reads the cover of a DCA1 file, written as a base64 image (also as a `data:` URI)
+/// Covers given as paths or URLs are left out, so that opening a file never reads anything else
+fn read_cover(cover: &str) -> Option<Visual> {
+    let cover = cover.trim();
+    let (media_type, data) = if let Some(uri) = cover.strip_prefix("data:") {
+        let (header, data) = uri.split_once(',')?;
+        let media_type = header.strip_suffix(";base64")?;
+        let data = decode_base64(data)?;
+        let media_type = match media_type {
+            "" => sniff_image(&data)?.to_string(),
+            media_type => media_type.to_string(),
+        };
+        (media_type, data)
+    } else {
+        let data = decode_base64(cover)?;
+        (sniff_image(&data)?.to_string(), data)
+    };
+
+    Some(Visual {
+        media_type,
+        dimensions: None,
+        bits_per_pixel: None,
+        color_mode: None,
+        usage: Some(StandardVisualKey::FrontCover),
+        tags: vec![],
+        data: data.into_boxed_slice(),
+    })
+}
+
+/// Returns the MIME type of an image from its first bytes
+fn sniff_image(data: &[u8]) -> Option<&'static str> {
+    match data {
+        [0xff, 0xd8, 0xff, ..] => Some("image/jpeg"),
+        [0x89, b'P', b'N', b'G', ..] => Some("image/png"),
+        [b'G', b'I', b'F', b'8', ..] => Some("image/gif"),
+        [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'E', b'B', b'P', ..] => Some("image/webp"),
+        [b'B', b'M', ..] => Some("image/bmp"),
+        _ => None,
+    }
+}
+
+/// Decodes standard or URL-safe base64, the padding and any whitespace are optional
+fn decode_base64(text: &str) -> Option<Vec<u8>> {
+    let mut data = Vec::with_capacity(text.len() / 4 * 3);
+    let mut bits = 0u32;
+    let mut count = 0;
+    for byte in text.bytes().filter(|byte| !byte.is_ascii_whitespace()) {
+        let value = match byte {
+            b'A'..=b'Z' => byte - b'A',
+            b'a'..=b'z' => byte - b'a' + 26,
+            b'0'..=b'9' => byte - b'0' + 52,
+            b'+' | b'-' => 62,
+            b'/' | b'_' => 63,
+            b'=' => break,
+            _ => return None,
+        };
+        bits = (bits << 6) | value as u32;
+        count += 6;
+        if count >= 8 {
+            count -= 8;
+            data.push((bits >> count) as u8);
+            bits &= (1 << count) - 1;
+        }
+    }
+    Some(data)
+}
+
+/// Encodes `data` as standard base64, with padding
+fn encode_base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut text = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let bits = chunk
+            .iter()
+            .fold(0u32, |bits, &byte| (bits << 8) | byte as u32)
+            << (8 * (3 - chunk.len()));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                text.push(ALPHABET[(bits >> (18 - 6 * i)) as usize & 63] as char);
+            } else {
+                text.push('=');
+            }
+        }
+    }
+    text
+}
+
 impl QueryDescriptor for DcaReader {
     fn query() -> &'static [Descriptor] {
         &[symphonia_core::support_format!(
@@ -122,6 +267,8 @@ pub struct DcaReader {
     curr_ts: TimeStamp,
     max_ts: Option<TimeStamp>,
     held_packet: Option<Packet>,
+    /// Duration of the last packet, see `DcaReader::next_packet`
+    last_dur: u64,
 }
 
 impl FormatReader for DcaReader {
@@ -168,6 +315,11 @@ impl FormatReader for DcaReader {
             let metadata: DcaMetadata = serde_json::from_slice::<DcaMetadata>(&mut raw_json)
                 .map_err(|_| SymphError::DecodeError("malformed DCA1 metadata block"))?;
 
+            codec_params.with_channels(match metadata.opus.channels {
+                1 => Channels::FRONT_LEFT,
+                _ => Channels::FRONT_LEFT | Channels::FRONT_RIGHT,
+            });
+
             let mut revision = MetadataBuilder::new();
 
             if let Some(info) = metadata.info {
@@ -206,8 +358,8 @@ impl FormatReader for DcaReader {
                         Value::String(t),
                     ));
                 }
-                if let Some(_t) = info.cover {
-                    // TODO: Add visual, figure out MIME types.
+                if let Some(visual) = info.cover.as_deref().and_then(read_cover) {
+                    revision.add_visual(visual);
                 }
             }
 
@@ -221,6 +373,16 @@ impl FormatReader for DcaReader {
         }
 
         let bytes_read = source.pos();
+        let mut seek_accel = SeekAccel::new(*options, bytes_read);
+        let mut max_ts = None;
+
+        // Knowing the length needs a walk through the whole file, so it's only done when asked
+        if options.prebuild_seek_index && source.is_seekable() {
+            let frames = scan(&mut source, &mut seek_accel)?;
+            source.seek(SeekFrom::Start(bytes_read))?;
+            codec_params.with_n_frames(frames);
+            max_ts = Some(frames);
+        }
 
         Ok(Self {
             source,
@@ -230,10 +392,11 @@ impl FormatReader for DcaReader {
                 codec_params,
             }),
             metas,
-            seek_accel: SeekAccel::new(*options, bytes_read),
+            seek_accel,
             curr_ts: 0,
-            max_ts: None,
+            max_ts,
             held_packet: None,
+            last_dur: DEFAULT_DUR,
         })
     }
 
@@ -340,16 +503,9 @@ impl FormatReader for DcaReader {
 
         let buf = self.source.read_boxed_slice_exact(p_len as usize)?;
 
-        let checked_buf = buf[..].try_into().or_else(|_| {
-            symph_err::decode_error("Packet was not a valid Opus Packet: too large for audiopus.")
-        })?;
-
-        let sample_ct =
-            audiopus::packet::nb_samples(checked_buf, SampleRate::Hz48000).or_else(|_| {
-                symph_err::decode_error(
-                    "Packet was not a valid Opus packet: couldn't read sample count.",
-                )
-            })? as u64;
+        // Empty or corrupt packets are still given to the decoder, which conceals them
+        let sample_ct = packet_dur(&buf, self.last_dur);
+        self.last_dur = sample_ct;
 
         let out = Packet::new_from_boxed_slice(0, self.curr_ts, sample_ct, buf);
 
@@ -364,3 +520,183 @@ impl FormatReader for DcaReader {
         self.source
     }
 }
+
+/// Encodes tracks to DCA1 files, which can be read back with [`DcaReader`]
+///
+/// ```no_run
+/// use std::fs::File;
+/// use n_audio::dca::DcaWriter;
+/// use n_audio::music_track::MusicTrack;
+///
+/// let track = MusicTrack::new("track.flac").unwrap();
+/// let file = File::create("track.dca").unwrap();
+/// DcaWriter::new().with_bitrate(96000).write(&track, file).unwrap();
+/// ```
+#[derive(Copy, Clone, Debug)]
+pub struct DcaWriter {
+    /// In bits per second, `None` lets libopus choose
+    bitrate: Option<u32>,
+    frame_size: usize,
+}
+
+impl Default for DcaWriter {
+    fn default() -> Self {
+        DcaWriter {
+            bitrate: None,
+            frame_size: 960,
+        }
+    }
+}
+
+impl DcaWriter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the bitrate in bits per second, by default libopus chooses one from the number of channels
+    pub fn with_bitrate(mut self, bitrate: u32) -> Self {
+        self.bitrate = Some(bitrate);
+        self
+    }
+
+    /// Sets how many frames go in a packet: 120, 240, 480, 960 (the default), 1920 or 2880
+    pub fn with_frame_size(mut self, frame_size: usize) -> Self {
+        self.frame_size = frame_size;
+        self
+    }
+
+    /// Decodes `track` with the same codecs used by the `Player` and writes it to `out`, returning how many frames were written
+    /// The audio is resampled to 48kHz and mixed down to stereo if it has more channels, the last packet is padded with silence
+    /// The tags and the front cover of the track are written in the header
+    pub fn write<W: Write>(&self, track: &MusicTrack, out: W) -> Result<u64, NError> {
+        if !FRAME_SIZES.contains(&self.frame_size) {
+            return Err(NError::Encode(OpusError::Opus(ErrorCode::BadArgument)));
+        }
+
+        // The title made up from the file name isn't a tag
+        let meta = track.read_meta()?;
+        let mut format = track.get_format()?;
+        let default_track = format.default_track().ok_or(NError::NoPlayableTrack)?;
+        let track_id = default_track.id;
+        let mut decoder = CODEC_REGISTRY
+            .make(&default_track.codec_params, &DecoderOptions::default())
+            .map_err(NError::Decoder)?;
+
+        let channels = if meta.channels == Some(1) { 1 } else { 2 };
+        let opus_channels = if channels == 1 {
+            OpusChannels::Mono
+        } else {
+            OpusChannels::Stereo
+        };
+        let mut encoder = Encoder::new(SampleRate::Hz48000, opus_channels, Application::Audio)
+            .map_err(NError::Encode)?;
+        if let Some(bitrate) = self.bitrate {
+            encoder
+                .set_bitrate(Bitrate::BitsPerSecond(bitrate as i32))
+                .map_err(NError::Encode)?;
+        }
+
+        let metadata = DcaMetadata {
+            dca: DcaInfo {
+                version: 1,
+                tool: Tool {
+                    name: env!("CARGO_PKG_NAME").to_string(),
+                    version: env!("CARGO_PKG_VERSION").to_string(),
+                    url: Some(env!("CARGO_PKG_HOMEPAGE").to_string()),
+                    author: Some(env!("CARGO_PKG_AUTHORS").to_string()),
+                },
+            },
+            opus: Opus {
+                mode: "music".to_string(),
+                sample_rate: RATE,
+                frame_size: self.frame_size as u64,
+                abr: self.bitrate.map(u64::from),
+                vbr: true,
+                channels: channels as u8,
+            },
+            info: Some(Info {
+                title: Some(meta.title).filter(|title| !title.is_empty()),
+                artist: Some(meta.artist).filter(|artist| !artist.is_empty()),
+                album: meta.album,
+                genre: meta.genre,
+                cover: meta
+                    .pictures
+                    .iter()
+                    .find(|picture| picture.front_cover)
+                    .map(|picture| encode_base64(&picture.data)),
+                comments: meta.comment,
+            }),
+            origin: Some(Origin {
+                source: Some("file".to_string()),
+                abr: meta.bitrate.map(u64::from),
+                channels: meta.channels.map(|channels| channels as u8),
+                encoding: meta.codec,
+                url: None,
+            }),
+            extra: None,
+        };
+        let json = serde_json::to_vec(&metadata).map_err(io::Error::from)?;
+
+        let mut out = BufWriter::new(out);
+        out.write_all(b"DCA1")?;
+        out.write_all(&(json.len() as u32).to_le_bytes())?;
+        out.write_all(&json)?;
+
+        let mut converter = Converter::new(SignalSpec::new(RATE, channels_for_count(channels)));
+        let mut buf = AudioBuffer::<f32>::unused();
+        let mut samples = vec![];
+        let mut packet = [0; MAX_PACKET];
+        let mut frames = 0;
+        let chunk = self.frame_size * channels;
+        let mut write_packet = |input: &[f32], out: &mut BufWriter<W>| -> Result<(), NError> {
+            let len = encoder
+                .encode_float(input, &mut packet)
+                .map_err(NError::Encode)?;
+            out.write_all(&(len as u16).to_le_bytes())?;
+            out.write_all(&packet[..len])?;
+            frames += self.frame_size as u64;
+            Ok(())
+        };
+
+        loop {
+            let packet = match format.next_packet() {
+                Ok(packet) => packet,
+                Err(SymphError::IoError(err)) if err.kind() == io::ErrorKind::UnexpectedEof => {
+                    break
+                }
+                Err(SymphError::ResetRequired) => break,
+                Err(err) => return Err(NError::Decode(err)),
+            };
+            if packet.track_id() != track_id {
+                continue;
+            }
+
+            match decoder.decode(&packet) {
+                Ok(decoded) => {
+                    if buf.capacity() < decoded.capacity() || buf.spec() != decoded.spec() {
+                        buf = decoded.make_equivalent();
+                    }
+                    decoded.convert(&mut buf);
+                    buf.trim(packet.trim_start() as usize, packet.trim_end() as usize);
+                    converter.convert(&buf, buf.spec().rate, &mut samples);
+                }
+                // A corrupted packet can be skipped
+                Err(SymphError::DecodeError(_)) => {}
+                Err(err) => return Err(NError::Decode(err)),
+            }
+
+            let whole = samples.len() / chunk * chunk;
+            for input in samples[..whole].chunks_exact(chunk) {
+                write_packet(input, &mut out)?;
+            }
+            samples.drain(..whole);
+        }
+        if !samples.is_empty() {
+            samples.resize(chunk, 0.0);
+            write_packet(&samples, &mut out)?;
+        }
+
+        out.flush()?;
+        Ok(frames)
+    }
+}
@@ -0,0 +1,69 @@
+//! Enumeration of the audio hosts and output devices available on the system
+
+use cpal::traits::{DeviceTrait, HostTrait};
+
+/// An audio output device, as given by one of the hosts of the system
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OutputDevice {
+    pub host: String,
+    pub name: String,
+}
+
+/// Returns the names of the audio hosts available on the system (e.g. ALSA, JACK, WASAPI)
+pub fn hosts() -> Vec<String> {
+    cpal::available_hosts()
+        .into_iter()
+        .map(|id| id.name().to_string())
+        .collect()
+}
+
+/// Returns every output device of every available host, those of the default host first
+pub fn output_devices() -> Vec<OutputDevice> {
+    let mut devices = vec![];
+    for (host, device) in all_devices() {
+        if let Ok(name) = device.name() {
+            let device = OutputDevice {
+                host: host.clone(),
+                name,
+            };
+            if !devices.contains(&device) {
+                devices.push(device);
+            }
+        }
+    }
+    devices
+}
+
+/// Returns the device the system would use by default
+pub fn default_output_device() -> Option<OutputDevice> {
+    let host = cpal::default_host();
+    let device = host.default_output_device()?;
+    Some(OutputDevice {
+        host: host.id().name().to_string(),
+        name: device.name().ok()?,
+    })
+}
+
+/// Finds the output device with the given name, looking into the default host first
+pub(crate) fn find_device(name: &str) -> Option<cpal::Device> {
+    all_devices()
+        .into_iter()
+        .map(|(_, device)| device)
+        .find(|device| device.name().is_ok_and(|device_name| device_name == name))
+}
+
+fn all_devices() -> Vec<(String, cpal::Device)> {
+    let default_host = cpal::default_host().id();
+    let mut ids = cpal::available_hosts();
+    ids.sort_by_key(|id| *id != default_host);
+
+    let mut devices = vec![];
+    for id in ids {
+        if let Ok(host) = cpal::host_from_id(id) {
+            if let Ok(host_devices) = host.output_devices() {
+                devices.extend(host_devices.map(|device| (id.name().to_string(), device)));
+            }
+        }
+    }
+    devices
+}
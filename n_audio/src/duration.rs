@@ -0,0 +1,130 @@
+//! Finding out the length of tracks whose format doesn't say it (e.g. VBR MP3s without a Xing header)
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use once_cell::sync::Lazy;
+use symphonia::core::formats::FormatReader;
+use symphonia::core::io::{MediaSource, ReadBytes};
+
+use crate::LengthKind;
+
+/// How many packets are looked at to estimate the bitrate of a track
+const SAMPLED_PACKETS: usize = 500;
+
+/// Lengths found by reading whole files, so that it only happens once per file
+static SCANNED: Lazy<Mutex<HashMap<String, Scanned>>> = Lazy::new(Default::default);
+
+struct Scanned {
+    modified: Option<SystemTime>,
+    size: u64,
+    frames: u64,
+}
+
+/// The length of the default track, in units of its time base
+#[derive(Copy, Clone, Debug)]
+pub(crate) struct Length {
+    pub frames: u64,
+    pub kind: LengthKind,
+}
+
+/// Returns the length of the default track as written in the file
+pub(crate) fn from_params(format: &dyn FormatReader) -> Option<Length> {
+    let params = &format.default_track()?.codec_params;
+    params.n_frames.map(|frames| Length {
+        frames: params.start_ts + frames,
+        kind: LengthKind::Exact,
+    })
+}
+
+/// Returns the length found by a previous `scan` of the file, as long as the file didn't change since then
+pub(crate) fn cached(path: &str) -> Option<Length> {
+    let metadata = fs::metadata(path).ok()?;
+    let scanned = SCANNED.lock().unwrap();
+    let scanned = scanned.get(path)?;
+    if scanned.size != metadata.len() || scanned.modified != metadata.modified().ok() {
+        return None;
+    }
+    Some(Length {
+        frames: scanned.frames,
+        kind: LengthKind::Exact,
+    })
+}
+
+/// Reads every packet of the default track to find where it ends, remembering the result for `cached`
+pub(crate) fn scan(path: &str, mut format: Box<dyn FormatReader>) -> Option<Length> {
+    let track_id = format.default_track()?.id;
+    let mut end = None;
+    while let Ok(packet) = format.next_packet() {
+        if packet.track_id() == track_id {
+            end = Some(packet.ts() + packet.dur());
+        }
+    }
+    let frames = end?;
+
+    if let Ok(metadata) = fs::metadata(Path::new(path)) {
+        SCANNED.lock().unwrap().insert(
+            path.to_string(),
+            Scanned {
+                modified: metadata.modified().ok(),
+                size: metadata.len(),
+                frames,
+            },
+        );
+    }
+
+    Some(Length {
+        frames,
+        kind: LengthKind::Exact,
+    })
+}
+
+/// Estimates the length from the bitrate of the first packets and the size of the file
+/// If the track ends within those packets, the length is exact
+pub(crate) fn estimate(mut format: Box<dyn FormatReader>) -> Option<Length> {
+    let track_id = format.default_track()?.id;
+
+    let mut payload = 0;
+    let mut start = None;
+    let mut end = 0;
+    let mut packets = 0;
+    let mut ended = false;
+    while packets < SAMPLED_PACKETS {
+        let Ok(packet) = format.next_packet() else {
+            ended = true;
+            break;
+        };
+        if packet.track_id() != track_id {
+            continue;
+        }
+        start.get_or_insert(packet.ts());
+        end = packet.ts() + packet.dur();
+        payload += packet.buf().len() as u64;
+        packets += 1;
+    }
+
+    if ended {
+        return start.map(|_| Length {
+            frames: end,
+            kind: LengthKind::Exact,
+        });
+    }
+
+    let source = format.into_inner();
+    let size = source.byte_len()?;
+    let read = source.pos();
+    let sampled = end - start?;
+    if payload == 0 || sampled == 0 {
+        return None;
+    }
+
+    // Whatever is left of the file is assumed to have the same bitrate as what was read so far
+    let left = size.saturating_sub(read) as f64 * sampled as f64 / payload as f64;
+    Some(Length {
+        frames: end + left as u64,
+        kind: LengthKind::Estimated,
+    })
+}
@@ -0,0 +1,298 @@
+//! Effects the `Player` runs on the decoded audio, in order, before it reaches the output
+//!
+//! Any type implementing [`AudioProcessor`] can be added to the chain, the built-in ones are
+//! [`Gain`], [`Balance`], [`MonoDownmix`], [`ChannelSwap`] and [`Limiter`].
+//!
+//! ```no_run
+//! use n_audio::effect::{Balance, Limiter};
+//! use n_audio::player::Player;
+//!
+//! let player = Player::new(1.0, 1.0);
+//! let mut effects = player.effects();
+//! effects.push(Box::new(Balance::new(-0.25)));
+//! effects.push(Box::new(Limiter::default()));
+//! ```
+
+use std::fmt::{self, Debug, Formatter};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+
+use symphonia::core::audio::{AudioBuffer, Signal};
+
+/// Something that changes the audio as it's played
+///
+/// Decoded audio is always converted to `f32` samples, in the channels and rate of the track, before being processed;
+/// both can change between calls when a new track starts
+pub trait AudioProcessor: Send {
+    /// Changes the frames of `buf` in place
+    fn process(&mut self, buf: &mut AudioBuffer<f32>);
+
+    /// Forgets the audio processed so far, called when the `Player` seeks
+    fn reset(&mut self) {}
+
+    /// Name shown when debugging the chain
+    fn name(&self) -> &str {
+        std::any::type_name::<Self>()
+    }
+}
+
+/// Identifies an effect inside an [`EffectChain`], it stays the same when effects are added, removed or moved
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct EffectId(u64);
+
+/// The ordered effects of a `Player`, see [`crate::player::Player::effects`]
+#[derive(Default)]
+pub struct EffectChain {
+    effects: Vec<(EffectId, Box<dyn AudioProcessor>)>,
+    next_id: u64,
+}
+
+impl EffectChain {
+    /// Adds an effect at the end of the chain
+    pub fn push(&mut self, effect: Box<dyn AudioProcessor>) -> EffectId {
+        self.insert(self.effects.len(), effect)
+    }
+
+    /// Adds an effect at `index`, so that it runs before the ones after it
+    /// The index is clamped to the length of the chain
+    pub fn insert(&mut self, index: usize, effect: Box<dyn AudioProcessor>) -> EffectId {
+        let id = EffectId(self.next_id);
+        self.next_id += 1;
+        self.effects
+            .insert(index.min(self.effects.len()), (id, effect));
+        id
+    }
+
+    pub fn remove(&mut self, id: EffectId) -> Option<Box<dyn AudioProcessor>> {
+        let index = self.index_of(id)?;
+        Some(self.effects.remove(index).1)
+    }
+
+    /// Puts `effect` in place of the one with `id`, returning the old one
+    pub fn replace(
+        &mut self,
+        id: EffectId,
+        effect: Box<dyn AudioProcessor>,
+    ) -> Option<Box<dyn AudioProcessor>> {
+        let index = self.index_of(id)?;
+        Some(std::mem::replace(&mut self.effects[index].1, effect))
+    }
+
+    /// Moves the effect with `id` to `index`, returning whether it was found
+    pub fn move_to(&mut self, id: EffectId, index: usize) -> bool {
+        let Some(from) = self.index_of(id) else {
+            return false;
+        };
+        let effect = self.effects.remove(from);
+        self.effects.insert(index.min(self.effects.len()), effect);
+        true
+    }
+
+    pub fn get_mut(&mut self, id: EffectId) -> Option<&mut dyn AudioProcessor> {
+        let index = self.index_of(id)?;
+        Some(self.effects[index].1.as_mut())
+    }
+
+    pub fn clear(&mut self) {
+        self.effects.clear();
+    }
+
+    /// Returns the ids of the effects, in the order they run
+    pub fn ids(&self) -> Vec<EffectId> {
+        self.effects.iter().map(|(id, _)| *id).collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.effects.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.effects.is_empty()
+    }
+
+    fn index_of(&self, id: EffectId) -> Option<usize> {
+        self.effects
+            .iter()
+            .position(|(effect_id, _)| *effect_id == id)
+    }
+
+    pub(crate) fn process(&mut self, buf: &mut AudioBuffer<f32>) {
+        for (_, effect) in &mut self.effects {
+            effect.process(buf);
+        }
+    }
+
+    pub(crate) fn reset(&mut self) {
+        for (_, effect) in &mut self.effects {
+            effect.reset();
+        }
+    }
+}
+
+impl Debug for EffectChain {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_list()
+            .entries(self.effects.iter().map(|(_, effect)| effect.name()))
+            .finish()
+    }
+}
+
+/// The chain shared between the `Player` and its track threads
+#[derive(Clone, Debug, Default)]
+pub(crate) struct SharedChain {
+    chain: Arc<Mutex<EffectChain>>,
+}
+
+impl SharedChain {
+    /// A processor that panicked doesn't stop the others from being used
+    pub fn lock(&self) -> MutexGuard<'_, EffectChain> {
+        self.chain.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// Changes the volume by a fixed amount
+#[derive(Copy, Clone, Debug)]
+pub struct Gain {
+    factor: f32,
+}
+
+impl Gain {
+    /// `db` is the gain in dB, negative values lower the volume
+    pub fn new(db: f32) -> Self {
+        Gain {
+            factor: 10f32.powf(db / 20.0),
+        }
+    }
+}
+
+impl AudioProcessor for Gain {
+    fn process(&mut self, buf: &mut AudioBuffer<f32>) {
+        let factor = self.factor;
+        buf.transform(|sample| sample * factor);
+    }
+}
+
+/// Moves the sound between the left and the right channel, by lowering the other one
+/// Only the first two channels are changed
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Balance {
+    pan: f32,
+}
+
+impl Balance {
+    /// `pan` goes from `-1.0` (only left) to `1.0` (only right), `0.0` leaves the audio as it is
+    pub fn new(pan: f32) -> Self {
+        Balance {
+            pan: pan.clamp(-1.0, 1.0),
+        }
+    }
+}
+
+impl AudioProcessor for Balance {
+    fn process(&mut self, buf: &mut AudioBuffer<f32>) {
+        if self.pan == 0.0 || buf.spec().channels.count() < 2 {
+            return;
+        }
+        let left = (1.0 - self.pan).min(1.0);
+        let right = (1.0 + self.pan).min(1.0);
+        let (l, r) = buf.chan_pair_mut(0, 1);
+        l.iter_mut().for_each(|sample| *sample *= left);
+        r.iter_mut().for_each(|sample| *sample *= right);
+    }
+}
+
+/// Plays the average of every channel on all of them
+#[derive(Copy, Clone, Debug, Default)]
+pub struct MonoDownmix;
+
+impl AudioProcessor for MonoDownmix {
+    fn process(&mut self, buf: &mut AudioBuffer<f32>) {
+        let channels = buf.spec().channels.count();
+        if channels < 2 {
+            return;
+        }
+        for ch in 1..channels {
+            let (mix, other) = buf.chan_pair_mut(0, ch);
+            mix.iter_mut()
+                .zip(other.iter())
+                .for_each(|(mix, sample)| *mix += sample);
+        }
+        let scale = 1.0 / channels as f32;
+        buf.chan_mut(0)
+            .iter_mut()
+            .for_each(|sample| *sample *= scale);
+        for ch in 1..channels {
+            let (mix, other) = buf.chan_pair_mut(0, ch);
+            other.copy_from_slice(mix);
+        }
+    }
+}
+
+/// Swaps the left and the right channel
+#[derive(Copy, Clone, Debug, Default)]
+pub struct ChannelSwap;
+
+impl AudioProcessor for ChannelSwap {
+    fn process(&mut self, buf: &mut AudioBuffer<f32>) {
+        if buf.spec().channels.count() < 2 {
+            return;
+        }
+        let (l, r) = buf.chan_pair_mut(0, 1);
+        l.swap_with_slice(r);
+    }
+}
+
+/// Keeps the peaks of the audio under a threshold
+/// The gain drops as soon as a sample would go over it and comes back up slowly, so that there's no distortion
+#[derive(Copy, Clone, Debug)]
+pub struct Limiter {
+    threshold: f32,
+    release: f32,
+    gain: f32,
+}
+
+impl Limiter {
+    /// `threshold_db` is the highest peak let through, `release_ms` how long the gain takes to come back up
+    pub fn new(threshold_db: f32, release_ms: f32) -> Self {
+        Limiter {
+            threshold: 10f32.powf(threshold_db / 20.0),
+            release: release_ms.max(1.0) / 1000.0,
+            gain: 1.0,
+        }
+    }
+}
+
+impl Default for Limiter {
+    fn default() -> Self {
+        Self::new(-1.0, 200.0)
+    }
+}
+
+impl AudioProcessor for Limiter {
+    fn process(&mut self, buf: &mut AudioBuffer<f32>) {
+        let channels = buf.spec().channels.count();
+        // Every channel gets the same gain, so that the stereo image doesn't move
+        let coefficient = (-1.0 / (self.release * buf.spec().rate as f32)).exp();
+        for frame in 0..buf.frames() {
+            let peak = (0..channels)
+                .map(|ch| buf.chan(ch)[frame].abs())
+                .fold(0.0, f32::max);
+            let target = if peak * self.gain > self.threshold {
+                self.threshold / peak
+            } else {
+                1.0
+            };
+            self.gain = if target < self.gain {
+                target
+            } else {
+                target + (self.gain - target) * coefficient
+            };
+            for ch in 0..channels {
+                buf.chan_mut(ch)[frame] *= self.gain;
+            }
+        }
+    }
+
+    fn reset(&mut self) {
+        self.gain = 1.0;
+    }
+}
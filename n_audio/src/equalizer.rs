@@ -0,0 +1,297 @@
+//! Parametric equalizer applied by the `Player` to the decoded audio
+//!
+//! ```no_run
+//! use n_audio::equalizer::Band;
+//! use n_audio::player::Player;
+//!
+//! # async fn example() {
+//! let mut player = Player::new(1.0, 1.0);
+//! // A graphic equalizer boosting the bass
+//! let gains = [6.0, 5.0, 3.0, 1.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0];
+//! player.set_equalizer(Band::graphic_bands(&gains)).await.unwrap();
+//! # }
+//! ```
+
+use std::f64::consts::PI;
+
+use symphonia::core::audio::{AudioBuffer, Signal, SignalSpec};
+
+use crate::filter::Biquad;
+
+/// Center frequencies of the bands of a 10 band graphic equalizer, an octave apart
+pub const GRAPHIC_FREQUENCIES: [f32; 10] = [
+    31.25, 62.5, 125.0, 250.0, 500.0, 1000.0, 2000.0, 4000.0, 8000.0, 16000.0,
+];
+/// Quality factor making the bands of a graphic equalizer an octave wide
+pub const GRAPHIC_Q: f32 = std::f32::consts::SQRT_2;
+
+/// The shape of the response of a band
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum FilterKind {
+    /// Boosts or cuts around the frequency
+    #[default]
+    Peaking,
+    /// Boosts or cuts below the frequency
+    LowShelf,
+    /// Boosts or cuts above the frequency
+    HighShelf,
+    /// Removes what's above the frequency, the gain isn't used
+    LowPass,
+    /// Removes what's below the frequency, the gain isn't used
+    HighPass,
+}
+
+/// A band of the equalizer
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Band {
+    pub kind: FilterKind,
+    /// Center (or corner) frequency in Hz
+    pub frequency: f32,
+    /// Gain in dB
+    pub gain: f32,
+    /// Quality factor, the higher it is the narrower the band
+    pub q: f32,
+}
+
+impl Band {
+    pub fn peaking(frequency: f32, gain: f32, q: f32) -> Self {
+        Band {
+            kind: FilterKind::Peaking,
+            frequency,
+            gain,
+            q,
+        }
+    }
+
+    /// Returns the band at `index` of a 10 band graphic equalizer, see [`GRAPHIC_FREQUENCIES`]
+    pub fn graphic(index: usize, gain: f32) -> Self {
+        Self::peaking(GRAPHIC_FREQUENCIES[index], gain, GRAPHIC_Q)
+    }
+
+    /// Returns the bands of a graphic equalizer with the given gains, from the lowest frequency
+    pub fn graphic_bands(gains: &[f32]) -> Vec<Self> {
+        gains
+            .iter()
+            .take(GRAPHIC_FREQUENCIES.len())
+            .enumerate()
+            .map(|(index, &gain)| Self::graphic(index, gain))
+            .collect()
+    }
+
+    /// Whether the band leaves the audio as it is
+    fn is_flat(&self) -> bool {
+        match self.kind {
+            FilterKind::Peaking | FilterKind::LowShelf | FilterKind::HighShelf => self.gain == 0.0,
+            FilterKind::LowPass | FilterKind::HighPass => false,
+        }
+    }
+
+    /// Returns the normalized coefficients of the band, from the Audio EQ Cookbook by Robert Bristow-Johnson
+    fn coefficients(&self, rate: u32) -> ([f64; 3], [f64; 2]) {
+        let rate = rate as f64;
+        // Past Nyquist the filter would be unstable
+        let frequency = (self.frequency as f64).clamp(1.0, rate * 0.49);
+        let q = (self.q as f64).max(0.01);
+        let a = 10f64.powf(self.gain as f64 / 40.0);
+        let w0 = 2.0 * PI * frequency / rate;
+        let (sin, cos) = w0.sin_cos();
+        let alpha = sin / (2.0 * q);
+        let shelf = 2.0 * a.sqrt() * alpha;
+
+        let (b, a) = match self.kind {
+            FilterKind::Peaking => (
+                [1.0 + alpha * a, -2.0 * cos, 1.0 - alpha * a],
+                [1.0 + alpha / a, -2.0 * cos, 1.0 - alpha / a],
+            ),
+            FilterKind::LowShelf => (
+                [
+                    a * ((a + 1.0) - (a - 1.0) * cos + shelf),
+                    2.0 * a * ((a - 1.0) - (a + 1.0) * cos),
+                    a * ((a + 1.0) - (a - 1.0) * cos - shelf),
+                ],
+                [
+                    (a + 1.0) + (a - 1.0) * cos + shelf,
+                    -2.0 * ((a - 1.0) + (a + 1.0) * cos),
+                    (a + 1.0) + (a - 1.0) * cos - shelf,
+                ],
+            ),
+            FilterKind::HighShelf => (
+                [
+                    a * ((a + 1.0) + (a - 1.0) * cos + shelf),
+                    -2.0 * a * ((a - 1.0) + (a + 1.0) * cos),
+                    a * ((a + 1.0) + (a - 1.0) * cos - shelf),
+                ],
+                [
+                    (a + 1.0) - (a - 1.0) * cos + shelf,
+                    2.0 * ((a - 1.0) - (a + 1.0) * cos),
+                    (a + 1.0) - (a - 1.0) * cos - shelf,
+                ],
+            ),
+            FilterKind::LowPass => (
+                [(1.0 - cos) / 2.0, 1.0 - cos, (1.0 - cos) / 2.0],
+                [1.0 + alpha, -2.0 * cos, 1.0 - alpha],
+            ),
+            FilterKind::HighPass => (
+                [(1.0 + cos) / 2.0, -(1.0 + cos), (1.0 + cos) / 2.0],
+                [1.0 + alpha, -2.0 * cos, 1.0 - alpha],
+            ),
+        };
+
+        let a0 = a[0];
+        ([b[0] / a0, b[1] / a0, b[2] / a0], [a[1] / a0, a[2] / a0])
+    }
+}
+
+/// Runs the bands over the audio, with a filter for every band and channel
+pub(crate) struct Equalizer {
+    bands: Vec<Band>,
+    spec: Option<SignalSpec>,
+    /// `filters[band][channel]`
+    filters: Vec<Vec<Biquad>>,
+    /// Whether each band was skipped as flat the last time, its filters then hold the state of older audio
+    skipped: Vec<bool>,
+}
+
+impl Equalizer {
+    pub fn new(bands: Vec<Band>) -> Self {
+        Equalizer {
+            bands,
+            spec: None,
+            filters: vec![],
+            skipped: vec![],
+        }
+    }
+
+    /// Changes every band, the filters keep their state if the number of bands stays the same
+    pub fn set_bands(&mut self, bands: Vec<Band>) {
+        if bands.len() != self.bands.len() {
+            self.spec = None;
+        }
+        self.bands = bands;
+        self.update();
+    }
+
+    /// Changes a single band, adding flat bands if there weren't enough of them
+    pub fn set_band(&mut self, index: usize, band: Band) {
+        if index >= self.bands.len() {
+            self.bands
+                .resize(index + 1, Band::peaking(1000.0, 0.0, 1.0));
+            self.spec = None;
+        }
+        self.bands[index] = band;
+        self.update();
+    }
+
+    pub fn reset(&mut self) {
+        self.filters.iter_mut().flatten().for_each(Biquad::reset);
+    }
+
+    fn update(&mut self) {
+        let Some(spec) = self.spec else {
+            return;
+        };
+        for (band, filters) in self.bands.iter().zip(&mut self.filters) {
+            let (b, a) = band.coefficients(spec.rate);
+            for filter in filters {
+                filter.set_coefficients(b, a);
+            }
+        }
+    }
+
+    fn setup(&mut self, spec: SignalSpec) {
+        self.filters = self
+            .bands
+            .iter()
+            .map(|band| {
+                let (b, a) = band.coefficients(spec.rate);
+                vec![Biquad::new(b, a); spec.channels.count()]
+            })
+            .collect();
+        self.skipped = vec![false; self.bands.len()];
+        self.spec = Some(spec);
+    }
+
+    pub fn process(&mut self, buf: &mut AudioBuffer<f32>) {
+        if self.bands.iter().all(Band::is_flat) {
+            self.skipped.fill(true);
+            return;
+        }
+        if self.spec != Some(*buf.spec()) {
+            self.setup(*buf.spec());
+        }
+
+        for ((band, filters), skipped) in self
+            .bands
+            .iter()
+            .zip(&mut self.filters)
+            .zip(&mut self.skipped)
+        {
+            if band.is_flat() {
+                *skipped = true;
+                continue;
+            }
+            // Going on from where it stopped would be a click
+            if std::mem::take(skipped) {
+                filters.iter_mut().for_each(Biquad::reset);
+            }
+            for (ch, filter) in filters.iter_mut().enumerate() {
+                for sample in buf.chan_mut(ch) {
+                    *sample = filter.process(*sample as f64) as f32;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use symphonia::core::audio::Layout;
+
+    /// Returns the gain in dB of the filter with these coefficients at `frequency`
+    fn gain_at(b: [f64; 3], a: [f64; 2], frequency: f64, rate: u32) -> f64 {
+        let w = 2.0 * PI * frequency / rate as f64;
+        // The transfer function evaluated on the unit circle, at z = e^(jw)
+        let response = |c: [f64; 3]| {
+            let re = c[0] + c[1] * w.cos() + c[2] * (2.0 * w).cos();
+            let im = -c[1] * w.sin() - c[2] * (2.0 * w).sin();
+            (re * re + im * im).sqrt()
+        };
+        20.0 * (response(b) / response([1.0, a[0], a[1]])).log10()
+    }
+
+    #[test]
+    fn peaking_band_has_its_gain_at_the_center() {
+        for rate in [44100, 48000, 96000] {
+            for frequency in GRAPHIC_FREQUENCIES {
+                for gain in [-12.0, -3.0, 6.0, 12.0] {
+                    let (b, a) = Band::peaking(frequency, gain, GRAPHIC_Q).coefficients(rate);
+                    let actual = gain_at(b, a, frequency as f64, rate);
+                    assert!(
+                        (actual - gain as f64).abs() < 0.01,
+                        "{actual}dB instead of {gain}dB at {frequency}Hz and {rate}Hz"
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn band_turned_back_on_starts_afresh() {
+        let spec = SignalSpec::new_with_layout(48000, Layout::Mono);
+        let mut buf = AudioBuffer::<f32>::new(256, spec);
+        buf.render_reserved(Some(256));
+        let mut equalizer = Equalizer::new(vec![Band::peaking(100.0, 6.0, 1.0)]);
+
+        buf.chan_mut(0).fill(1.0);
+        equalizer.process(&mut buf);
+        // The band is turned off while the audio goes silent, then back on
+        equalizer.set_band(0, Band::peaking(100.0, 0.0, 1.0));
+        buf.chan_mut(0).fill(0.0);
+        equalizer.process(&mut buf);
+        equalizer.set_band(0, Band::peaking(100.0, 6.0, 1.0));
+        equalizer.process(&mut buf);
+
+        assert!(buf.chan(0).iter().all(|&sample| sample == 0.0));
+    }
+}
@@ -0,0 +1,75 @@
+//! Errors that can happen while loading or playing a track
+
+use std::fmt::{Display, Formatter};
+use std::{error, fmt, io};
+
+use symphonia::core::errors::Error as SymphoniaError;
+
+use crate::output::AudioOutputError;
+
+#[derive(Debug)]
+pub enum NError {
+    /// There's no track with the given name in the queue
+    NoTrack,
+    /// The file couldn't be read
+    Io(io::Error),
+    /// The file isn't in a supported format, or it's corrupted
+    Probe(SymphoniaError),
+    /// The file doesn't contain any track that can be played
+    NoPlayableTrack,
+    /// The track doesn't say something that's needed to play it back
+    MissingParameter(&'static str),
+    /// There's no decoder for the codec of the track
+    Decoder(SymphoniaError),
+    /// The track stopped being decodable halfway through
+    Decode(SymphoniaError),
+    /// The track couldn't be moved to the requested position
+    Seek(SymphoniaError),
+    /// The audio output couldn't be opened or written to
+    Output(AudioOutputError),
+    /// The audio couldn't be encoded, e.g. because of an invalid bitrate or frame size
+    Encode(audiopus::Error),
+}
+
+impl Display for NError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            NError::NoTrack => write!(f, "no such track in the queue"),
+            NError::Io(err) => write!(f, "can't read the file: {err}"),
+            NError::Probe(err) => write!(f, "format not supported: {err}"),
+            NError::NoPlayableTrack => write!(f, "the file doesn't contain any playable track"),
+            NError::MissingParameter(param) => write!(f, "the track doesn't specify its {param}"),
+            NError::Decoder(err) => write!(f, "codec not supported: {err}"),
+            NError::Decode(err) => write!(f, "can't decode the track: {err}"),
+            NError::Seek(err) => write!(f, "can't seek: {err}"),
+            NError::Output(err) => write!(f, "audio output error: {err}"),
+            NError::Encode(err) => write!(f, "can't encode the track: {err}"),
+        }
+    }
+}
+
+impl error::Error for NError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            NError::Io(err) => Some(err),
+            NError::Probe(err) | NError::Decoder(err) | NError::Decode(err) | NError::Seek(err) => {
+                Some(err)
+            }
+            NError::Output(err) => Some(err),
+            NError::Encode(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for NError {
+    fn from(value: io::Error) -> Self {
+        NError::Io(value)
+    }
+}
+
+impl From<AudioOutputError> for NError {
+    fn from(value: AudioOutputError) -> Self {
+        NError::Output(value)
+    }
+}
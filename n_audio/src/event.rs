@@ -0,0 +1,77 @@
+//! Events the `Player` sends to whoever subscribed to it
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use flume::{Receiver, Sender};
+
+use crate::{LoopRegion, NError, TrackTime};
+
+/// How often `PlayerEvent::Position` is sent while a track is playing
+const POSITION_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Something that happened to the `Player`, see [`crate::player::Player::subscribe`]
+#[derive(Clone, Debug)]
+pub enum PlayerEvent {
+    /// A track started playing, either because `Player::play` was called or because the preloaded one took over
+    TrackStarted,
+    /// Where the current track is at, sent a few times per second and after seeking
+    Position(TrackTime),
+    Paused,
+    Resumed,
+    VolumeChanged(f32),
+    PlaybackSpeedChanged(f32),
+    /// The A-B loop was set, played again (with one repeat less) or dropped, because it ended or the track changed
+    LoopChanged(Option<LoopRegion>),
+    /// The current track ended by itself, this isn't sent when using `Player::end_current`
+    TrackEnded,
+    Error(Arc<NError>),
+}
+
+/// The senders of every subscriber, shared between the `Player` and its track threads
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Subscribers {
+    senders: Arc<Mutex<Vec<Sender<PlayerEvent>>>>,
+}
+
+impl Subscribers {
+    pub fn subscribe(&self) -> Receiver<PlayerEvent> {
+        let (tx, rx) = flume::unbounded();
+        self.senders.lock().unwrap().push(tx);
+        rx
+    }
+
+    /// Sends `event` to every subscriber, forgetting the ones that dropped their receiver
+    pub fn emit(&self, event: PlayerEvent) {
+        self.senders
+            .lock()
+            .unwrap()
+            .retain(|tx| tx.send(event.clone()).is_ok());
+    }
+}
+
+/// Keeps `PlayerEvent::Position` from being sent for every single packet
+#[derive(Debug, Default)]
+pub(crate) struct PositionThrottle {
+    last: Option<Instant>,
+}
+
+impl PositionThrottle {
+    /// Returns whether enough time has passed since the last position was sent
+    pub fn ready(&mut self) -> bool {
+        let now = Instant::now();
+        if self
+            .last
+            .is_some_and(|last| now.duration_since(last) < POSITION_INTERVAL)
+        {
+            return false;
+        }
+        self.last = Some(now);
+        true
+    }
+
+    /// Lets the next position through, e.g. after seeking
+    pub fn reset(&mut self) {
+        self.last = None;
+    }
+}
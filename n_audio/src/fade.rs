@@ -0,0 +1,64 @@
+//! Gain ramps, so that the audio never starts or stops in the middle of a waveform
+
+/// A gain that moves linearly towards a target, one step per frame
+#[derive(Copy, Clone, Debug)]
+pub struct Ramp {
+    gain: f32,
+    target: f32,
+    step: f32,
+}
+
+impl Ramp {
+    pub fn new(gain: f32) -> Self {
+        Ramp {
+            gain,
+            target: gain,
+            step: 0.0,
+        }
+    }
+
+    /// Starts moving towards `target`, getting there in `frames` frames (right away when it's `0`)
+    pub fn set_target(&mut self, target: f32, frames: usize) {
+        self.target = target;
+        if frames == 0 {
+            self.gain = target;
+        } else {
+            self.step = (target - self.gain).abs() / frames as f32;
+        }
+    }
+
+    /// Sets the gain without ramping, e.g. to start from silence
+    pub fn jump(&mut self, gain: f32) {
+        self.gain = gain;
+        self.target = gain;
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.gain == self.target
+    }
+
+    /// Multiplies the interleaved `samples` by the gain, moving it after every frame
+    pub fn apply(&mut self, samples: &mut [f32], channels: usize) {
+        if self.is_done() {
+            if self.gain != 1.0 {
+                let gain = self.gain;
+                samples.iter_mut().for_each(|sample| *sample *= gain);
+            }
+            return;
+        }
+        for frame in samples.chunks_exact_mut(channels.max(1)) {
+            self.gain = if self.gain < self.target {
+                (self.gain + self.step).min(self.target)
+            } else {
+                (self.gain - self.step).max(self.target)
+            };
+            let gain = self.gain;
+            frame.iter_mut().for_each(|sample| *sample *= gain);
+        }
+    }
+}
+
+/// How many frames at `rate` last `secs` seconds
+pub fn frames(secs: f64, rate: u32) -> usize {
+    (secs.max(0.0) * rate as f64).round() as usize
+}
@@ -0,0 +1,33 @@
+//! Biquad filters, shared by the equalizer and the loudness meter
+
+/// A second order IIR filter, in transposed direct form II
+/// Coefficients are normalized, so that `a0` is `1.0`
+#[derive(Copy, Clone, Debug)]
+pub(crate) struct Biquad {
+    b: [f64; 3],
+    a: [f64; 2],
+    z: [f64; 2],
+}
+
+impl Biquad {
+    pub fn new(b: [f64; 3], a: [f64; 2]) -> Self {
+        Biquad { b, a, z: [0.0; 2] }
+    }
+
+    /// Changes the response of the filter, keeping its state so that there's no click
+    pub fn set_coefficients(&mut self, b: [f64; 3], a: [f64; 2]) {
+        self.b = b;
+        self.a = a;
+    }
+
+    pub fn reset(&mut self) {
+        self.z = [0.0; 2];
+    }
+
+    pub fn process(&mut self, x: f64) -> f64 {
+        let y = self.b[0] * x + self.z[0];
+        self.z[0] = self.b[1] * x - self.a[0] * y + self.z[1];
+        self.z[1] = self.b[2] * x - self.a[1] * y;
+        y
+    }
+}
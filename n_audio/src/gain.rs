@@ -0,0 +1,132 @@
+//! Loudness normalization, using the ReplayGain and EBU R128 tags of the tracks
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use once_cell::sync::Lazy;
+use symphonia::core::formats::FormatReader;
+use symphonia::core::meta::{StandardTagKey, Tag, Value};
+
+use crate::{GainMode, ReplayGain};
+
+/// R128 gains bring tracks to -23 LUFS, ReplayGain ones to -18 LUFS
+const R128_TO_REPLAY_GAIN: f32 = 5.0;
+
+/// Gains computed for files, see `MusicTrack::set_replay_gain`
+static COMPUTED: Lazy<Mutex<HashMap<String, ReplayGain>>> = Lazy::new(Default::default);
+
+pub(crate) fn remember(path: &str, gain: ReplayGain) {
+    COMPUTED.lock().unwrap().insert(path.to_string(), gain);
+}
+
+pub(crate) fn computed(path: &str) -> Option<ReplayGain> {
+    COMPUTED.lock().unwrap().get(path).copied()
+}
+
+/// Returns `tagged` with the values it misses taken from `computed`
+pub(crate) fn fill(tagged: ReplayGain, computed: ReplayGain) -> ReplayGain {
+    ReplayGain {
+        track_gain: tagged.track_gain.or(computed.track_gain),
+        track_peak: tagged.track_peak.or(computed.track_peak),
+        album_gain: tagged.album_gain.or(computed.album_gain),
+        album_peak: tagged.album_peak.or(computed.album_peak),
+    }
+}
+
+/// Returns the ReplayGain tags holding the values of `gain`
+pub(crate) fn to_tags(gain: &ReplayGain) -> Vec<Tag> {
+    let values = [
+        (
+            StandardTagKey::ReplayGainTrackGain,
+            "REPLAYGAIN_TRACK_GAIN",
+            gain.track_gain,
+            " dB",
+        ),
+        (
+            StandardTagKey::ReplayGainTrackPeak,
+            "REPLAYGAIN_TRACK_PEAK",
+            gain.track_peak,
+            "",
+        ),
+        (
+            StandardTagKey::ReplayGainAlbumGain,
+            "REPLAYGAIN_ALBUM_GAIN",
+            gain.album_gain,
+            " dB",
+        ),
+        (
+            StandardTagKey::ReplayGainAlbumPeak,
+            "REPLAYGAIN_ALBUM_PEAK",
+            gain.album_peak,
+            "",
+        ),
+    ];
+    values
+        .into_iter()
+        .filter_map(|(std_key, key, value, unit)| {
+            let value = Value::String(format!("{:.6}{unit}", value?));
+            Some(Tag::new(Some(std_key), key, value))
+        })
+        .collect()
+}
+
+/// Reads `tag` into `gain` if it's a ReplayGain or a R128 tag, the latter are converted to ReplayGain
+/// Returns whether the tag was read
+pub(crate) fn read_tag(tag: &Tag, gain: &mut ReplayGain) -> bool {
+    let text = tag.value.to_string();
+    match tag.std_key {
+        Some(StandardTagKey::ReplayGainTrackGain) => gain.track_gain = parse_number(&text),
+        Some(StandardTagKey::ReplayGainTrackPeak) => gain.track_peak = parse_number(&text),
+        Some(StandardTagKey::ReplayGainAlbumGain) => gain.album_gain = parse_number(&text),
+        Some(StandardTagKey::ReplayGainAlbumPeak) => gain.album_peak = parse_number(&text),
+        _ if tag.key.eq_ignore_ascii_case("R128_TRACK_GAIN") => gain.track_gain = parse_q78(&text),
+        _ if tag.key.eq_ignore_ascii_case("R128_ALBUM_GAIN") => gain.album_gain = parse_q78(&text),
+        _ => return false,
+    }
+    true
+}
+
+/// Reads the gains from the latest tags of `format`
+pub(crate) fn of_format(format: &mut dyn FormatReader) -> ReplayGain {
+    let mut gain = ReplayGain::default();
+    if let Some(revision) = format.metadata().skip_to_latest() {
+        for tag in revision.tags() {
+            read_tag(tag, &mut gain);
+        }
+    }
+    gain
+}
+
+/// Returns the factor the samples of a track are multiplied by
+/// The pre-amp is only added to tracks with gain tags, and the gain is lowered if the peak would clip
+/// When the tags for `mode` are missing the other ones are used (e.g. album gain without track gain)
+/// The output gain of Opus streams is applied by the decoder, their R128 tags are relative to it
+pub(crate) fn factor(tags: &ReplayGain, mode: GainMode, preamp: f32) -> f32 {
+    let track = (tags.track_gain, tags.track_peak);
+    let album = (tags.album_gain, tags.album_peak);
+    let (gain, peak) = match mode {
+        GainMode::Off => (None, None),
+        GainMode::Track if track.0.is_some() => track,
+        GainMode::Album if album.0.is_some() => album,
+        GainMode::Track => album,
+        GainMode::Album => track,
+    };
+
+    let db = gain.map_or(0.0, |gain| gain + preamp);
+    let factor = 10f32.powf(db / 20.0);
+    match peak {
+        Some(peak) if peak > 0.0 && peak * factor > 1.0 => 1.0 / peak,
+        _ => factor,
+    }
+}
+
+/// Parses the number at the start of `text`, ignoring the unit after it (e.g. "-6.5 dB")
+fn parse_number(text: &str) -> Option<f32> {
+    text.split_whitespace().next()?.parse().ok()
+}
+
+/// Parses R128 gains, written in dB as Q7.8 fixed point numbers (e.g. "-1536" for -6 dB)
+fn parse_q78(text: &str) -> Option<f32> {
+    let gain: i16 = text.trim().parse().ok()?;
+    Some(gain as f32 / 256.0 + R128_TO_REPLAY_GAIN)
+}
@@ -1,23 +1,43 @@
 #![feature(io_error_more)]
 
 use std::path::Path;
+use std::sync::Arc;
 use symphonia::core::codecs::CodecRegistry;
+use symphonia::core::formats::FormatReader;
 
 use crate::dca::DcaReader;
+use crate::equalizer::Band;
 use crate::opus::OpusDecoder;
 use crate::raw::RawReader;
 use once_cell::sync::Lazy;
-use symphonia::core::units::Time;
 use symphonia::default::{register_enabled_codecs, register_enabled_formats};
 use symphonia_core::probe::Probe;
 
-mod dca;
+pub use error::NError;
+pub use event::PlayerEvent;
+pub use symphonia::core::units::Time;
+
+mod convert;
+pub mod dca;
+pub mod device;
+mod duration;
+pub mod effect;
+pub mod equalizer;
+mod error;
+mod event;
+mod fade;
+mod filter;
+mod gain;
+pub mod loudness;
 pub mod music_track;
 mod opus;
-mod output;
+pub mod output;
+mod patched;
 pub mod player;
 pub mod queue;
 mod raw;
+mod stretch;
+pub mod visualizer;
 
 /// Default Symphonia [`CodecRegistry`], including the (audiopus-backed) Opus codec.
 pub static CODEC_REGISTRY: Lazy<CodecRegistry> = Lazy::new(|| {
@@ -35,11 +55,6 @@ pub static PROBE: Lazy<Probe> = Lazy::new(|| {
     probe
 });
 
-#[derive(Debug)]
-pub enum NError {
-    NoTrack,
-}
-
 /// Messages sent inside the `Player`
 pub enum Message {
     Play,
@@ -47,9 +62,51 @@ pub enum Message {
     End,
     Exit,
     Seek(Time),
-    Time(TrackTime),
     Volume(f32),
     PlaybackSpeed(f32),
+    SpeedMode(SpeedMode),
+    GainMode(GainMode),
+    /// Pre-amp in dB, added to the gain of tracks with gain tags
+    Preamp(f32),
+    Crossfade(f64),
+    /// Seconds the gain takes to ramp when pausing, resuming, seeking, ending or changing the volume
+    FadeLength(f64),
+    /// Replaces every band of the equalizer, no bands means no equalizer
+    Equalizer(Vec<Band>),
+    /// Changes a single band of the equalizer
+    EqualizerBand(usize, Band),
+    OutputDevice(Option<String>),
+    /// Sets or clears the A-B loop of the current track
+    Loop(Option<LoopRegion>),
+    /// The format of the next track, and whether the length it gives is exact
+    Preload(Box<dyn FormatReader>, LengthKind),
+    /// Forgets the preloaded track, the current one is then the last to play
+    ClearPreload,
+    Advance,
+    /// Something went wrong in the track thread
+    Error(Arc<NError>),
+}
+
+/// How the `Player` changes the playback speed
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum SpeedMode {
+    /// Plays the samples faster or slower, like a tape deck, so the pitch changes with the speed
+    Tape,
+    /// Time stretches the audio, so the pitch stays the same at any speed
+    #[default]
+    PreservePitch,
+}
+
+/// Which gain the `Player` uses to make every track sound about as loud
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum GainMode {
+    /// Tracks are played as they are (Opus streams still get the gain written in their header)
+    #[default]
+    Off,
+    /// Every track is brought to the same loudness
+    Track,
+    /// Every album is brought to the same loudness, keeping the differences between its tracks
+    Album,
 }
 
 /// Returns the file name without its extension
@@ -93,9 +150,42 @@ pub fn strip_absolute_path(path: String) -> String {
 pub struct TrackTime {
     pub position: f64,
     pub length: f64,
+    pub length_kind: LengthKind,
+}
+
+/// A part of a track played over and over, see `Player::set_loop`
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct LoopRegion {
+    pub start: Time,
+    pub end: Time,
+    /// How many more times the part is played again, `None` means forever
+    pub count: Option<u32>,
+}
+
+/// Statistics about what the `Player` played so far, see `Player::stats`
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct PlaybackStats {
+    /// Frames of Opus audio that were lost or corrupt and had to be made up (or recovered from the next packet)
+    pub concealed_frames: u64,
+}
+
+/// Where the length of a track comes from, and so how much it can be trusted
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum LengthKind {
+    /// Written in the file, or counted by reading all of it
+    #[default]
+    Exact,
+    /// Computed from the size of the file and its first packets, it may be a bit off
+    Estimated,
+    /// The length isn't known, `TrackTime::length` is `0.0`
+    Unknown,
 }
 
 impl TrackTime {
+    pub fn is_length_known(&self) -> bool {
+        self.length_kind != LengthKind::Unknown
+    }
+
     pub fn format_pos(&self) -> String {
         format!(
             "{:02}:{:02}",
@@ -105,6 +195,9 @@ impl TrackTime {
     }
 
     pub fn format_len(&self) -> String {
+        if !self.is_length_known() {
+            return String::from("--:--");
+        }
         format!(
             "{:02}:{:02}",
             (self.length / 60.0).floor() as u64,
@@ -113,9 +206,47 @@ impl TrackTime {
     }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Default)]
 pub struct Metadata {
     pub time: TrackTime,
     pub artist: String,
     pub title: String,
+    pub album: Option<String>,
+    pub album_artist: Option<String>,
+    pub track_number: Option<u32>,
+    pub track_total: Option<u32>,
+    pub disc_number: Option<u32>,
+    pub disc_total: Option<u32>,
+    pub year: Option<i32>,
+    pub genre: Option<String>,
+    pub composer: Option<String>,
+    pub comment: Option<String>,
+    /// Short name of the codec (e.g. `flac`, `mp3`, `opus`)
+    pub codec: Option<String>,
+    /// Average bitrate in bits per second, estimated from the size of the file
+    pub bitrate: Option<u32>,
+    pub sample_rate: Option<u32>,
+    pub bit_depth: Option<u32>,
+    pub channels: Option<usize>,
+    pub replay_gain: ReplayGain,
+    pub pictures: Vec<Picture>,
+}
+
+/// ReplayGain values as written in the tags, gains are in dB and peaks are linear
+/// R128 tags are converted to ReplayGain, for Opus files they don't include the gain written in the header
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct ReplayGain {
+    pub track_gain: Option<f32>,
+    pub track_peak: Option<f32>,
+    pub album_gain: Option<f32>,
+    pub album_peak: Option<f32>,
+}
+
+/// An image embedded in the track, usually its cover
+#[derive(Clone, Debug)]
+pub struct Picture {
+    /// MIME type of `data` (e.g. `image/jpeg`)
+    pub media_type: String,
+    pub front_cover: bool,
+    pub data: Vec<u8>,
 }
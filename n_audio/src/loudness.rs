@@ -0,0 +1,515 @@
+//! Loudness measurement as described by EBU R128 (ITU-R BS.1770), used to compute the gains of tracks without gain tags
+//!
+//! ```no_run
+//! use n_audio::loudness;
+//! use n_audio::music_track::MusicTrack;
+//!
+//! let track = MusicTrack::new("track.flac").unwrap();
+//! let loudness = loudness::analyze_track(&track).unwrap();
+//! println!("{:.1} LUFS, {:.1} LU", loudness.integrated, loudness.range);
+//! // The player uses it when the track has no gain tags
+//! track.set_replay_gain(loudness.replay_gain());
+//! ```
+
+use std::f64::consts::PI;
+use std::io;
+
+use symphonia::core::audio::{AudioBuffer, Channels, Signal, SignalSpec};
+use symphonia::core::codecs::DecoderOptions;
+use symphonia::core::errors::Error as SymphoniaError;
+
+use crate::filter::Biquad;
+use crate::music_track::MusicTrack;
+use crate::{NError, ReplayGain, CODEC_REGISTRY};
+
+/// Loudness ReplayGain 2.0 brings the tracks to, in LUFS
+const REFERENCE: f64 = -18.0;
+/// Blocks quieter than this are silence, in LUFS
+const ABSOLUTE_GATE: f64 = -70.0;
+/// Blocks quieter than the average by this much don't count for the integrated loudness, in LU
+const INTEGRATED_GATE: f64 = -10.0;
+/// Short-term blocks quieter than the average by this much don't count for the loudness range, in LU
+const RANGE_GATE: f64 = -20.0;
+/// Blocks are made of steps of 100ms
+const STEPS_PER_SECOND: u32 = 10;
+/// Blocks used for the integrated loudness are 400ms long
+const BLOCK_STEPS: usize = 4;
+/// Blocks used for the loudness range are 3s long
+const SHORT_TERM_STEPS: usize = 30;
+/// The true peak is found by looking at the signal oversampled 4 times
+const OVERSAMPLING: usize = 4;
+const TAPS_PER_PHASE: usize = 12;
+
+/// The loudness of a track, or of an album
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Loudness {
+    /// Integrated loudness in LUFS, `f64::NEG_INFINITY` if it's all silence
+    pub integrated: f64,
+    /// Loudness range in LU
+    pub range: f64,
+    /// True peak, linear (`1.0` is full scale)
+    pub true_peak: f64,
+}
+
+impl Loudness {
+    /// Returns the ReplayGain 2.0 gain in dB, `None` if it's all silence
+    pub fn gain(&self) -> Option<f32> {
+        self.integrated
+            .is_finite()
+            .then_some((REFERENCE - self.integrated) as f32)
+    }
+
+    /// Returns the track gain and peak, as they would be written in the tags
+    pub fn replay_gain(&self) -> ReplayGain {
+        ReplayGain {
+            track_gain: self.gain(),
+            track_peak: Some(self.true_peak as f32),
+            ..Default::default()
+        }
+    }
+}
+
+/// The loudness of every track of an album, and of the album as a whole
+#[derive(Clone, Debug, PartialEq)]
+pub struct AlbumLoudness {
+    /// `None` for the tracks that couldn't be decoded, which are left out of the album
+    pub tracks: Vec<Option<Loudness>>,
+    pub album: Loudness,
+}
+
+impl AlbumLoudness {
+    /// Returns the track and album gains and peaks of the track at `index`
+    pub fn replay_gain(&self, index: usize) -> Option<ReplayGain> {
+        let track = self.tracks.get(index)?.as_ref()?;
+        Some(ReplayGain {
+            album_gain: self.album.gain(),
+            album_peak: Some(self.album.true_peak as f32),
+            ..track.replay_gain()
+        })
+    }
+}
+
+/// Decodes the whole track and measures its loudness
+pub fn analyze_track(track: &MusicTrack) -> Result<Loudness, NError> {
+    Ok(Meter::of(track)?.loudness())
+}
+
+/// Measures the loudness of every track, and of all of them together as an album
+/// The tracks that can't be decoded are skipped, it only fails when none of them can
+pub fn analyze_album(tracks: &[MusicTrack]) -> Result<AlbumLoudness, NError> {
+    let mut error = None;
+    let meters: Vec<Option<Meter>> = tracks
+        .iter()
+        .map(|track| Meter::of(track).map_err(|err| error = Some(err)).ok())
+        .collect();
+    if let Some(err) = error.filter(|_| meters.iter().all(Option::is_none)) {
+        return Err(err);
+    }
+
+    let measured = || meters.iter().flatten();
+    let blocks: Vec<f64> = measured()
+        .flat_map(|meter| &meter.blocks)
+        .copied()
+        .collect();
+    let short_term: Vec<f64> = measured()
+        .flat_map(|meter| &meter.short_term)
+        .copied()
+        .collect();
+    let album = Loudness {
+        integrated: integrated(&blocks),
+        range: range(&short_term),
+        true_peak: measured().map(|meter| meter.peak).fold(0.0, f64::max),
+    };
+
+    Ok(AlbumLoudness {
+        tracks: meters
+            .iter()
+            .map(|meter| meter.as_ref().map(Meter::loudness))
+            .collect(),
+        album,
+    })
+}
+
+/// Measures the loudness of a signal while it's fed to it
+struct Meter {
+    spec: Option<SignalSpec>,
+    /// How much each channel counts for the loudness
+    weights: Vec<f64>,
+    filters: Vec<KWeighting>,
+    peaks: Vec<TruePeak>,
+    step_len: usize,
+    step_frames: usize,
+    /// Sum of the squares of the samples of the current step, for each channel
+    step_sums: Vec<f64>,
+    /// Weighted mean square of every step
+    steps: Vec<f64>,
+    /// Energy of the 400ms blocks, overlapping by 75%
+    blocks: Vec<f64>,
+    /// Energy of the 3s blocks, one every step
+    short_term: Vec<f64>,
+    peak: f64,
+}
+
+impl Meter {
+    fn new() -> Self {
+        Meter {
+            spec: None,
+            weights: vec![],
+            filters: vec![],
+            peaks: vec![],
+            step_len: 0,
+            step_frames: 0,
+            step_sums: vec![],
+            steps: vec![],
+            blocks: vec![],
+            short_term: vec![],
+            peak: 0.0,
+        }
+    }
+
+    /// Decodes `track` with the same codecs used by the `Player`, measuring all of it
+    fn of(track: &MusicTrack) -> Result<Self, NError> {
+        let mut format = track.get_format()?;
+        let default_track = format.default_track().ok_or(NError::NoPlayableTrack)?;
+        let track_id = default_track.id;
+        let mut decoder = CODEC_REGISTRY
+            .make(&default_track.codec_params, &DecoderOptions::default())
+            .map_err(NError::Decoder)?;
+
+        let mut meter = Meter::new();
+        let mut buf = AudioBuffer::<f32>::unused();
+        loop {
+            let packet = match format.next_packet() {
+                Ok(packet) => packet,
+                Err(SymphoniaError::IoError(err)) if err.kind() == io::ErrorKind::UnexpectedEof => {
+                    break
+                }
+                Err(SymphoniaError::ResetRequired) => break,
+                Err(err) => return Err(NError::Decode(err)),
+            };
+            if packet.track_id() != track_id {
+                continue;
+            }
+
+            match decoder.decode(&packet) {
+                Ok(decoded) => {
+                    if buf.capacity() < decoded.capacity() || buf.spec() != decoded.spec() {
+                        buf = decoded.make_equivalent();
+                    }
+                    decoded.convert(&mut buf);
+                    buf.trim(packet.trim_start() as usize, packet.trim_end() as usize);
+                    meter.process(&buf);
+                }
+                // A corrupted packet can be skipped
+                Err(SymphoniaError::DecodeError(_)) => {}
+                Err(err) => return Err(NError::Decode(err)),
+            }
+        }
+
+        Ok(meter)
+    }
+
+    fn setup(&mut self, spec: SignalSpec) {
+        let rate = spec.rate as f64;
+        self.weights = channel_weights(spec.channels);
+        self.filters = self.weights.iter().map(|_| KWeighting::new(rate)).collect();
+        self.peaks = self.weights.iter().map(|_| TruePeak::new()).collect();
+        self.step_len = (spec.rate / STEPS_PER_SECOND) as usize;
+        self.step_frames = 0;
+        self.step_sums = vec![0.0; self.weights.len()];
+        self.spec = Some(spec);
+    }
+
+    fn process(&mut self, buf: &AudioBuffer<f32>) {
+        match self.spec {
+            None => self.setup(*buf.spec()),
+            // Streams whose format changes midway are rare, what doesn't match the start is left out
+            Some(spec) if spec != *buf.spec() => return,
+            _ => {}
+        }
+
+        let mut frame = 0;
+        while frame < buf.frames() {
+            let frames = (self.step_len - self.step_frames).min(buf.frames() - frame);
+            for ch in 0..self.weights.len() {
+                let samples = &buf.chan(ch)[frame..frame + frames];
+                let filter = &mut self.filters[ch];
+                let true_peak = &mut self.peaks[ch];
+                let sum = &mut self.step_sums[ch];
+                for &sample in samples {
+                    let sample = sample as f64;
+                    let filtered = filter.process(sample);
+                    *sum += filtered * filtered;
+                    self.peak = self.peak.max(true_peak.process(sample));
+                }
+            }
+            frame += frames;
+            self.step_frames += frames;
+            if self.step_frames == self.step_len {
+                self.end_step();
+            }
+        }
+    }
+
+    fn end_step(&mut self) {
+        let energy = self
+            .step_sums
+            .iter()
+            .zip(&self.weights)
+            .map(|(sum, weight)| sum * weight)
+            .sum::<f64>()
+            / self.step_len as f64;
+        self.step_sums.iter_mut().for_each(|sum| *sum = 0.0);
+        self.step_frames = 0;
+
+        self.steps.push(energy);
+        if let Some(block) = mean_of_last(&self.steps, BLOCK_STEPS) {
+            self.blocks.push(block);
+        }
+        if let Some(block) = mean_of_last(&self.steps, SHORT_TERM_STEPS) {
+            self.short_term.push(block);
+        }
+    }
+
+    fn loudness(&self) -> Loudness {
+        Loudness {
+            integrated: integrated(&self.blocks),
+            range: range(&self.short_term),
+            true_peak: self.peak,
+        }
+    }
+}
+
+fn mean_of_last(steps: &[f64], count: usize) -> Option<f64> {
+    let start = steps.len().checked_sub(count)?;
+    Some(steps[start..].iter().sum::<f64>() / count as f64)
+}
+
+fn to_lufs(energy: f64) -> f64 {
+    -0.691 + 10.0 * energy.log10()
+}
+
+fn to_energy(lufs: f64) -> f64 {
+    10f64.powf((lufs + 0.691) / 10.0)
+}
+
+/// Returns the blocks louder than the absolute gate and than their average lowered by `relative`
+fn gate(blocks: &[f64], relative: f64) -> Vec<f64> {
+    let loud: Vec<f64> = blocks
+        .iter()
+        .copied()
+        .filter(|&block| block >= to_energy(ABSOLUTE_GATE))
+        .collect();
+    if loud.is_empty() {
+        return loud;
+    }
+    let average = loud.iter().sum::<f64>() / loud.len() as f64;
+    let threshold = average * 10f64.powf(relative / 10.0);
+    loud.into_iter()
+        .filter(|&block| block >= threshold)
+        .collect()
+}
+
+fn integrated(blocks: &[f64]) -> f64 {
+    let gated = gate(blocks, INTEGRATED_GATE);
+    if gated.is_empty() {
+        return f64::NEG_INFINITY;
+    }
+    to_lufs(gated.iter().sum::<f64>() / gated.len() as f64)
+}
+
+/// Returns the difference between the 95th and the 10th percentile of the short-term loudness
+fn range(short_term: &[f64]) -> f64 {
+    let mut gated = gate(short_term, RANGE_GATE);
+    if gated.is_empty() {
+        return 0.0;
+    }
+    gated.sort_by(f64::total_cmp);
+    let percentile = |p: f64| gated[((gated.len() - 1) as f64 * p).round() as usize];
+    to_lufs(percentile(0.95)) - to_lufs(percentile(0.10))
+}
+
+/// Surround channels count a bit more and the LFE isn't counted at all
+fn channel_weights(channels: Channels) -> Vec<f64> {
+    let surround =
+        Channels::SIDE_LEFT | Channels::SIDE_RIGHT | Channels::REAR_LEFT | Channels::REAR_RIGHT;
+    channels
+        .iter()
+        .map(|channel| {
+            if channel == Channels::LFE1 || channel == Channels::LFE2 {
+                0.0
+            } else if surround.contains(channel) {
+                1.41
+            } else {
+                1.0
+            }
+        })
+        .collect()
+}
+
+/// The K-weighting filter of BS.1770, a high shelf followed by a high pass
+struct KWeighting {
+    shelf: Biquad,
+    high_pass: Biquad,
+}
+
+impl KWeighting {
+    fn new(rate: f64) -> Self {
+        // Coefficients given for 48kHz by the standard, turned back into analog parameters to work at any rate
+        let f0 = 1681.974450955533;
+        let g = 3.999843853973347;
+        let q = 0.7071752369554196;
+        let k = (PI * f0 / rate).tan();
+        let vh = 10f64.powf(g / 20.0);
+        let vb = vh.powf(0.4996667741545416);
+        let a0 = 1.0 + k / q + k * k;
+        let shelf = Biquad::new(
+            [
+                (vh + vb * k / q + k * k) / a0,
+                2.0 * (k * k - vh) / a0,
+                (vh - vb * k / q + k * k) / a0,
+            ],
+            [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+        );
+
+        let f0 = 38.13547087602444;
+        let q = 0.5003270373238773;
+        let k = (PI * f0 / rate).tan();
+        let a0 = 1.0 + k / q + k * k;
+        let high_pass = Biquad::new(
+            [1.0, -2.0, 1.0],
+            [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+        );
+
+        KWeighting { shelf, high_pass }
+    }
+
+    fn process(&mut self, sample: f64) -> f64 {
+        self.high_pass.process(self.shelf.process(sample))
+    }
+}
+
+/// Finds the peaks between the samples by oversampling the signal
+struct TruePeak {
+    /// Windowed sinc, `taps[phase][tap]`
+    taps: [[f64; TAPS_PER_PHASE]; OVERSAMPLING],
+    history: [f64; TAPS_PER_PHASE],
+    next: usize,
+}
+
+impl TruePeak {
+    fn new() -> Self {
+        let len = OVERSAMPLING * TAPS_PER_PHASE;
+        let center = (len - 1) as f64 / 2.0;
+        let mut taps = [[0.0; TAPS_PER_PHASE]; OVERSAMPLING];
+        for (i, tap) in (0..len).map(|i| (i, i as f64 - center)) {
+            let x = tap / OVERSAMPLING as f64;
+            let sinc = if x == 0.0 {
+                1.0
+            } else {
+                (PI * x).sin() / (PI * x)
+            };
+            let window = 0.5 - 0.5 * (2.0 * PI * (i as f64 + 0.5) / len as f64).cos();
+            taps[i % OVERSAMPLING][i / OVERSAMPLING] = sinc * window;
+        }
+
+        TruePeak {
+            taps,
+            history: [0.0; TAPS_PER_PHASE],
+            next: 0,
+        }
+    }
+
+    /// Returns the highest absolute value between the last sample and `sample`
+    fn process(&mut self, sample: f64) -> f64 {
+        self.history[self.next] = sample;
+        self.next = (self.next + 1) % TAPS_PER_PHASE;
+
+        let mut peak = sample.abs();
+        for phase in &self.taps {
+            let value: f64 = phase
+                .iter()
+                .enumerate()
+                .map(|(tap, coefficient)| {
+                    let index = (self.next + TAPS_PER_PHASE - 1 - tap) % TAPS_PER_PHASE;
+                    coefficient * self.history[index]
+                })
+                .sum();
+            peak = peak.max(value.abs());
+        }
+        peak
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+    use std::io::{BufWriter, Write};
+    use std::path::Path;
+    use symphonia::core::audio::Layout;
+
+    /// Returns `seconds` of a mono 1kHz sine at 48kHz, with a peak of `amplitude`
+    fn sine(seconds: usize, amplitude: f32) -> Vec<f32> {
+        (0..seconds * 48000)
+            .map(|i| (i as f32 / 48.0 * std::f32::consts::TAU).sin() * amplitude)
+            .collect()
+    }
+
+    fn write_raw(path: &Path, samples: &[f32]) {
+        let mut file = BufWriter::new(File::create(path).unwrap());
+        file.write_all(b"SbirdRaw").unwrap();
+        file.write_all(&48000u32.to_le_bytes()).unwrap();
+        file.write_all(&1u32.to_le_bytes()).unwrap();
+        for sample in samples {
+            file.write_all(&sample.to_le_bytes()).unwrap();
+        }
+    }
+
+    #[test]
+    fn full_scale_sine_is_at_minus_3_lufs() {
+        let spec = SignalSpec::new_with_layout(48000, Layout::Mono);
+        let mut meter = Meter::new();
+        for packet in sine(10, 1.0).chunks(1024) {
+            let mut buf = AudioBuffer::<f32>::new(packet.len() as u64, spec);
+            buf.render_reserved(Some(packet.len()));
+            buf.chan_mut(0).copy_from_slice(packet);
+            meter.process(&buf);
+        }
+        let loudness = meter.loudness();
+
+        // The reference level of BS.1770
+        assert!(
+            (loudness.integrated + 3.01).abs() < 0.05,
+            "{} LUFS",
+            loudness.integrated
+        );
+        assert!(
+            (loudness.true_peak - 1.0).abs() < 0.02,
+            "true peak of {}",
+            loudness.true_peak
+        );
+        assert!(loudness.range < 0.1, "range of {} LU", loudness.range);
+    }
+
+    #[test]
+    fn undecodable_track_is_left_out_of_the_album() {
+        let dir = tempfile::tempdir().unwrap();
+        let good = dir.path().join("good.rawf32");
+        let broken = dir.path().join("broken.rawf32");
+        write_raw(&good, &sine(5, 0.5));
+        std::fs::write(&broken, b"not audio").unwrap();
+        let tracks = [
+            MusicTrack::new(good.to_str().unwrap()).unwrap(),
+            MusicTrack::new(broken.to_str().unwrap()).unwrap(),
+        ];
+
+        let loudness = analyze_album(&tracks).unwrap();
+
+        let good = loudness.tracks[0].unwrap();
+        assert_eq!(loudness.tracks[1], None);
+        assert_eq!(loudness.album, good);
+        assert!(loudness.replay_gain(0).is_some());
+        assert_eq!(loudness.replay_gain(1), None);
+    }
+}
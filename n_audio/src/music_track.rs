@@ -1,19 +1,33 @@
 use std::ffi::OsStr;
-use std::io::Cursor;
+use std::fs::File;
 use std::path::Path;
+use std::str::FromStr;
 use std::{fs, io};
 
-use crate::{remove_ext, Metadata, TrackTime, PROBE};
+use crate::dca::DcaReader;
+use crate::duration::{self, Length};
+use crate::gain;
+use crate::patched::PatchedFormat;
+use crate::{
+    remove_ext, LengthKind, Metadata, NError, Picture, ReplayGain, TrackTime, CODEC_REGISTRY, PROBE,
+};
 use symphonia::core::formats::{FormatOptions, FormatReader};
-use symphonia::core::io::MediaSourceStream;
-use symphonia::core::meta::MetadataOptions;
-use symphonia::core::probe::Hint;
+use symphonia::core::io::{MediaSourceStream, MediaSourceStreamOptions};
+use symphonia::core::meta::{MetadataLog, MetadataOptions, MetadataRevision, StandardVisualKey};
+use symphonia::core::probe::{Hint, ProbeResult};
+use symphonia::core::units::TimeBase;
 use symphonia_core::meta::StandardTagKey;
 
+/// How much of the file is read ahead by default, in bytes
+pub const DEFAULT_READ_AHEAD: usize = 64 * 1024;
+/// Symphonia reads up to 32KiB at a time, so it needs at least twice as much room
+const MIN_READ_AHEAD: usize = 64 * 1024;
+
 /// The basics where everything is built upon
 pub struct MusicTrack {
     path: String,
     ext: String,
+    read_ahead: usize,
 }
 
 impl MusicTrack {
@@ -28,85 +42,265 @@ impl MusicTrack {
                 .to_str()
                 .unwrap()
                 .to_string(),
+            read_ahead: DEFAULT_READ_AHEAD,
         })
     }
 
+    /// Sets how many bytes are read ahead from the file, rounded up to a power of two of at least 64KiB
+    /// A bigger read-ahead means fewer reads, which helps with slow disks and network shares
+    pub fn with_read_ahead(mut self, bytes: usize) -> Self {
+        self.read_ahead = bytes.max(MIN_READ_AHEAD).next_power_of_two();
+        self
+    }
+
     /// Returns the `FormatReader` provided by Symphonia
-    pub fn get_format(&self) -> Result<Box<dyn FormatReader>, io::Error> {
-        let file = fs::read(&self.path)?;
+    /// The file is streamed from disk, only the read-ahead is kept in memory
+    /// If the format doesn't say how long the track is, the length found by `MusicTrack::get_length` is given to it,
+    /// and the tags found outside of the container (e.g. ID3v2 in MP3 files) can be read from it
+    pub fn get_format(&self) -> Result<Box<dyn FormatReader>, NError> {
+        Ok(self.get_format_with_length_kind()?.0)
+    }
+
+    /// Same as `MusicTrack::get_format`, also telling whether the length given to the format is exact
+    pub(crate) fn get_format_with_length_kind(
+        &self,
+    ) -> Result<(Box<dyn FormatReader>, LengthKind), NError> {
+        let (mut format, metadata) = self.probe(true)?;
+        let (length, length_kind) = match duration::from_params(format.as_ref()) {
+            Some(length) => (None, length.kind),
+            None => {
+                let length = self.find_length(format.as_ref())?;
+                (
+                    length,
+                    length.map_or(LengthKind::Unknown, |length| length.kind),
+                )
+            }
+        };
+        let tags = match format.metadata().current() {
+            Some(_) => None,
+            None => metadata,
+        };
+        let computed = gain::computed(&self.path);
+        if length.is_none() && tags.is_none() && computed.is_none() {
+            return Ok((format, length_kind));
+        }
+
+        let mut patched = PatchedFormat::new(format);
+        if let Some(length) = length {
+            patched = patched.with_length(length);
+        }
+        if let Some(tags) = tags {
+            patched = patched.with_tags(tags);
+        }
+        if let Some(computed) = computed {
+            patched = patched.with_replay_gain(computed);
+        }
+        Ok((Box::new(patched), length_kind))
+    }
+
+    /// Returns the format of the file, and the tags found outside of its container
+    /// DCA files are read whole to find their length and index them for seeking when `scan` is set
+    fn probe(&self, scan: bool) -> Result<(Box<dyn FormatReader>, Option<MetadataLog>), NError> {
+        let file = File::open(&self.path)?;
         let media_stream = MediaSourceStream::new(
-            Box::new(Cursor::new(file)),
-            std::default::Default::default(),
+            Box::new(file),
+            MediaSourceStreamOptions {
+                buffer_len: self.read_ahead,
+            },
         );
         let mut hint = Hint::new();
         hint.with_extension(self.ext.as_ref());
         let meta_ops = MetadataOptions::default();
         let fmt_ops = FormatOptions {
             enable_gapless: true,
+            // Only DCA files make use of it
+            prebuild_seek_index: scan,
             ..Default::default()
         };
-        let probed = PROBE
+
+        // DCA0 files are just Opus packets, without any marker the probe could find them by
+        if self.ext.eq_ignore_ascii_case("dca") {
+            let format = DcaReader::try_new(media_stream, &fmt_ops).map_err(NError::Probe)?;
+            return Ok((Box::new(format), None));
+        }
+        let ProbeResult { format, metadata } = PROBE
             .format(&hint, media_stream, &fmt_ops, &meta_ops)
-            .expect("Format not supported");
-        Ok(probed.format)
+            .map_err(NError::Probe)?;
+        Ok((format, metadata.into_inner()))
     }
 
-    pub fn get_meta(&self) -> Result<Metadata, io::Error> {
-        let mut format = self.get_format()?;
-        let track = format.default_track().expect("Can't load tracks");
-        let time_base = track.codec_params.time_base.unwrap();
+    /// Returns the tags, the pictures and the audio properties of the track
+    /// A track whose length isn't known has a length of `0.0`
+    /// DCA files aren't read whole here, so their length may be estimated where `MusicTrack::get_length` is exact
+    pub fn get_meta(&self) -> Result<Metadata, NError> {
+        let mut meta = self.read_meta()?;
+        if meta.title.is_empty() {
+            meta.title = remove_ext(&self.path);
+        }
+        Ok(meta)
+    }
 
-        let duration = track
-            .codec_params
-            .n_frames
-            .map(|frames| track.codec_params.start_ts + frames)
-            .unwrap();
-        let time = time_base.calc_time(duration);
+    /// Same as `MusicTrack::get_meta`, but the title stays empty when the tags don't have one
+    pub(crate) fn read_meta(&self) -> Result<Metadata, NError> {
+        let (mut format, probed) = self.probe(false)?;
 
-        let time = TrackTime {
-            position: 0.0,
-            length: time.seconds as f64 + time.frac,
+        let mut meta = Metadata {
+            time: self.length_of(format.as_ref())?,
+            ..Default::default()
         };
 
-        let mut artist = String::new();
-        let mut title = String::new();
+        let params = &format
+            .default_track()
+            .ok_or(NError::NoPlayableTrack)?
+            .codec_params;
+        meta.codec = CODEC_REGISTRY
+            .get_codec(params.codec)
+            .map(|codec| codec.short_name.to_string());
+        meta.sample_rate = params.sample_rate;
+        meta.bit_depth = params.bits_per_sample;
+        meta.channels = params.channels.map(|channels| channels.count());
+        if meta.time.is_length_known() && meta.time.length > 0.0 {
+            let size = fs::metadata(&self.path)?.len();
+            meta.bitrate = Some((size as f64 * 8.0 / meta.time.length) as u32);
+        }
+
+        // Tags outside of the container (e.g. ID3v2 in MP3 files) are found while probing, the others by the format
+        if let Some(mut probed) = probed {
+            if let Some(revision) = probed.metadata().current() {
+                Self::read_revision(revision, &mut meta);
+            }
+        }
+        if let Some(revision) = format.metadata().skip_to_latest() {
+            Self::read_revision(revision, &mut meta);
+        }
+
+        if let Some(computed) = gain::computed(&self.path) {
+            meta.replay_gain = gain::fill(meta.replay_gain, computed);
+        }
 
-        if let Some(metadata) = format.metadata().skip_to_latest() {
-            for tag in metadata.tags() {
-                if let Some(StandardTagKey::Artist) = tag.std_key {
-                    artist = tag.value.to_string();
-                } else if let Some(StandardTagKey::TrackTitle) = tag.std_key {
-                    title = tag.value.to_string();
+        Ok(meta)
+    }
+
+    fn read_revision(revision: &MetadataRevision, meta: &mut Metadata) {
+        for tag in revision.tags() {
+            if gain::read_tag(tag, &mut meta.replay_gain) {
+                continue;
+            }
+            let Some(key) = tag.std_key else {
+                continue;
+            };
+            let text = tag.value.to_string();
+            match key {
+                StandardTagKey::Artist => meta.artist = text,
+                StandardTagKey::TrackTitle => meta.title = text,
+                StandardTagKey::Album => meta.album = Some(text),
+                StandardTagKey::AlbumArtist => meta.album_artist = Some(text),
+                StandardTagKey::Genre => meta.genre = Some(text),
+                StandardTagKey::Composer => meta.composer = Some(text),
+                StandardTagKey::Comment => meta.comment = Some(text),
+                StandardTagKey::TrackNumber => {
+                    // Often written as "number/total"
+                    let (number, total) = parse_position(&text);
+                    meta.track_number = number.or(meta.track_number);
+                    meta.track_total = total.or(meta.track_total);
+                }
+                StandardTagKey::TrackTotal => meta.track_total = parse_number(&text),
+                StandardTagKey::DiscNumber => {
+                    let (number, total) = parse_position(&text);
+                    meta.disc_number = number.or(meta.disc_number);
+                    meta.disc_total = total.or(meta.disc_total);
                 }
+                StandardTagKey::DiscTotal => meta.disc_total = parse_number(&text),
+                // Dates start with the year, whatever comes after it
+                StandardTagKey::Date | StandardTagKey::OriginalDate
+                    if meta.year.is_none() || key == StandardTagKey::Date =>
+                {
+                    meta.year = text.get(..4).and_then(|year| year.parse().ok());
+                }
+                _ => {}
             }
         }
 
-        if title.is_empty() {
-            title = remove_ext(&self.path);
+        for visual in revision.visuals() {
+            meta.pictures.push(Picture {
+                media_type: visual.media_type.clone(),
+                front_cover: visual.usage == Some(StandardVisualKey::FrontCover),
+                data: visual.data.to_vec(),
+            });
         }
+    }
 
-        Ok(Metadata {
-            time,
-            artist,
-            title,
-        })
+    /// Gives the track the gains computed for it (e.g. with [`crate::loudness::analyze_track`]),
+    /// which are used in place of the gain tags it doesn't have, by every `MusicTrack` of the same file
+    pub fn set_replay_gain(&self, replay_gain: ReplayGain) {
+        gain::remember(&self.path, replay_gain);
     }
 
-    pub fn get_length(&self) -> Result<TrackTime, io::Error> {
-        let format = self.get_format()?;
-        let track = format.default_track().expect("Can't load tracks");
-        let time_base = track.codec_params.time_base.unwrap();
+    /// Returns the length of the track
+    /// When the format doesn't say it, the length of a previous `MusicTrack::scan_length` is used,
+    /// otherwise it gets estimated from the size of the file
+    pub fn get_length(&self) -> Result<TrackTime, NError> {
+        let (format, _) = self.probe(true)?;
+        self.length_of(format.as_ref())
+    }
 
-        let duration = track
-            .codec_params
-            .n_frames
-            .map(|frames| track.codec_params.start_ts + frames)
-            .unwrap();
-        let time = time_base.calc_time(duration);
+    /// Reads the whole track to find its exact length, which is remembered for the next calls to `MusicTrack::get_length`
+    /// It's only needed for formats that don't say how long the track is
+    pub fn scan_length(&self) -> Result<TrackTime, NError> {
+        let (format, _) = self.probe(true)?;
+        let time_base = Self::time_base_of(format.as_ref())?;
+        let length = match duration::from_params(format.as_ref()) {
+            Some(length) => Some(length),
+            None => duration::scan(&self.path, format),
+        };
+        Ok(Self::to_time(time_base, length))
+    }
 
-        Ok(TrackTime {
-            position: 0.0,
-            length: time.seconds as f64 + time.frac,
-        })
+    fn find_length(&self, format: &dyn FormatReader) -> Result<Option<Length>, NError> {
+        if let Some(length) = duration::from_params(format).or_else(|| duration::cached(&self.path))
+        {
+            return Ok(Some(length));
+        }
+        Ok(duration::estimate(self.probe(false)?.0))
+    }
+
+    fn length_of(&self, format: &dyn FormatReader) -> Result<TrackTime, NError> {
+        let time_base = Self::time_base_of(format)?;
+        Ok(Self::to_time(time_base, self.find_length(format)?))
+    }
+
+    fn time_base_of(format: &dyn FormatReader) -> Result<Option<TimeBase>, NError> {
+        let track = format.default_track().ok_or(NError::NoPlayableTrack)?;
+        Ok(track.codec_params.time_base)
+    }
+
+    fn to_time(time_base: Option<TimeBase>, length: Option<Length>) -> TrackTime {
+        match (time_base, length) {
+            (Some(time_base), Some(length)) => {
+                let time = time_base.calc_time(length.frames);
+                TrackTime {
+                    position: 0.0,
+                    length: time.seconds as f64 + time.frac,
+                    length_kind: length.kind,
+                }
+            }
+            _ => TrackTime {
+                length_kind: LengthKind::Unknown,
+                ..Default::default()
+            },
+        }
+    }
+}
+
+/// Parses the number at the start of `text`, ignoring any unit after it (e.g. "-6.5 dB")
+fn parse_number<T: FromStr>(text: &str) -> Option<T> {
+    text.split_whitespace().next()?.parse().ok()
+}
+
+/// Parses positions written as "number/total" or just "number"
+fn parse_position(text: &str) -> (Option<u32>, Option<u32>) {
+    match text.split_once('/') {
+        Some((number, total)) => (parse_number(number), parse_number(total)),
+        None => (parse_number(text), None),
     }
 }
@@ -1,24 +1,70 @@
+use std::os::raw::c_int;
+use std::ptr::{self, NonNull};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
 use audiopus::{
     coder::{Decoder as AudiopusDecoder, GenericCtl},
-    Channels, Error as OpusError, ErrorCode, SampleRate,
+    error::try_map_opus_error,
+    ffi, Channels, Error as OpusError, ErrorCode, Result as OpusResult, SampleRate,
 };
 use symphonia_core::{
-    audio::{AsAudioBufferRef, AudioBuffer, AudioBufferRef, Layout, Signal, SignalSpec},
+    audio::{
+        AsAudioBufferRef, AudioBuffer, AudioBufferRef, Channels as Layout, Signal, SignalSpec,
+    },
     codecs::{
         CodecDescriptor, CodecParameters, Decoder, DecoderOptions, FinalizeResult, CODEC_TYPE_OPUS,
     },
-    errors::{decode_error, Result as SymphResult},
+    errors::{decode_error, unsupported_error, Result as SymphResult},
     formats::Packet,
 };
 
+use crate::convert::channels_for_count;
+
 // Original code from the Songbird project
 
+/// Opus always decodes at 48kHz
+const RATE: u32 = 48000;
+/// Frames in the default 20ms packet, what the buffers are sized for at first
+const DEFAULT_FRAMES: usize = RATE as usize / 50;
+/// Frames in the longest packet Opus allows, 120ms
+const MAX_FRAMES: usize = RATE as usize * 120 / 1000;
+/// Lost audio is concealed in multiples of 2.5ms, the shortest Opus frame
+const MIN_FRAMES: usize = RATE as usize / 400;
+/// Gaps longer than a second are most likely a jump in the stream rather than lost packets, so they aren't concealed
+const MAX_LOST: usize = RATE as usize;
+
 /// Opus decoder for symphonia, based on libopus v1.3 (via [`audiopus`]).
+///
+/// Mono and stereo streams use the plain libopus decoder, streams with more channels its multistream one.
+/// The pre-skip and the output gain of the `OpusHead` are applied, so the samples are the ones the encoder was given.
+///
+/// Empty, corrupt or missing packets don't fail the decoding: the audio they held is concealed by libopus when the next
+/// good packet comes, recovered from the redundant copy it carries when the encoder used in-band FEC.
+/// That audio comes before the one of the good packet, see `OpusDecoder::first_ts`, and what's lost at the end of the
+/// stream is concealed by `OpusDecoder::flush`.
+/// Missing packets are found from gaps in the timestamps, so with formats that number the packets themselves (e.g. DCA)
+/// only the empty and corrupt ones are concealed.
 pub struct OpusDecoder {
-    inner: AudiopusDecoder,
+    inner: InnerDecoder,
     params: CodecParameters,
+    head: OpusHead,
+    /// Index in `buf` of every decoded channel, which libopus orders as Vorbis does
+    order: Vec<usize>,
+    /// Output gain of the header, as a factor
+    gain: f32,
     buf: AudioBuffer<f32>,
     rawbuf: Vec<f32>,
+    /// Where the packet after the last one starts, to find the packets that never arrived
+    next_ts: Option<u64>,
+    /// Frames of the last good packet, how long a lost packet without a duration is taken to be
+    last_frames: usize,
+    /// Frames lost since the last good packet, yet to be concealed
+    lost: usize,
+    /// Timestamp of the first frame in `buf`
+    first_ts: u64,
+    /// How many frames were concealed so far, see `OpusDecoder::count_concealed`
+    concealed: Arc<AtomicU64>,
 }
 
 /// # SAFETY
@@ -31,69 +77,171 @@ pub struct OpusDecoder {
 unsafe impl Sync for OpusDecoder {}
 
 impl OpusDecoder {
-    fn decode_inner(&mut self, packet: &Packet) -> SymphResult<()> {
-        let s_ct = loop {
-            let pkt = if packet.buf().is_empty() {
-                None
-            } else if let Ok(checked_pkt) = packet.buf().try_into() {
-                Some(checked_pkt)
-            } else {
-                return decode_error("Opus packet was too large (greater than i32::MAX bytes).");
-            };
-            let out_space = (&mut self.rawbuf[..]).try_into().expect("The following logic expands this buffer safely below i32::MAX, and we throw our own error.");
-
-            match self.inner.decode_float(pkt, out_space, false) {
-                Ok(v) => break v,
-                Err(OpusError::Opus(ErrorCode::BufferTooSmall)) => {
-                    // double the buffer size
-                    // correct behav would be to mirror the decoder logic in the udp_rx set.
-                    let new_size = (self.rawbuf.len() * 2).min(i32::MAX as usize);
-                    if new_size == self.rawbuf.len() {
-                        return decode_error("Opus frame too big: cannot expand opus frame decode buffer any further.");
-                    }
-
-                    self.rawbuf.resize(new_size, 0.0);
-                    self.buf = AudioBuffer::new(
-                        self.rawbuf.len() as u64 / 2,
-                        SignalSpec::new_with_layout(48000, Layout::Stereo),
-                    );
-                }
-                Err(_) => {
-                    return decode_error("Opus decode error: see 'tracing' logs.");
-                }
-            }
+    /// Adds the frames this decoder conceals to `counter`, which can be shared with other decoders
+    pub fn count_concealed(mut self, counter: Arc<AtomicU64>) -> Self {
+        self.concealed = counter;
+        self
+    }
+
+    /// Timestamp of the first frame of the last decoded audio
+    /// It's before the timestamp of the packet when the audio of the packets lost before it was concealed
+    pub fn first_ts(&self) -> u64 {
+        self.first_ts
+    }
+
+    /// Conceals the audio lost after the last good packet, as the end of the stream was reached before the next one
+    pub fn flush(&mut self) -> AudioBufferRef<'_> {
+        let first_ts = self.next_ts.unwrap_or(0).saturating_sub(self.lost as u64);
+        let frames = if self.lost > MAX_LOST {
+            self.lost = 0;
+            0
+        } else {
+            self.conceal(None)
+        };
+        self.fill_buf(first_ts, frames);
+        self.buf.as_audio_buffer_ref()
+    }
+
+    /// Conceals the frames lost so far at the start of `rawbuf`, recovering them from `packet` when it has them
+    /// Returns how many frames were concealed
+    fn conceal(&mut self, packet: Option<&[u8]>) -> usize {
+        if self.lost == 0 {
+            return 0;
+        }
+        let channels = self.head.channels as usize;
+        let lost = (self.lost / MIN_FRAMES * MIN_FRAMES).max(MIN_FRAMES);
+        self.lost = 0;
+        if self.rawbuf.len() < (lost + MAX_FRAMES) * channels {
+            self.rawbuf.resize((lost + MAX_FRAMES) * channels, 0.0);
+        }
+        let out = &mut self.rawbuf[..lost * channels];
+        let concealed = match packet.map(|packet| self.inner.decode(Some(packet), out, true)) {
+            Some(Ok(frames)) => frames,
+            _ => self.inner.decode(None, out, false).unwrap_or(0),
         };
+        self.concealed
+            .fetch_add(concealed as u64, Ordering::Relaxed);
+        concealed
+    }
+
+    /// Puts the first `frames` frames of `rawbuf` in `buf`, which start at `first_ts`
+    fn fill_buf(&mut self, first_ts: u64, frames: usize) {
+        let channels = self.head.channels as usize;
+        // The first frames of the stream only prime the decoder
+        let skip = self
+            .head
+            .pre_skip
+            .saturating_sub(first_ts)
+            .min(frames as u64) as usize;
+        self.first_ts = first_ts + skip as u64;
 
+        if self.buf.capacity() < frames {
+            self.buf = AudioBuffer::new(frames as u64, *self.buf.spec());
+        }
         self.buf.clear();
-        self.buf.render_reserved(Some(s_ct));
+        self.buf.render_reserved(Some(frames - skip));
 
-        // Forcibly assuming stereo, for now.
-        for ch in 0..2 {
-            let iter = self.rawbuf.chunks_exact(2).map(|chunk| chunk[ch]);
-            for (tgt, src) in self.buf.chan_mut(ch).iter_mut().zip(iter) {
+        for (ch, &index) in self.order.iter().enumerate() {
+            let iter = self.rawbuf[..frames * channels]
+                .chunks_exact(channels)
+                .skip(skip)
+                .map(|chunk| chunk[ch]);
+            for (tgt, src) in self.buf.chan_mut(index).iter_mut().zip(iter) {
                 *tgt = src;
             }
         }
 
+        if self.gain != 1.0 {
+            let gain = self.gain;
+            self.buf.transform(|sample| sample * gain);
+        }
+    }
+
+    fn decode_inner(&mut self, packet: &Packet) -> SymphResult<()> {
+        let channels = self.head.channels as usize;
+        let data = packet.buf();
+        if i32::try_from(data.len()).is_err() {
+            return decode_error("Opus packet was too large (greater than i32::MAX bytes).");
+        }
+
+        let dur = match packet.dur() as usize {
+            0 => self.last_frames,
+            dur => dur,
+        };
+        // A gap in the timestamps means that packets went missing
+        if let Some(next_ts) = self.next_ts {
+            self.lost += packet.ts().saturating_sub(next_ts) as usize;
+        }
+        self.next_ts = Some(packet.ts() + dur as u64);
+
+        let mut frames = 0;
+        if !data.is_empty() {
+            if self.lost > MAX_LOST {
+                self.lost = 0;
+            }
+            // The end of the lost audio may still be in this packet, the rest is made up
+            frames = self.conceal(Some(data));
+
+            if self.rawbuf.len() < (frames + MAX_FRAMES) * channels {
+                self.rawbuf.resize((frames + MAX_FRAMES) * channels, 0.0);
+            }
+            let out = &mut self.rawbuf[frames * channels..(frames + MAX_FRAMES) * channels];
+            match self.inner.decode(Some(data), out, false) {
+                Ok(decoded) => {
+                    self.last_frames = decoded;
+                    self.fill_buf(packet.ts().saturating_sub(frames as u64), frames + decoded);
+                    return Ok(());
+                }
+                // It's concealed along with the next packet
+                Err(_) => self.lost += dur,
+            }
+        } else {
+            self.lost += dur;
+        }
+        self.fill_buf(packet.ts().saturating_sub(frames as u64), frames);
+
         Ok(())
     }
 }
 
 impl Decoder for OpusDecoder {
     fn try_new(params: &CodecParameters, _options: &DecoderOptions) -> SymphResult<Self> {
-        let inner = AudiopusDecoder::new(SampleRate::Hz48000, Channels::Stereo).unwrap();
+        // Streams without a header (e.g. DCA) are mono or stereo
+        let head = match params.extra_data.as_deref().and_then(OpusHead::parse) {
+            Some(head) => head,
+            None => OpusHead::for_channels(params.channels.map_or(2, |channels| channels.count())),
+        };
 
+        let inner = match (head.family, head.channels) {
+            (0, 1) => {
+                AudiopusDecoder::new(SampleRate::Hz48000, Channels::Mono).map(InnerDecoder::Single)
+            }
+            (0, _) => AudiopusDecoder::new(SampleRate::Hz48000, Channels::Stereo)
+                .map(InnerDecoder::Single),
+            _ => MultistreamDecoder::new(&head).map(InnerDecoder::Multi),
+        };
+        let Ok(inner) = inner else {
+            return unsupported_error("Opus: the channels of the stream can't be decoded");
+        };
+
+        let (layout, order) = head.layout();
         let mut params = params.clone();
-        params.with_sample_rate(48000);
+        params.with_sample_rate(RATE).with_channels(layout);
 
+        let channels = head.channels as usize;
         Ok(Self {
             inner,
             params,
-            buf: AudioBuffer::new(
-                48000 / 50,
-                SignalSpec::new_with_layout(48000, Layout::Stereo),
-            ),
-            rawbuf: vec![0.0f32; 2 * (48000 / 50)],
+            order,
+            gain: 10f32.powf(head.output_gain / 20.0),
+            head,
+            buf: AudioBuffer::new(DEFAULT_FRAMES as u64, SignalSpec::new(RATE, layout)),
+            rawbuf: vec![0.0f32; channels * MAX_FRAMES],
+            next_ts: None,
+            last_frames: DEFAULT_FRAMES,
+            lost: 0,
+            first_ts: 0,
+            concealed: Arc::default(),
         })
     }
 
@@ -106,7 +254,10 @@ impl Decoder for OpusDecoder {
     }
 
     fn reset(&mut self) {
-        _ = self.inner.reset_state();
+        self.inner.reset();
+        // Whatever was lost before a seek isn't played anymore
+        self.next_ts = None;
+        self.lost = 0;
     }
 
     fn codec_params(&self) -> &CodecParameters {
@@ -130,3 +281,284 @@ impl Decoder for OpusDecoder {
         self.buf.as_audio_buffer_ref()
     }
 }
+
+/// The identification header of an Ogg Opus stream, see RFC 7845
+#[derive(Clone, Debug)]
+struct OpusHead {
+    channels: u8,
+    /// Frames at the start of the stream that are decoded and then thrown away
+    pre_skip: u64,
+    /// In dB
+    output_gain: f32,
+    /// How the decoded channels are laid out, 0 is mono or stereo and 1 the Vorbis order
+    family: u8,
+    streams: u8,
+    /// How many of the streams are stereo, they come first
+    coupled: u8,
+    /// The stream channel each output channel comes from
+    mapping: Vec<u8>,
+}
+
+impl OpusHead {
+    fn parse(data: &[u8]) -> Option<Self> {
+        if data.len() < 19 || !data.starts_with(b"OpusHead") {
+            return None;
+        }
+        let channels = data[9];
+        let family = data[18];
+        let (streams, coupled, mapping) = if family == 0 {
+            let head = Self::for_channels(channels as usize);
+            (head.streams, head.coupled, head.mapping)
+        } else {
+            let mapping = data.get(21..21 + channels as usize)?;
+            (data[19], data[20], mapping.to_vec())
+        };
+        if channels == 0 || (family == 0 && channels > 2) {
+            return None;
+        }
+
+        Some(OpusHead {
+            channels,
+            pre_skip: u16::from_le_bytes([data[10], data[11]]) as u64,
+            output_gain: i16::from_le_bytes([data[16], data[17]]) as f32 / 256.0,
+            family,
+            streams,
+            coupled,
+            mapping,
+        })
+    }
+
+    /// A single mono or stereo stream, without any pre-skip or gain
+    fn for_channels(channels: usize) -> Self {
+        let channels = channels.clamp(1, 2) as u8;
+        OpusHead {
+            channels,
+            pre_skip: 0,
+            output_gain: 0.0,
+            family: 0,
+            streams: 1,
+            coupled: channels - 1,
+            mapping: (0..channels).collect(),
+        }
+    }
+
+    /// Returns the layout of the decoded audio, and where every decoded channel goes in it
+    fn layout(&self) -> (Layout, Vec<usize>) {
+        let vorbis: &[Layout] = match self.channels {
+            1 => &[Layout::FRONT_LEFT],
+            2 => &[Layout::FRONT_LEFT, Layout::FRONT_RIGHT],
+            3 => &[
+                Layout::FRONT_LEFT,
+                Layout::FRONT_CENTRE,
+                Layout::FRONT_RIGHT,
+            ],
+            4 => &[
+                Layout::FRONT_LEFT,
+                Layout::FRONT_RIGHT,
+                Layout::REAR_LEFT,
+                Layout::REAR_RIGHT,
+            ],
+            5 => &[
+                Layout::FRONT_LEFT,
+                Layout::FRONT_CENTRE,
+                Layout::FRONT_RIGHT,
+                Layout::REAR_LEFT,
+                Layout::REAR_RIGHT,
+            ],
+            6 => &[
+                Layout::FRONT_LEFT,
+                Layout::FRONT_CENTRE,
+                Layout::FRONT_RIGHT,
+                Layout::REAR_LEFT,
+                Layout::REAR_RIGHT,
+                Layout::LFE1,
+            ],
+            7 => &[
+                Layout::FRONT_LEFT,
+                Layout::FRONT_CENTRE,
+                Layout::FRONT_RIGHT,
+                Layout::SIDE_LEFT,
+                Layout::SIDE_RIGHT,
+                Layout::REAR_CENTRE,
+                Layout::LFE1,
+            ],
+            8 => &[
+                Layout::FRONT_LEFT,
+                Layout::FRONT_CENTRE,
+                Layout::FRONT_RIGHT,
+                Layout::SIDE_LEFT,
+                Layout::SIDE_RIGHT,
+                Layout::REAR_LEFT,
+                Layout::REAR_RIGHT,
+                Layout::LFE1,
+            ],
+            _ => &[],
+        };
+
+        // Other families have no positions, the channels are kept in order
+        if self.family > 1 || vorbis.is_empty() {
+            let channels = self.channels as usize;
+            return (channels_for_count(channels), (0..channels).collect());
+        }
+
+        let layout = vorbis
+            .iter()
+            .fold(Layout::empty(), |layout, ch| layout | *ch);
+        // Symphonia orders the channels by their position
+        let order = vorbis
+            .iter()
+            .map(|ch| (layout.bits() & (ch.bits() - 1)).count_ones() as usize)
+            .collect();
+        (layout, order)
+    }
+}
+
+enum InnerDecoder {
+    Single(AudiopusDecoder),
+    Multi(MultistreamDecoder),
+}
+
+impl InnerDecoder {
+    /// Decodes `packet` into `out` as interleaved samples, returning how many frames there are
+    /// Without a packet, or with `fec`, exactly as many frames as `out` holds are concealed (or recovered from the packet)
+    fn decode(&mut self, packet: Option<&[u8]>, out: &mut [f32], fec: bool) -> OpusResult<usize> {
+        match self {
+            InnerDecoder::Single(decoder) => {
+                let packet = packet.map(|packet| packet.try_into()).transpose()?;
+                decoder.decode_float(packet, out.try_into()?, fec)
+            }
+            InnerDecoder::Multi(decoder) => decoder.decode(packet, out, fec),
+        }
+    }
+
+    fn reset(&mut self) {
+        match self {
+            InnerDecoder::Single(decoder) => _ = decoder.reset_state(),
+            InnerDecoder::Multi(decoder) => decoder.reset(),
+        }
+    }
+}
+
+/// The libopus multistream decoder, which [`audiopus`] doesn't wrap
+struct MultistreamDecoder {
+    pointer: NonNull<ffi::OpusMSDecoder>,
+    channels: usize,
+}
+
+// The decoder state is only touched through `&mut self`, see `OpusDecoder`
+unsafe impl Send for MultistreamDecoder {}
+
+impl MultistreamDecoder {
+    fn new(head: &OpusHead) -> OpusResult<Self> {
+        let mut error = 0;
+        // SAFETY: the mapping has an entry for every channel, libopus checks that the rest is consistent
+        let pointer = unsafe {
+            ffi::opus_multistream_decoder_create(
+                RATE as i32,
+                head.channels as c_int,
+                head.streams as c_int,
+                head.coupled as c_int,
+                head.mapping.as_ptr(),
+                &mut error,
+            )
+        };
+        try_map_opus_error(error)?;
+        let pointer = NonNull::new(pointer).ok_or(OpusError::Opus(ErrorCode::AllocFail))?;
+        Ok(MultistreamDecoder {
+            pointer,
+            channels: head.channels as usize,
+        })
+    }
+
+    fn decode(&mut self, packet: Option<&[u8]>, out: &mut [f32], fec: bool) -> OpusResult<usize> {
+        let packet = packet.unwrap_or_default();
+        let data = if packet.is_empty() {
+            ptr::null()
+        } else {
+            packet.as_ptr()
+        };
+        // SAFETY: `out` has room for the number of frames given, and the decoder is valid until dropped
+        let frames = unsafe {
+            ffi::opus_multistream_decode_float(
+                self.pointer.as_ptr(),
+                data,
+                packet.len() as i32,
+                out.as_mut_ptr(),
+                (out.len() / self.channels) as c_int,
+                fec as c_int,
+            )
+        };
+        try_map_opus_error(frames).map(|frames| frames as usize)
+    }
+
+    fn reset(&mut self) {
+        // SAFETY: the decoder is valid until dropped
+        unsafe {
+            ffi::opus_multistream_decoder_ctl(self.pointer.as_ptr(), ffi::OPUS_RESET_STATE);
+        }
+    }
+}
+
+impl Drop for MultistreamDecoder {
+    fn drop(&mut self) {
+        // SAFETY: the decoder was created by `opus_multistream_decoder_create` and isn't used anymore
+        unsafe { ffi::opus_multistream_decoder_destroy(self.pointer.as_ptr()) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use audiopus::coder::Encoder;
+    use audiopus::Application;
+
+    /// Returns a stereo Opus packet of `DEFAULT_FRAMES` frames of silence
+    fn packet() -> Vec<u8> {
+        let encoder =
+            Encoder::new(SampleRate::Hz48000, Channels::Stereo, Application::Audio).unwrap();
+        let mut packet = vec![0; 4000];
+        let len = encoder
+            .encode_float(&[0.0; DEFAULT_FRAMES * 2], &mut packet)
+            .unwrap();
+        packet.truncate(len);
+        packet
+    }
+
+    fn decoder() -> OpusDecoder {
+        let mut params = CodecParameters::new();
+        params
+            .for_codec(CODEC_TYPE_OPUS)
+            .with_channels(Layout::FRONT_LEFT | Layout::FRONT_RIGHT);
+        OpusDecoder::try_new(&params, &DecoderOptions::default()).unwrap()
+    }
+
+    fn decode(decoder: &mut OpusDecoder, index: u64, data: Vec<u8>) -> usize {
+        let ts = index * DEFAULT_FRAMES as u64;
+        let packet = Packet::new_from_boxed_slice(0, ts, DEFAULT_FRAMES as u64, data.into());
+        decoder.decode(&packet).unwrap().frames()
+    }
+
+    #[test]
+    fn concealed_audio_starts_where_it_was_lost() {
+        let mut decoder = decoder();
+        assert_eq!(decode(&mut decoder, 0, packet()), DEFAULT_FRAMES);
+        assert_eq!(decoder.first_ts(), 0);
+
+        // An empty packet, then a missing one
+        assert_eq!(decode(&mut decoder, 1, vec![]), 0);
+        assert_eq!(decode(&mut decoder, 3, packet()), 3 * DEFAULT_FRAMES);
+        assert_eq!(decoder.first_ts(), DEFAULT_FRAMES as u64);
+    }
+
+    #[test]
+    fn flush_conceals_the_lost_end() {
+        let mut decoder = decoder();
+        decode(&mut decoder, 0, packet());
+        decode(&mut decoder, 1, vec![]);
+
+        assert_eq!(decoder.flush().frames(), DEFAULT_FRAMES);
+        assert_eq!(decoder.first_ts(), DEFAULT_FRAMES as u64);
+        // Nothing is left once flushed
+        assert_eq!(decoder.flush().frames(), 0);
+    }
+}
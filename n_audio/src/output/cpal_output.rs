@@ -0,0 +1,378 @@
+//! Platform-dependant Audio Outputs, through cpal
+
+/// This is a modified version of [symphonia-play's `output.rs`](https://github.com/pdeljanov/Symphonia/blob/master/symphonia-play/src/output.rs)
+/// It was originally made by [Philip Deljanov](https://github.com/pdeljanov)
+/// Modifications: support for volume (for all platforms)
+/// Modifications: support for custom name app (only for PulseAudio)
+/// Modifications: completely removed pulseaudio in 1.3.0
+/// Modifications: the device is opened with its default config, the audio gets converted to it in `convert`
+/// Modifications: every sample format of cpal, with dithering for the ones with less than 24 bits
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::convert::{channels_for_count, Dither};
+use crate::device::find_device;
+use crate::output::{AudioOutput, AudioOutputError, OutputBackend, Result};
+use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use dasp::sample::FromSample;
+use dasp::Sample;
+use rb::*;
+use symphonia::core::audio::SignalSpec;
+
+/// Value of `CpalAudioOutputImpl::discard` when nothing has to be discarded
+const NO_DISCARD: usize = usize::MAX;
+/// How long `AudioOutput::discard` waits for the device to drop the samples
+const DISCARD_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// The default backend, playing the audio through the devices of the system
+#[derive(Copy, Clone, Debug, Default)]
+pub struct CpalAudioOutput;
+
+impl OutputBackend for CpalAudioOutput {
+    fn open(&self, device_name: Option<&str>) -> Result<Box<dyn AudioOutput>> {
+        Self::try_open(device_name)
+    }
+}
+
+trait AudioOutputSample:
+    Sample + FromSample<f32> + cpal::SizedSample + Default + Send + 'static
+{
+    /// Bits of precision of the format
+    const BITS: u32;
+}
+
+impl AudioOutputSample for i8 {
+    const BITS: u32 = 8;
+}
+
+impl AudioOutputSample for i16 {
+    const BITS: u32 = 16;
+}
+
+impl AudioOutputSample for i32 {
+    const BITS: u32 = 32;
+}
+
+impl AudioOutputSample for i64 {
+    const BITS: u32 = 64;
+}
+
+impl AudioOutputSample for u8 {
+    const BITS: u32 = 8;
+}
+
+impl AudioOutputSample for u16 {
+    const BITS: u32 = 16;
+}
+
+impl AudioOutputSample for u32 {
+    const BITS: u32 = 32;
+}
+
+impl AudioOutputSample for u64 {
+    const BITS: u32 = 64;
+}
+
+impl AudioOutputSample for f32 {
+    const BITS: u32 = f32::MANTISSA_DIGITS;
+}
+
+impl AudioOutputSample for f64 {
+    const BITS: u32 = f64::MANTISSA_DIGITS;
+}
+
+impl CpalAudioOutput {
+    /// Opens the output device with the given name, falling back to the default one if it can't be found
+    pub fn try_open(device_name: Option<&str>) -> Result<Box<dyn AudioOutput>> {
+        let device = match device_name.and_then(find_device) {
+            Some(device) => device,
+            None => {
+                if let Some(name) = device_name {
+                    eprintln!("Output device {name} not found, using the default one");
+                }
+
+                // Get the default audio output device.
+                match cpal::default_host().default_output_device() {
+                    Some(device) => device,
+                    _ => {
+                        eprintln!("Failed to get default audio output device");
+                        return Err(AudioOutputError::OpenStreamError);
+                    }
+                }
+            }
+        };
+
+        let config = match device.default_output_config() {
+            Ok(config) => config,
+            Err(err) => {
+                eprintln!(
+                    "Failed to get default audio output device config: {:?}",
+                    err
+                );
+                return Err(AudioOutputError::OpenStreamError);
+            }
+        };
+
+        // Select proper playback routine based on sample format.
+        match config.sample_format() {
+            cpal::SampleFormat::I8 => CpalAudioOutputImpl::<i8>::try_open(&config, &device),
+            cpal::SampleFormat::I16 => CpalAudioOutputImpl::<i16>::try_open(&config, &device),
+            cpal::SampleFormat::I32 => CpalAudioOutputImpl::<i32>::try_open(&config, &device),
+            cpal::SampleFormat::I64 => CpalAudioOutputImpl::<i64>::try_open(&config, &device),
+            cpal::SampleFormat::U8 => CpalAudioOutputImpl::<u8>::try_open(&config, &device),
+            cpal::SampleFormat::U16 => CpalAudioOutputImpl::<u16>::try_open(&config, &device),
+            cpal::SampleFormat::U32 => CpalAudioOutputImpl::<u32>::try_open(&config, &device),
+            cpal::SampleFormat::U64 => CpalAudioOutputImpl::<u64>::try_open(&config, &device),
+            cpal::SampleFormat::F32 => CpalAudioOutputImpl::<f32>::try_open(&config, &device),
+            cpal::SampleFormat::F64 => CpalAudioOutputImpl::<f64>::try_open(&config, &device),
+            format => {
+                eprintln!("Unsupported sample format of the output device: {format}");
+                Err(AudioOutputError::OpenStreamError)
+            }
+        }
+    }
+}
+
+struct CpalAudioOutputImpl<T: AudioOutputSample>
+where
+    T: AudioOutputSample,
+{
+    ring_buf: SpscRb<T>,
+    ring_buf_producer: Producer<T>,
+    closed: Arc<AtomicBool>,
+    /// Frames to fade out before dropping the rest of the ring buffer, read by the stream callback
+    discard: Arc<AtomicUsize>,
+    spec: SignalSpec,
+    samples: Vec<T>,
+    /// Only used when the device has less precision than the samples
+    dither: Option<Dither>,
+    stream: cpal::Stream,
+}
+
+impl<T: AudioOutputSample> CpalAudioOutputImpl<T> {
+    pub fn try_open(
+        config: &cpal::SupportedStreamConfig,
+        device: &cpal::Device,
+    ) -> Result<Box<dyn AudioOutput>> {
+        // The device is opened with its own configuration, the audio is converted to it beforehand.
+        let config = config.config();
+        let num_channels = config.channels as usize;
+        let spec = SignalSpec::new(config.sample_rate.0, channels_for_count(num_channels));
+
+        // Create a ring buffer with a capacity for up-to 200ms of audio.
+        let ring_len = ((200 * spec.rate as usize) / 1000) * num_channels;
+
+        let ring_buf = SpscRb::new(ring_len);
+        let (ring_buf_producer, ring_buf_consumer) = (ring_buf.producer(), ring_buf.consumer());
+
+        // Set when the device goes away, as nothing would ever read from the ring buffer again
+        let closed = Arc::new(AtomicBool::new(false));
+        let stream_closed = closed.clone();
+        let discard = Arc::new(AtomicUsize::new(NO_DISCARD));
+        let stream_discard = discard.clone();
+        let mut fading = FadeOut::new(ring_len, num_channels);
+
+        let stream_result = device.build_output_stream(
+            &config,
+            move |data: &mut [T], _: &cpal::OutputCallbackInfo| {
+                let fade = stream_discard.load(Ordering::Acquire);
+                if fade != NO_DISCARD {
+                    fading.start(&ring_buf_consumer, fade);
+                    stream_discard.store(NO_DISCARD, Ordering::Release);
+                }
+                // Finish the fade out first, it can be longer than a callback
+                let mut written = fading.play(data);
+                if written < data.len() {
+                    // Write out as many samples as possible from the ring buffer to the audio
+                    // output.
+                    written += ring_buf_consumer.read(&mut data[written..]).unwrap_or(0);
+                }
+                // Mute any remaining samples.
+                data[written..].iter_mut().for_each(|s| *s = T::EQUILIBRIUM);
+            },
+            move |err| {
+                eprintln!("audio output error: {:?}", err);
+                if let cpal::StreamError::DeviceNotAvailable = err {
+                    stream_closed.store(true, Ordering::Relaxed);
+                }
+            },
+            None,
+        );
+
+        if let Err(err) = stream_result {
+            eprintln!("audio output stream open error: {:?}", err);
+
+            return Err(AudioOutputError::OpenStreamError);
+        }
+
+        let stream = stream_result.unwrap();
+
+        // Start the output stream.
+        if let Err(err) = stream.play() {
+            eprintln!("audio output stream play error: {:?}", err);
+
+            return Err(AudioOutputError::PlayStreamError);
+        }
+
+        Ok(Box::new(CpalAudioOutputImpl {
+            ring_buf,
+            ring_buf_producer,
+            closed,
+            discard,
+            spec,
+            samples: vec![],
+            dither: Dither::for_bits(T::BITS),
+            stream,
+        }))
+    }
+}
+
+impl<T: AudioOutputSample> AudioOutput for CpalAudioOutputImpl<T> {
+    fn spec(&self) -> SignalSpec {
+        self.spec
+    }
+
+    fn write(&mut self, samples: &[f32]) -> Result<()> {
+        // Do nothing if there are no audio frames.
+        if samples.is_empty() {
+            return Ok(());
+        }
+
+        // Convert the samples to the format of the device.
+        self.samples.clear();
+        match &mut self.dither {
+            Some(dither) => self.samples.extend(
+                samples
+                    .iter()
+                    .map(|sample| T::from_sample(*sample + dither.noise())),
+            ),
+            None => self
+                .samples
+                .extend(samples.iter().map(|sample| T::from_sample(*sample))),
+        }
+
+        // Write all the samples to the ring buffer, waiting for the device to consume them.
+        let mut samples = self.samples.as_slice();
+        while !samples.is_empty() {
+            if self.closed.load(Ordering::Relaxed) {
+                return Err(AudioOutputError::StreamClosedError);
+            }
+            match self.ring_buf_producer.write(samples) {
+                Ok(written) => samples = &samples[written..],
+                Err(_) => thread::sleep(Duration::from_millis(5)),
+            }
+        }
+
+        Ok(())
+    }
+
+    fn flush(&mut self) {
+        // Flush is best-effort, ignore the returned result.
+        let _ = self.stream.pause();
+    }
+
+    fn delay(&self) -> usize {
+        self.ring_buf.count() / self.spec.channels.count()
+    }
+
+    fn discard(&mut self, fade: usize) {
+        self.discard.store(fade, Ordering::Release);
+        // Wait for the callback to drop the samples, or it could drop the ones written afterwards too
+        let start = Instant::now();
+        while start.elapsed() < DISCARD_TIMEOUT {
+            if self.discard.load(Ordering::Acquire) == NO_DISCARD {
+                return;
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+        // The stream isn't running, so nothing is being played
+        if self.discard.swap(NO_DISCARD, Ordering::AcqRel) != NO_DISCARD {
+            self.ring_buf.clear();
+        }
+    }
+}
+
+/// Samples taken out of the ring buffer by `AudioOutput::discard`, played while they fade out
+struct FadeOut<T> {
+    samples: Vec<T>,
+    /// How many of `samples` were already played
+    played: usize,
+    channels: usize,
+}
+
+impl<T: AudioOutputSample> FadeOut<T> {
+    /// Allocates room for `capacity` samples up front, as the stream callback mustn't allocate
+    fn new(capacity: usize, channels: usize) -> Self {
+        Self {
+            samples: Vec::with_capacity(capacity),
+            played: 0,
+            channels,
+        }
+    }
+
+    /// Takes the next `frames` frames out of `consumer` to fade them out, and drops the others
+    fn start(&mut self, consumer: &impl RbConsumer<T>, frames: usize) {
+        let len = frames
+            .saturating_mul(self.channels)
+            .min(self.samples.capacity());
+        self.samples.clear();
+        self.samples.resize(len, T::EQUILIBRIUM);
+        let read = consumer.read(&mut self.samples).unwrap_or(0);
+        self.samples.truncate(read);
+        fade_out(&mut self.samples, self.channels);
+        self.played = 0;
+        let _ = consumer.skip_pending();
+    }
+
+    /// Writes what's left of the fade to the start of `data`, returning how many samples were written
+    fn play(&mut self, data: &mut [T]) -> usize {
+        let len = (self.samples.len() - self.played).min(data.len());
+        data[..len].copy_from_slice(&self.samples[self.played..self.played + len]);
+        self.played += len;
+        len
+    }
+}
+
+/// Lowers the interleaved samples linearly down to silence
+fn fade_out<T: AudioOutputSample>(samples: &mut [T], channels: usize) {
+    let frames = samples.len() / channels;
+    for (i, frame) in samples.chunks_exact_mut(channels).enumerate() {
+        let gain = (frames - i - 1) as f32 / frames as f32;
+        for sample in frame {
+            *sample = sample.mul_amp(<T::Float as Sample>::from_sample(gain));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fade_out_lasts_across_callbacks() {
+        let ring = SpscRb::<f32>::new(64);
+        let (producer, consumer) = (ring.producer(), ring.consumer());
+        producer.write(&[1.0; 40]).unwrap();
+        let mut fading = FadeOut::new(64, 2);
+
+        fading.start(&consumer, 10);
+        // Only the faded frames are left to play
+        assert!(ring.is_empty());
+
+        let mut played = vec![];
+        let mut data = [0.0; 6];
+        loop {
+            let written = fading.play(&mut data);
+            if written == 0 {
+                break;
+            }
+            played.extend_from_slice(&data[..written]);
+        }
+        assert_eq!(played.len(), 20);
+        let gains: Vec<f32> = played.chunks_exact(2).map(|frame| frame[0]).collect();
+        assert!(gains.windows(2).all(|pair| pair[1] < pair[0]));
+        assert_eq!(gains[9], 0.0);
+    }
+}
@@ -0,0 +1,103 @@
+//! Outputs that don't play anything, useful for tests and headless environments
+
+use std::sync::{Arc, Mutex};
+
+use symphonia::core::audio::SignalSpec;
+
+use crate::convert::channels_for_count;
+use crate::output::{AudioOutput, OutputBackend, Result};
+
+/// Backend that throws away everything written to it
+#[derive(Copy, Clone, Debug)]
+pub struct NullOutput {
+    spec: SignalSpec,
+}
+
+impl NullOutput {
+    pub fn new(rate: u32, channels: usize) -> Self {
+        NullOutput {
+            spec: SignalSpec::new(rate, channels_for_count(channels)),
+        }
+    }
+}
+
+impl Default for NullOutput {
+    fn default() -> Self {
+        Self::new(44100, 2)
+    }
+}
+
+impl OutputBackend for NullOutput {
+    fn open(&self, _device_name: Option<&str>) -> Result<Box<dyn AudioOutput>> {
+        Ok(Box::new(*self))
+    }
+}
+
+impl AudioOutput for NullOutput {
+    fn spec(&self) -> SignalSpec {
+        self.spec
+    }
+
+    fn write(&mut self, _samples: &[f32]) -> Result<()> {
+        Ok(())
+    }
+
+    fn flush(&mut self) {}
+}
+
+/// Backend that keeps in memory everything written to it
+///
+/// Clones share the same samples, so a clone can be given to the player and the other one read from
+#[derive(Clone, Debug)]
+pub struct CaptureOutput {
+    spec: SignalSpec,
+    samples: Arc<Mutex<Vec<f32>>>,
+}
+
+impl CaptureOutput {
+    pub fn new(rate: u32, channels: usize) -> Self {
+        CaptureOutput {
+            spec: SignalSpec::new(rate, channels_for_count(channels)),
+            samples: Arc::new(Mutex::new(vec![])),
+        }
+    }
+
+    pub fn spec(&self) -> SignalSpec {
+        self.spec
+    }
+
+    /// Returns the interleaved samples written so far
+    pub fn samples(&self) -> Vec<f32> {
+        self.samples.lock().unwrap().clone()
+    }
+
+    /// Returns and forgets the interleaved samples written so far
+    pub fn take_samples(&self) -> Vec<f32> {
+        std::mem::take(&mut *self.samples.lock().unwrap())
+    }
+}
+
+impl Default for CaptureOutput {
+    fn default() -> Self {
+        Self::new(44100, 2)
+    }
+}
+
+impl OutputBackend for CaptureOutput {
+    fn open(&self, _device_name: Option<&str>) -> Result<Box<dyn AudioOutput>> {
+        Ok(Box::new(self.clone()))
+    }
+}
+
+impl AudioOutput for CaptureOutput {
+    fn spec(&self) -> SignalSpec {
+        self.spec
+    }
+
+    fn write(&mut self, samples: &[f32]) -> Result<()> {
+        self.samples.lock().unwrap().extend_from_slice(samples);
+        Ok(())
+    }
+
+    fn flush(&mut self) {}
+}
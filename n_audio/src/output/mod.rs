@@ -0,0 +1,77 @@
+//! Audio outputs the player can write the decoded audio to
+//!
+//! The default one plays through the devices of the system ([`CpalAudioOutput`]),
+//! other backends can be plugged in with [`crate::player::Player::builder`]
+
+use std::fmt::{Debug, Display, Formatter};
+use std::{error, fmt, io, result};
+
+use symphonia::core::audio::SignalSpec;
+
+mod cpal_output;
+mod memory;
+mod wav;
+
+pub use cpal_output::CpalAudioOutput;
+pub use memory::{CaptureOutput, NullOutput};
+pub use wav::WavOutput;
+
+pub trait AudioOutput {
+    /// The signal spec the samples given to `AudioOutput::write` must be in
+    fn spec(&self) -> SignalSpec;
+    /// Writes interleaved samples, blocking until there's enough space for them
+    fn write(&mut self, samples: &[f32]) -> Result<()>;
+    fn flush(&mut self);
+    /// Throws away the samples that were written but haven't been played yet
+    /// The first `fade` frames of them are faded out instead, when the output can, so that the audio doesn't stop abruptly
+    fn discard(&mut self, _fade: usize) {}
+    /// How many of the frames that were written haven't been played yet
+    fn delay(&self) -> usize {
+        0
+    }
+}
+
+/// Something that can open an [`AudioOutput`], given the name of the device to use
+///
+/// Backends that don't have devices can ignore the name
+pub trait OutputBackend: Send + Sync + Debug {
+    fn open(&self, device_name: Option<&str>) -> Result<Box<dyn AudioOutput>>;
+}
+
+#[allow(dead_code)]
+#[allow(clippy::enum_variant_names)]
+#[derive(Debug)]
+pub enum AudioOutputError {
+    OpenStreamError,
+    PlayStreamError,
+    StreamClosedError,
+    IoError(io::Error),
+}
+
+impl Display for AudioOutputError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            AudioOutputError::OpenStreamError => write!(f, "can't open the output stream"),
+            AudioOutputError::PlayStreamError => write!(f, "can't play the output stream"),
+            AudioOutputError::StreamClosedError => write!(f, "the output stream was closed"),
+            AudioOutputError::IoError(err) => write!(f, "{err}"),
+        }
+    }
+}
+
+impl error::Error for AudioOutputError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            AudioOutputError::IoError(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for AudioOutputError {
+    fn from(value: io::Error) -> Self {
+        Self::IoError(value)
+    }
+}
+
+pub type Result<T> = result::Result<T, AudioOutputError>;
@@ -0,0 +1,111 @@
+//! Output that renders the audio to a WAV file instead of playing it
+
+use std::fs::File;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+
+use symphonia::core::audio::SignalSpec;
+
+use crate::convert::channels_for_count;
+use crate::output::{AudioOutput, OutputBackend, Result};
+
+/// `WAVE_FORMAT_IEEE_FLOAT`, the samples are written as they are
+const FORMAT_FLOAT: u16 = 3;
+const HEADER_SIZE: u32 = 44;
+
+/// Backend that writes 32-bit float WAV files
+///
+/// Every time the player opens the output the file gets truncated
+#[derive(Clone, Debug)]
+pub struct WavOutput {
+    path: PathBuf,
+    spec: SignalSpec,
+}
+
+impl WavOutput {
+    pub fn new(path: impl Into<PathBuf>, rate: u32, channels: usize) -> Self {
+        WavOutput {
+            path: path.into(),
+            spec: SignalSpec::new(rate, channels_for_count(channels)),
+        }
+    }
+}
+
+impl OutputBackend for WavOutput {
+    fn open(&self, _device_name: Option<&str>) -> Result<Box<dyn AudioOutput>> {
+        let mut writer = WavWriter {
+            file: BufWriter::new(File::create(&self.path)?),
+            spec: self.spec,
+            data_size: 0,
+        };
+        writer.write_header()?;
+        Ok(Box::new(writer))
+    }
+}
+
+struct WavWriter {
+    file: BufWriter<File>,
+    spec: SignalSpec,
+    /// Bytes of samples written so far
+    data_size: u32,
+}
+
+impl WavWriter {
+    fn write_header(&mut self) -> std::io::Result<()> {
+        let channels = self.spec.channels.count() as u16;
+        let block_align = channels * 4;
+
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.write_all(b"RIFF")?;
+        self.file
+            .write_all(&(HEADER_SIZE - 8 + self.data_size).to_le_bytes())?;
+        self.file.write_all(b"WAVE")?;
+
+        self.file.write_all(b"fmt ")?;
+        self.file.write_all(&16u32.to_le_bytes())?;
+        self.file.write_all(&FORMAT_FLOAT.to_le_bytes())?;
+        self.file.write_all(&channels.to_le_bytes())?;
+        self.file.write_all(&self.spec.rate.to_le_bytes())?;
+        self.file
+            .write_all(&(self.spec.rate * block_align as u32).to_le_bytes())?;
+        self.file.write_all(&block_align.to_le_bytes())?;
+        self.file.write_all(&32u16.to_le_bytes())?;
+
+        self.file.write_all(b"data")?;
+        self.file.write_all(&self.data_size.to_le_bytes())?;
+        Ok(())
+    }
+
+    /// Writes the final sizes in the header, leaving the file ready for more samples
+    fn finalize(&mut self) -> std::io::Result<()> {
+        self.write_header()?;
+        self.file.seek(SeekFrom::End(0))?;
+        self.file.flush()
+    }
+}
+
+impl AudioOutput for WavWriter {
+    fn spec(&self) -> SignalSpec {
+        self.spec
+    }
+
+    fn write(&mut self, samples: &[f32]) -> Result<()> {
+        for sample in samples {
+            self.file.write_all(&sample.to_le_bytes())?;
+        }
+        self.data_size = self.data_size.saturating_add((samples.len() * 4) as u32);
+        Ok(())
+    }
+
+    fn flush(&mut self) {
+        if let Err(err) = self.finalize() {
+            eprintln!("Failed to finalize the WAV file: {err}");
+        }
+    }
+}
+
+impl Drop for WavWriter {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
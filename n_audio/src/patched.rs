@@ -0,0 +1,118 @@
+//! A `FormatReader` filled in with what `MusicTrack` found out about the track
+
+use symphonia::core::errors::{unsupported_error, Result as SymphResult};
+use symphonia::core::formats::{
+    Cue, FormatOptions, FormatReader, Packet, SeekMode, SeekTo, SeekedTo, Track,
+};
+use symphonia::core::io::MediaSourceStream;
+use symphonia::core::meta::{Metadata, MetadataBuilder, MetadataLog};
+
+use crate::duration::Length;
+use crate::gain;
+use crate::ReplayGain;
+
+/// Wraps a `FormatReader`, giving it the length of its default track and the tags found outside of it
+pub(crate) struct PatchedFormat {
+    inner: Box<dyn FormatReader>,
+    tracks: Vec<Track>,
+    /// Replaces the tags of the container, see `PatchedFormat::with_tags` and `PatchedFormat::with_replay_gain`
+    tags: Option<MetadataLog>,
+}
+
+impl PatchedFormat {
+    pub fn new(inner: Box<dyn FormatReader>) -> Self {
+        let tracks = inner.tracks().to_vec();
+        PatchedFormat {
+            inner,
+            tracks,
+            tags: None,
+        }
+    }
+
+    pub fn with_length(mut self, length: Length) -> Self {
+        let default_id = self.inner.default_track().map(|track| track.id);
+        for track in &mut self.tracks {
+            if Some(track.id) == default_id {
+                let start = track.codec_params.start_ts;
+                track
+                    .codec_params
+                    .with_n_frames(length.frames.saturating_sub(start));
+            }
+        }
+        self
+    }
+
+    /// Used for the tags outside of the container (e.g. ID3v2 in MP3 files), when the container has none
+    pub fn with_tags(mut self, tags: MetadataLog) -> Self {
+        self.tags = Some(tags);
+        self
+    }
+
+    /// Adds the gains computed for the track to its tags, for the values the tags don't have
+    pub fn with_replay_gain(mut self, computed: ReplayGain) -> Self {
+        let mut builder = MetadataBuilder::new();
+        let mut tagged = ReplayGain::default();
+        if let Some(revision) = self.metadata().skip_to_latest() {
+            for tag in revision.tags() {
+                gain::read_tag(tag, &mut tagged);
+                builder.add_tag(tag.clone());
+            }
+            for visual in revision.visuals() {
+                builder.add_visual(visual.clone());
+            }
+        }
+
+        let missing = ReplayGain {
+            track_gain: computed.track_gain.filter(|_| tagged.track_gain.is_none()),
+            track_peak: computed.track_peak.filter(|_| tagged.track_peak.is_none()),
+            album_gain: computed.album_gain.filter(|_| tagged.album_gain.is_none()),
+            album_peak: computed.album_peak.filter(|_| tagged.album_peak.is_none()),
+        };
+        for tag in gain::to_tags(&missing) {
+            builder.add_tag(tag);
+        }
+
+        let mut tags = MetadataLog::default();
+        tags.push(builder.metadata());
+        self.tags = Some(tags);
+        self
+    }
+}
+
+impl FormatReader for PatchedFormat {
+    fn try_new(_source: MediaSourceStream, _options: &FormatOptions) -> SymphResult<Self> {
+        unsupported_error("PatchedFormat only wraps other readers")
+    }
+
+    fn cues(&self) -> &[Cue] {
+        self.inner.cues()
+    }
+
+    fn metadata(&mut self) -> Metadata<'_> {
+        match &mut self.tags {
+            Some(tags) => tags.metadata(),
+            None => self.inner.metadata(),
+        }
+    }
+
+    fn seek(&mut self, mode: SeekMode, to: SeekTo) -> SymphResult<SeekedTo> {
+        self.inner.seek(mode, to)
+    }
+
+    fn tracks(&self) -> &[Track] {
+        &self.tracks
+    }
+
+    fn default_track(&self) -> Option<&Track> {
+        let id = self.inner.default_track()?.id;
+        self.tracks.iter().find(|track| track.id == id)
+    }
+
+    fn next_packet(&mut self) -> SymphResult<Packet> {
+        self.inner.next_packet()
+    }
+
+    fn into_inner(self: Box<Self>) -> MediaSourceStream {
+        self.inner.into_inner()
+    }
+}
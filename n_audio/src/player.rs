@@ -1,13 +1,30 @@
+use crate::convert::Converter;
+use crate::effect::{EffectChain, SharedChain};
+use crate::equalizer::{Band, Equalizer};
+use crate::event::{PlayerEvent, PositionThrottle, Subscribers};
+use crate::fade::{self, Ramp};
+use crate::gain;
 use crate::music_track::MusicTrack;
-use crate::{output, Message, TrackTime, CODEC_REGISTRY};
+use crate::opus::OpusDecoder;
+use crate::output::{CpalAudioOutput, OutputBackend};
+use crate::stretch::TimeStretch;
+use crate::visualizer::{AudioTap, TapWriter};
+use crate::{
+    GainMode, LengthKind, LoopRegion, Message, NError, PlaybackStats, ReplayGain, SpeedMode,
+    TrackTime, CODEC_REGISTRY,
+};
 use flume::{Receiver, SendError, Sender};
 use std::ffi::OsStr;
 use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
 use std::thread::JoinHandle;
 use std::{io, thread};
-use symphonia::core::codecs::DecoderOptions;
+use symphonia::core::audio::{AudioBuffer, AudioBufferRef, Signal, SignalSpec};
+use symphonia::core::codecs::{CodecParameters, Decoder, DecoderOptions, CODEC_TYPE_OPUS};
+use symphonia::core::errors::Error as SymphoniaError;
 use symphonia::core::formats::{FormatReader, SeekMode, SeekTo};
-use symphonia::core::units::Time;
+use symphonia::core::units::{Time, TimeBase, TimeStamp};
 // TODO: update docs
 
 /// The main actor for everything.
@@ -18,34 +35,81 @@ pub struct Player {
     is_paused: bool,
     volume: f32,
     playback_speed: f32,
+    speed_mode: SpeedMode,
+    gain_mode: GainMode,
+    preamp: f32,
+    equalizer: Vec<Band>,
+    effects: SharedChain,
+    tap: TapWriter,
+    crossfade: f64,
+    fade_length: f64,
+    output_device: Option<String>,
+    backend: Arc<dyn OutputBackend>,
     cached_get_time: Option<TrackTime>,
     thread: Option<JoinHandle<()>>,
     tx: Option<Sender<Message>>,
-    rx_t: Option<Receiver<Message>>,
+    /// Where the current track is at, written by the track thread after every packet and taken by `Player::get_time`
+    position: Option<Arc<Mutex<Option<TrackTime>>>>,
     rx_e: Option<Receiver<Message>>,
+    rx_a: Option<Receiver<Message>>,
+    /// Unlike the other channels this one outlives the track threads, so that no error goes missing
+    tx_err: Sender<Message>,
+    rx_err: Receiver<Message>,
+    subscribers: Subscribers,
+    /// Frames concealed by the Opus decoders of every track played, see `Player::stats`
+    concealed: Arc<AtomicU64>,
 }
 
 impl Player {
-    /// Instance a new `Player`
+    /// Instance a new `Player`, playing through the devices of the system
     pub fn new(volume: f32, playback_speed: f32) -> Self {
+        Self::builder()
+            .volume(volume)
+            .playback_speed(playback_speed)
+            .build()
+    }
+
+    /// Returns a builder to instance a `Player` with a different output backend
+    pub fn builder() -> PlayerBuilder {
+        PlayerBuilder::default()
+    }
+
+    fn with_backend(volume: f32, playback_speed: f32, backend: Arc<dyn OutputBackend>) -> Self {
+        let (tx_err, rx_err) = flume::unbounded();
         Player {
             is_paused: false,
             volume,
             playback_speed,
+            speed_mode: SpeedMode::default(),
+            gain_mode: GainMode::default(),
+            preamp: 0.0,
+            equalizer: vec![],
+            effects: SharedChain::default(),
+            tap: TapWriter::default(),
+            crossfade: 0.0,
+            fade_length: 0.02,
+            output_device: None,
+            backend,
             cached_get_time: None,
             thread: None,
             tx: None,
-            rx_t: None,
+            position: None,
             rx_e: None,
+            rx_a: None,
+            tx_err,
+            rx_err,
+            subscribers: Subscribers::default(),
+            concealed: Arc::default(),
         }
     }
 
     /// Pauses the current playing track, if any
     /// It only errors if it can't send the message (so something serious may have happened)
     pub async fn pause(&mut self) -> Result<(), SendError<Message>> {
-        if let Some(tx) = &self.tx {
+        if let Some(tx) = self.thread_tx() {
             tx.send_async(Message::Pause).await?;
             self.is_paused = true;
+            self.subscribers.emit(PlayerEvent::Paused);
         }
         Ok(())
     }
@@ -53,9 +117,10 @@ impl Player {
     /// Unpauses the current playing track, if any
     /// It only errors if it can't send the message (so something serious may have happened)
     pub async fn unpause(&mut self) -> Result<(), SendError<Message>> {
-        if let Some(tx) = &self.tx {
+        if let Some(tx) = self.thread_tx() {
             tx.send_async(Message::Play).await?;
             self.is_paused = false;
+            self.subscribers.emit(PlayerEvent::Resumed);
         }
         Ok(())
     }
@@ -76,64 +141,229 @@ impl Player {
     /// Sets the output volume
     /// It only errors if it can't send the message (so something serious may have happened)
     pub async fn set_volume(&mut self, volume: f32) -> Result<(), SendError<Message>> {
-        if let Some(tx) = &self.tx {
+        if let Some(tx) = self.thread_tx() {
             tx.send_async(Message::Volume(volume)).await?;
         }
         self.volume = volume;
+        self.subscribers.emit(PlayerEvent::VolumeChanged(volume));
         Ok(())
     }
 
-    /// Sets the playback speed
+    pub fn get_playback_speed(&self) -> f32 {
+        self.playback_speed
+    }
+
+    /// Sets the playback speed, applied immediately to the current track
     /// It only errors if it can't send the message (so something serious may have happened)
     pub async fn set_playback_speed(
         &mut self,
         playback_speed: f32,
     ) -> Result<(), SendError<Message>> {
-        if let Some(tx) = &self.tx {
+        if let Some(tx) = self.thread_tx() {
             tx.send_async(Message::PlaybackSpeed(playback_speed))
                 .await?;
-            self.playback_speed = playback_speed;
         }
+        self.playback_speed = playback_speed;
+        self.subscribers
+            .emit(PlayerEvent::PlaybackSpeedChanged(playback_speed));
         Ok(())
     }
 
-    /// Seeks to the set timestamp
-    /// Be aware that if the timestamp isn't valid the track thread will panic
+    pub fn get_speed_mode(&self) -> SpeedMode {
+        self.speed_mode
+    }
+
+    /// Sets how the playback speed is applied, see [`SpeedMode`]
     /// It only errors if it can't send the message (so something serious may have happened)
-    pub async fn seek_to(&self, secs: u64, mut frac: f64) -> Result<(), SendError<Message>> {
-        if let Some(tx) = &self.tx {
-            if secs == 0 && frac == 0.0 {
-                frac = 0.01;
-            }
-            if let Some(current_duration) = &self.cached_get_time {
-                let min_value = if secs as f64 + frac <= current_duration.length {
-                    Time {
-                        seconds: secs,
-                        frac,
-                    }
-                } else {
-                    Time {
-                        seconds: current_duration.length.trunc() as u64,
-                        frac: current_duration.length.fract(),
-                    }
-                };
-                tx.send_async(Message::Seek(min_value)).await?;
-            }
+    pub async fn set_speed_mode(
+        &mut self,
+        speed_mode: SpeedMode,
+    ) -> Result<(), SendError<Message>> {
+        if let Some(tx) = self.thread_tx() {
+            tx.send_async(Message::SpeedMode(speed_mode)).await?;
         }
+        self.speed_mode = speed_mode;
         Ok(())
     }
 
-    /// Returns the timestamp that was lastly sent by the track thread
-    pub fn get_time(&mut self) -> Option<TrackTime> {
-        let mut last = None;
+    pub fn get_gain_mode(&self) -> GainMode {
+        self.gain_mode
+    }
+
+    /// Sets which ReplayGain (or R128) gain is applied to the tracks, see [`GainMode`]
+    /// It only errors if it can't send the message (so something serious may have happened)
+    pub async fn set_gain_mode(&mut self, gain_mode: GainMode) -> Result<(), SendError<Message>> {
+        if let Some(tx) = self.thread_tx() {
+            tx.send_async(Message::GainMode(gain_mode)).await?;
+        }
+        self.gain_mode = gain_mode;
+        Ok(())
+    }
+
+    pub fn get_preamp(&self) -> f32 {
+        self.preamp
+    }
+
+    /// Sets the pre-amp in dB, added to the gain of the tracks that have gain tags
+    /// Tracks are still kept from clipping when their peak is known
+    /// It only errors if it can't send the message (so something serious may have happened)
+    pub async fn set_preamp(&mut self, preamp: f32) -> Result<(), SendError<Message>> {
+        if let Some(tx) = self.thread_tx() {
+            tx.send_async(Message::Preamp(preamp)).await?;
+        }
+        self.preamp = preamp;
+        Ok(())
+    }
+
+    pub fn get_equalizer(&self) -> &[Band] {
+        &self.equalizer
+    }
+
+    /// Replaces the bands of the equalizer, applied immediately to the current track
+    /// An empty list of bands turns the equalizer off
+    /// It only errors if it can't send the message (so something serious may have happened)
+    pub async fn set_equalizer(&mut self, bands: Vec<Band>) -> Result<(), SendError<Message>> {
+        if let Some(tx) = self.thread_tx() {
+            tx.send_async(Message::Equalizer(bands.clone())).await?;
+        }
+        self.equalizer = bands;
+        Ok(())
+    }
+
+    /// Changes a single band of the equalizer, adding flat bands before it if there aren't enough of them
+    /// It only errors if it can't send the message (so something serious may have happened)
+    pub async fn set_equalizer_band(
+        &mut self,
+        index: usize,
+        band: Band,
+    ) -> Result<(), SendError<Message>> {
+        if let Some(tx) = self.thread_tx() {
+            tx.send_async(Message::EqualizerBand(index, band)).await?;
+        }
+        if index >= self.equalizer.len() {
+            self.equalizer
+                .resize(index + 1, Band::peaking(1000.0, 0.0, 1.0));
+        }
+        self.equalizer[index] = band;
+        Ok(())
+    }
+
+    /// Returns the effects run on the audio after the equalizer, see [`crate::effect`]
+    /// Changes apply immediately, also to the current track; the audio waits while the chain is borrowed, so don't hold on to it
+    pub fn effects(&self) -> MutexGuard<'_, EffectChain> {
+        self.effects.lock()
+    }
+
+    /// Returns a tap on the audio being played, from the moment it's called, see [`crate::visualizer`]
+    pub fn tap(&self) -> AudioTap {
+        self.tap.reader()
+    }
+
+    pub fn get_crossfade(&self) -> f64 {
+        self.crossfade
+    }
+
+    /// Sets for how many seconds the end of a track is mixed with the start of the preloaded one
+    /// A value of `0.0` disables the crossfade, leaving the tracks to be played gaplessly
+    /// It only errors if it can't send the message (so something serious may have happened)
+    pub async fn set_crossfade(&mut self, secs: f64) -> Result<(), SendError<Message>> {
+        if let Some(tx) = self.thread_tx() {
+            tx.send_async(Message::Crossfade(secs)).await?;
+        }
+        self.crossfade = secs;
+        Ok(())
+    }
+
+    pub fn get_fade_length(&self) -> f64 {
+        self.fade_length
+    }
+
+    /// Sets for how many seconds the audio fades out when pausing, seeking or ending the track, and fades in afterwards
+    /// Volume changes are ramped over the same time, `0.0` makes every change immediate; it's 20ms by default
+    /// It only errors if it can't send the message (so something serious may have happened)
+    pub async fn set_fade_length(&mut self, secs: f64) -> Result<(), SendError<Message>> {
+        if let Some(tx) = self.thread_tx() {
+            tx.send_async(Message::FadeLength(secs)).await?;
+        }
+        self.fade_length = secs;
+        Ok(())
+    }
+
+    /// Returns the name of the output device chosen with `Player::set_output_device`
+    pub fn get_output_device(&self) -> Option<String> {
+        self.output_device.clone()
+    }
+
+    /// Sets the output device by its name (see [`crate::device::output_devices`]), `None` is the default device
+    /// The current track keeps playing on the new device, and if the device can't be found or goes away the default one is used
+    /// It only errors if it can't send the message (so something serious may have happened)
+    pub async fn set_output_device(
+        &mut self,
+        name: Option<String>,
+    ) -> Result<(), SendError<Message>> {
+        if let Some(tx) = self.thread_tx() {
+            tx.send_async(Message::OutputDevice(name.clone())).await?;
+        }
+        self.output_device = name;
+        Ok(())
+    }
+
+    /// Plays the part of the current track between `start` and `end` again `count` times, or until it's cleared if `count` is `None`
+    /// The track jumps back to `start` as soon as it gets to `end`, without any gap; if it's already past `end` it jumps right away
+    /// The loop is dropped when the track changes, [`PlayerEvent::LoopChanged`] tells when it's played again or dropped
+    /// It only errors if it can't send the message (so something serious may have happened)
+    pub async fn set_loop(
+        &self,
+        start: Time,
+        end: Time,
+        count: Option<u32>,
+    ) -> Result<(), SendError<Message>> {
+        if let Some(tx) = self.thread_tx() {
+            let region = LoopRegion { start, end, count };
+            tx.send_async(Message::Loop(Some(region))).await?;
+        }
+        Ok(())
+    }
+
+    /// Stops the A-B loop, the track keeps playing from where it is
+    /// It only errors if it can't send the message (so something serious may have happened)
+    pub async fn clear_loop(&self) -> Result<(), SendError<Message>> {
+        if let Some(tx) = self.thread_tx() {
+            tx.send_async(Message::Loop(None)).await?;
+        }
+        Ok(())
+    }
 
-        if let Some(rx_t) = &self.rx_t {
-            while let Ok(message) = rx_t.try_recv() {
-                if let Message::Time(time) = message {
-                    last = Some(time);
+    /// Seeks to the set timestamp, the first sample played afterwards is the one at that time
+    /// The timestamp is clamped to the length of the track when it's known, the position it landed on is returned by `Player::get_time`
+    /// It only errors if it can't send the message (so something serious may have happened)
+    pub async fn seek_to(&self, secs: u64, frac: f64) -> Result<(), SendError<Message>> {
+        if let Some(tx) = self.thread_tx() {
+            let time = match &self.cached_get_time {
+                Some(current)
+                    if current.is_length_known() && secs as f64 + frac > current.length =>
+                {
+                    Time {
+                        seconds: current.length.trunc() as u64,
+                        frac: current.length.fract(),
+                    }
                 }
-            }
+                _ => Time {
+                    seconds: secs,
+                    frac,
+                },
+            };
+            tx.send_async(Message::Seek(time)).await?;
         }
+        Ok(())
+    }
+
+    /// Returns the timestamp that was lastly reached by the track thread, `None` if it didn't move since the last call
+    pub fn get_time(&mut self) -> Option<TrackTime> {
+        let last = self
+            .position
+            .as_ref()
+            .and_then(|position| position.lock().unwrap().take());
 
         self.cached_get_time = last;
         last
@@ -152,16 +382,75 @@ impl Player {
         false
     }
 
+    /// Forgets the track given to `Player::preload`, e.g. because it isn't the next one anymore
+    /// It only errors if it can't send the message (so something serious may have happened)
+    pub fn clear_preload(&self) -> Result<(), SendError<Message>> {
+        if let Some(tx) = self.thread_tx() {
+            tx.send(Message::ClearPreload)?;
+        }
+        Ok(())
+    }
+
+    /// Returns whether the track thread has moved on to the track given to `Player::preload`
+    /// When this returns `true` the preloaded track is the one currently playing, so a new one can be preloaded
+    pub fn has_advanced(&self) -> bool {
+        if let Some(rx_a) = &self.rx_a {
+            while let Ok(message) = rx_a.try_recv() {
+                if let Message::Advance = message {
+                    return true;
+                }
+            }
+        }
+        false
+    }
+
+    /// Returns statistics about every track played by this `Player` so far
+    pub fn stats(&self) -> PlaybackStats {
+        PlaybackStats {
+            concealed_frames: self.concealed.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Returns a stream of everything that happens to the `Player` from now on
+    /// Any number of listeners can subscribe, each one gets every event; dropping the receiver unsubscribes
+    pub fn subscribe(&self) -> Receiver<PlayerEvent> {
+        self.subscribers.subscribe()
+    }
+
+    /// Returns the oldest error reported with `Message::Error` that hasn't been taken yet
+    pub fn take_error(&self) -> Option<Arc<NError>> {
+        while let Ok(message) = self.rx_err.try_recv() {
+            if let Message::Error(err) = message {
+                return Some(err);
+            }
+        }
+        None
+    }
+
+    /// Reports an error as if it came from the track thread
+    pub(crate) fn report_error(&self, err: NError) {
+        let err = Arc::new(err);
+        self.subscribers.emit(PlayerEvent::Error(err.clone()));
+        let _ = self.tx_err.send(Message::Error(err));
+    }
+
+    /// Returns the sender to the track thread, as long as the thread is still running
+    fn thread_tx(&self) -> Option<&Sender<Message>> {
+        self.tx.as_ref().filter(|_| self.is_playing())
+    }
+
     /// Returns whether if any track is playing
     /// Note that this function doesn't check if the track is paused or not
     pub fn is_playing(&self) -> bool {
-        self.thread.is_some()
+        self.thread
+            .as_ref()
+            .is_some_and(|thread| !thread.is_finished())
     }
 
     /// Ends the current track playing, if any
     /// It only errors if it can't send the message (so something serious may have happened)
     pub async fn end_current(&self) -> Result<(), SendError<Message>> {
-        if let Some(tx) = &self.tx {
+        if let Some(tx) = self.thread_tx() {
             tx.send_async(Message::Exit).await?;
         }
         Ok(())
@@ -171,100 +460,270 @@ impl Player {
     pub fn play_from_path<P: AsRef<Path> + AsRef<OsStr> + Clone + Into<String>>(
         &mut self,
         path: P,
-    ) -> io::Result<()> {
+    ) -> Result<(), NError> {
         let music_track = MusicTrack::new(path)?;
-        self.play(music_track.get_format()?);
-
-        Ok(())
+        self.play_from_track(&music_track)
     }
 
     /// Plays a certain track
-    pub fn play_from_track(&mut self, track: &MusicTrack) -> io::Result<()> {
-        self.play(track.get_format()?);
+    pub fn play_from_track(&mut self, track: &MusicTrack) -> Result<(), NError> {
+        let (format, length_kind) = track.get_format_with_length_kind()?;
+        self.play_with_length_kind(format, length_kind)
+    }
+
+    /// Gives the track thread the track that should be played right after the current one
+    /// The track is probed and its decoder is created ahead of time, so that the switch happens without any gap
+    /// Preloading again replaces the previously preloaded track
+    /// It only errors if it can't send the message (so something serious may have happened)
+    pub async fn preload(&self, format: Box<dyn FormatReader>) -> Result<(), SendError<Message>> {
+        self.preload_with_length_kind(format, LengthKind::Exact)
+            .await
+    }
+
+    /// Same as `Player::preload`, for formats whose length may only be an estimate (see `MusicTrack::get_format`)
+    pub(crate) async fn preload_with_length_kind(
+        &self,
+        format: Box<dyn FormatReader>,
+        length_kind: LengthKind,
+    ) -> Result<(), SendError<Message>> {
+        if let Some(tx) = self.thread_tx() {
+            tx.send_async(Message::Preload(format, length_kind)).await?;
+        }
         Ok(())
     }
 
     /// Plays a certain track given its format
-    pub fn play(&mut self, format: Box<dyn FormatReader>) {
-        let volume = self.volume;
-        let playback_speed = self.playback_speed;
+    /// The length the format gives is reported as exact, `Player::play_from_track` also knows when it was estimated
+    /// It errors if the track can't be played at all, errors happening later on are reported with `Message::Error`
+    pub fn play(&mut self, format: Box<dyn FormatReader>) -> Result<(), NError> {
+        self.play_with_length_kind(format, LengthKind::Exact)
+    }
+
+    /// Same as `Player::play`, for formats whose length may only be an estimate (see `MusicTrack::get_format`)
+    pub(crate) fn play_with_length_kind(
+        &mut self,
+        format: Box<dyn FormatReader>,
+        length_kind: LengthKind,
+    ) -> Result<(), NError> {
+        let track = OpenTrack::new(format, length_kind, &self.concealed)?;
+        let settings = ThreadSettings {
+            volume: self.volume,
+            playback_speed: self.playback_speed,
+            speed_mode: self.speed_mode,
+            gain_mode: self.gain_mode,
+            preamp: self.preamp,
+            equalizer: self.equalizer.clone(),
+            effects: self.effects.clone(),
+            tap: self.tap.clone(),
+            crossfade: self.crossfade,
+            fade_length: self.fade_length,
+            output_device: self.output_device.clone(),
+            backend: self.backend.clone(),
+            subscribers: self.subscribers.clone(),
+            concealed: self.concealed.clone(),
+        };
 
         let (tx, rx) = flume::unbounded();
-        let (tx_t, rx_t) = flume::unbounded();
+        let position = Arc::new(Mutex::new(None));
         let (tx_e, rx_e) = flume::unbounded();
+        let (tx_a, rx_a) = flume::unbounded();
+        let tx_err = self.tx_err.clone();
 
-        let thread =
-            thread::spawn(move || Self::thread_fn(format, rx, tx_t, tx_e, volume, playback_speed));
+        let thread_position = position.clone();
+        let thread = thread::spawn(move || {
+            Self::thread_fn(track, rx, thread_position, tx_e, tx_a, tx_err, settings)
+        });
 
         self.is_paused = false;
+        self.rx_a = Some(rx_a);
         self.rx_e = Some(rx_e);
-        self.rx_t = Some(rx_t);
+        self.position = Some(position);
         self.tx = Some(tx);
         self.thread = Some(thread);
+        Ok(())
     }
 
     fn thread_fn(
-        mut format: Box<dyn FormatReader>,
+        mut current: OpenTrack,
         rx: Receiver<Message>,
-        tx_t: Sender<Message>,
+        position: Arc<Mutex<Option<TrackTime>>>,
         tx_e: Sender<Message>,
-        mut volume: f32,
-        mut playback_speed: f32,
+        tx_a: Sender<Message>,
+        tx_err: Sender<Message>,
+        settings: ThreadSettings,
     ) {
-        // Vars used for audio output
-        let track = format.default_track().expect("Can't load tracks");
-        let track_id = track.id;
-        let time_base = track.codec_params.time_base.unwrap();
-        let duration = track
-            .codec_params
-            .n_frames
-            .map(|frames| track.codec_params.start_ts + frames)
-            .unwrap();
+        let ThreadSettings {
+            volume,
+            mut playback_speed,
+            mut speed_mode,
+            mut gain_mode,
+            mut preamp,
+            equalizer,
+            effects,
+            tap,
+            mut crossfade,
+            mut fade_length,
+            mut output_device,
+            backend,
+            subscribers,
+            concealed,
+        } = settings;
 
-        let mut decoder = CODEC_REGISTRY
-            .make(&track.codec_params, &DecoderOptions::default())
-            .expect("Can't load decoder");
-        let mut audio_output = None;
+        let report = |err: NError| {
+            eprintln!("{err}");
+            let err = Arc::new(err);
+            subscribers.emit(PlayerEvent::Error(err.clone()));
+            let _ = tx_err.send(Message::Error(err));
+        };
 
-        let mut spec = None;
-        let mut dur = None;
+        // Vars used for audio output
+        current.set_gain(gain_mode, preamp);
+        let mut next: Option<OpenTrack> = None;
+        // The output stays open for every track, the audio is converted to its spec
+        let mut audio_output = match backend.open(output_device.as_deref()) {
+            Ok(audio_output) => audio_output,
+            Err(err) => {
+                report(err.into());
+                let _ = tx_e.send(Message::End);
+                subscribers.emit(PlayerEvent::TrackEnded);
+                return;
+            }
+        };
+        subscribers.emit(PlayerEvent::TrackStarted);
+        let mut converter = Converter::new(audio_output.spec());
+        let mut samples = vec![];
+
+        let mut buf = AudioBuffer::<f32>::unused();
+        let mut scratch = AudioBuffer::<f32>::unused();
+        let mut equalizer = Equalizer::new(equalizer);
+        let mut stretch = TimeStretch::new(playback_speed);
+        let mut stretched = AudioBuffer::<f32>::unused();
+        let mut throttle = PositionThrottle::default();
+        let mut volume = Ramp::new(volume);
+        // Ramps down before pausing and back up when resuming or after seeking
+        let mut fade = Ramp::new(1.0);
 
         // Vars used to control audio output
         let mut is_paused = false;
+        // Paused as soon as the fade out is over
+        let mut pausing = false;
         let mut exit = false;
+        // The A-B loop, with its count being the repeats left
+        let mut ab_loop: Option<LoopRegion> = None;
 
         loop {
-            if let Some(message) = if is_paused {
+            let message = if is_paused {
                 rx.recv().ok()
             } else {
                 rx.try_recv().ok()
-            } {
+            };
+            if message.is_none() && rx.is_disconnected() {
+                // Nobody is listening anymore, the `Player` moved on to another track or was dropped
+                exit = true;
+                break;
+            }
+            if let Some(message) = message {
+                let fade_frames = fade::frames(fade_length, audio_output.spec().rate);
                 match message {
-                    Message::Play => is_paused = false,
-                    Message::Pause => is_paused = true,
-                    Message::Volume(v) => volume = v,
-                    Message::PlaybackSpeed(speed) => playback_speed = speed,
+                    Message::Play => {
+                        is_paused = false;
+                        pausing = false;
+                        fade.set_target(1.0, fade_frames);
+                    }
+                    Message::Pause if fade_frames == 0 || is_paused => is_paused = true,
+                    Message::Pause => {
+                        pausing = true;
+                        fade.set_target(0.0, fade_frames);
+                    }
+                    Message::Volume(v) => volume.set_target(v, fade_frames),
+                    Message::PlaybackSpeed(speed) => {
+                        // The stretcher is skipped at normal speed, so what it buffered isn't continuous anymore
+                        if (speed == 1.0) != (playback_speed == 1.0) {
+                            stretch.reset();
+                        }
+                        playback_speed = speed;
+                        stretch.set_speed(speed);
+                    }
+                    Message::SpeedMode(mode) => {
+                        speed_mode = mode;
+                        stretch.reset();
+                    }
+                    Message::GainMode(mode) => {
+                        gain_mode = mode;
+                        current.set_gain(gain_mode, preamp);
+                        if let Some(next) = &mut next {
+                            next.set_gain(gain_mode, preamp);
+                        }
+                    }
+                    Message::Preamp(db) => {
+                        preamp = db;
+                        current.set_gain(gain_mode, preamp);
+                        if let Some(next) = &mut next {
+                            next.set_gain(gain_mode, preamp);
+                        }
+                    }
+                    Message::Equalizer(bands) => equalizer.set_bands(bands),
+                    Message::EqualizerBand(index, band) => equalizer.set_band(index, band),
+                    Message::Crossfade(secs) => crossfade = secs,
+                    Message::FadeLength(secs) => fade_length = secs,
+                    Message::Loop(region) => {
+                        ab_loop = region.filter(|r| r.end > r.start && r.count != Some(0));
+                        subscribers.emit(PlayerEvent::LoopChanged(ab_loop));
+                    }
+                    Message::OutputDevice(name) => {
+                        if name != output_device {
+                            match backend.open(name.as_deref()) {
+                                Ok(new_output) => {
+                                    output_device = name;
+                                    audio_output = new_output;
+                                    converter = Converter::new(audio_output.spec());
+                                }
+                                // Keep playing on the current one
+                                Err(err) => report(err.into()),
+                            }
+                        }
+                    }
+                    Message::Preload(format, length_kind) => {
+                        match OpenTrack::new(format, length_kind, &concealed) {
+                            Ok(mut track) => {
+                                track.set_gain(gain_mode, preamp);
+                                next = Some(track);
+                            }
+                            Err(err) => {
+                                next = None;
+                                report(err);
+                            }
+                        }
+                    }
+                    Message::ClearPreload => next = None,
                     Message::Exit => {
+                        audio_output.discard(fade_frames);
                         exit = true;
                         break;
                     }
                     Message::Seek(time) => {
-                        if let Err(err) = format.seek(
-                            SeekMode::Coarse,
-                            SeekTo::Time {
-                                time,
-                                track_id: Some(track_id),
-                            },
-                        ) {
-                            println!("error seeking");
-                            if !err.to_string().contains("end of stream") {
-                                eprintln!(
-                                    "Couldn't seek to position {}+{}\nError: {}",
-                                    time.seconds, time.frac, err
-                                );
-                            } else {
-                                break;
+                        throttle.reset();
+                        current.clear_pending();
+                        equalizer.reset();
+                        effects.lock().reset();
+                        stretch.reset();
+                        converter.reset();
+                        // What the output still has to play is from before the seek
+                        audio_output.discard(fade_frames);
+                        fade.jump(0.0);
+                        if !pausing {
+                            fade.set_target(1.0, fade_frames);
+                        }
+                        match current.seek(time) {
+                            Ok(ts) => {
+                                let time = current.time_at(ts);
+                                subscribers.emit(PlayerEvent::Position(time));
+                                *position.lock().unwrap() = Some(time);
+                            }
+                            Err(err) if !err.to_string().contains("end of stream") => {
+                                report(NError::Seek(err))
                             }
+                            Err(_) => break,
                         }
                     }
                     _ => {}
@@ -276,85 +735,563 @@ impl Player {
             }
 
             if !is_paused {
-                let packet = match format.next_packet() {
-                    Ok(packet) => packet,
-                    Err(_err) => {
+                let decoded = current.decode_into(&mut buf).unwrap_or_else(|err| {
+                    // The rest of the track is skipped, as if it ended here
+                    report(err);
+                    None
+                });
+                let stretching = speed_mode == SpeedMode::PreservePitch && playback_speed != 1.0;
+                let ts = match decoded {
+                    Some(ts) => Some(ts),
+                    // What the stretcher buffered is the end of the track, unless the next one carries it on
+                    None if stretching
+                        && !next
+                            .as_ref()
+                            .is_some_and(|next| next.spec() == stretch.spec())
+                        && stretch.flush(&mut stretched) =>
+                    {
+                        None
+                    }
+                    None => {
+                        // Keep feeding the same output with the preloaded track, if there is one
+                        if let Some(track) = next.take() {
+                            current = track;
+                            throttle.reset();
+                            // The loop points belong to the track that ended
+                            if ab_loop.take().is_some() {
+                                subscribers.emit(PlayerEvent::LoopChanged(None));
+                            }
+                            subscribers.emit(PlayerEvent::TrackEnded);
+                            if tx_a.send(Message::Advance).is_err() {
+                                break;
+                            }
+                            subscribers.emit(PlayerEvent::TrackStarted);
+                            continue;
+                        }
                         break;
                     }
                 };
 
-                if packet.track_id() != track_id {
-                    continue;
+                if let Some(ts) = ts {
+                    // The frames after the end of the loop are cut, and the next ones are decoded from its start
+                    if let Some(region) = ab_loop {
+                        let end = current.time_base.calc_timestamp(region.end);
+                        let frames = buf.frames() as u64;
+                        if ts + frames >= end {
+                            buf.truncate(end.saturating_sub(ts) as usize);
+                            current.clear_pending();
+                            ab_loop = match current.seek(region.start) {
+                                Ok(_) => match region.count {
+                                    Some(count) if count <= 1 => None,
+                                    count => Some(LoopRegion {
+                                        count: count.map(|count| count - 1),
+                                        ..region
+                                    }),
+                                },
+                                Err(err) => {
+                                    report(NError::Seek(err));
+                                    None
+                                }
+                            };
+                            subscribers.emit(PlayerEvent::LoopChanged(ab_loop));
+                            if buf.frames() == 0 {
+                                continue;
+                            }
+                        }
+                    }
+
+                    let time = current.time_at(ts);
+                    if throttle.ready() {
+                        subscribers.emit(PlayerEvent::Position(time));
+                    }
+                    *position.lock().unwrap() = Some(time);
+
+                    // Without a length there's no telling when the track is about to end, so it can't be crossfaded
+                    // While looping it won't end at all
+                    if let (Some(next), Some(duration)) = (&mut next, current.duration) {
+                        if crossfade > 0.0 && ab_loop.is_none() {
+                            let rate = buf.spec().rate as f64;
+                            let left = current.time_base.calc_time(duration.saturating_sub(ts));
+                            let left = (left.seconds as f64 + left.frac) * rate;
+                            mix_crossfade(&mut buf, next, &mut scratch, left, crossfade * rate);
+                        }
+                    }
+
+                    equalizer.process(&mut buf);
+                    effects.lock().process(&mut buf);
                 }
 
-                while !format.metadata().is_latest() {
-                    format.metadata().pop();
+                // Tape mode resamples the audio as if it had a different rate, so the pitch changes along with the tempo
+                let mut rate = buf.spec().rate;
+                let out = match speed_mode {
+                    _ if ts.is_none() => &stretched,
+                    _ if playback_speed == 1.0 => &buf,
+                    SpeedMode::Tape => {
+                        rate = (rate as f32 * playback_speed).round() as u32;
+                        &buf
+                    }
+                    SpeedMode::PreservePitch => {
+                        stretch.process(&buf, &mut stretched);
+                        &stretched
+                    }
+                };
+
+                samples.clear();
+                converter.convert(out, rate, &mut samples);
+                let spec = audio_output.spec();
+                tap.write(&samples, spec.rate, spec.channels.count());
+                fade.apply(&mut samples, spec.channels.count());
+                volume.apply(&mut samples, spec.channels.count());
+                if pausing && fade.is_done() {
+                    pausing = false;
+                    is_paused = true;
                 }
-                let position = time_base.calc_time(packet.ts());
-                let length = time_base.calc_time(duration);
-                if let Err(err) = tx_t.send(Message::Time(TrackTime {
-                    position: position.seconds as f64 + position.frac,
-                    length: length.seconds as f64 + length.frac,
-                })) {
-                    if let Ok(message) = rx.try_recv() {
-                        if let Message::Exit = message {
-                            exit = true;
-                            break;
+                if let Err(err) = audio_output.write(&samples) {
+                    // The device went away, continue on the default one
+                    report(err.into());
+                    output_device = None;
+                    match backend.open(None) {
+                        Ok(new_output) => {
+                            audio_output = new_output;
+                            converter = Converter::new(audio_output.spec());
                         }
-                        if exit {
+                        Err(err) => {
+                            report(err.into());
                             break;
-                        } else {
-                            panic!("Can't send Time message: {}", err);
                         }
                     }
                 }
+                // The taps follow what can be heard, not what was decoded
+                tap.played(audio_output.delay() * audio_output.spec().channels.count());
+            }
+        }
+        // The messages go first, so that listeners reacting to the event can already see them
+        if !exit {
+            let _ = tx_e.send(Message::End);
+            subscribers.emit(PlayerEvent::TrackEnded);
+        }
+    }
+}
 
-                match decoder.decode(&packet) {
-                    Ok(decoded) => {
-                        if audio_output.is_none() {
-                            let mut tmp_spec = *decoded.spec();
-                            tmp_spec.rate = (tmp_spec.rate as f32 * playback_speed).round() as u32;
-                            spec = Some(tmp_spec);
-                            dur = Some(decoded.capacity() as u64);
-                            audio_output =
-                                Some(output::try_open(spec.unwrap(), dur.unwrap()).unwrap());
-                        } else {
-                            let mut new_spec = *decoded.spec();
-                            new_spec.rate = (new_spec.rate as f32 * playback_speed).round() as u32;
-                            let new_dur = decoded.capacity() as u64;
-                            let mut changed = false;
-                            if new_spec != spec.unwrap() {
-                                spec = Some(new_spec);
-                                changed = true;
-                            }
-                            if new_dur != dur.unwrap() {
-                                dur = Some(new_dur);
-                                changed = true
-                            }
-                            if changed {
-                                audio_output =
-                                    Some(output::try_open(spec.unwrap(), dur.unwrap()).unwrap());
-                            }
-                        }
+/// Settings the track thread starts with, they can be changed later on with the respective `Message`
+#[derive(Clone, Debug)]
+struct ThreadSettings {
+    volume: f32,
+    playback_speed: f32,
+    speed_mode: SpeedMode,
+    gain_mode: GainMode,
+    preamp: f32,
+    equalizer: Vec<Band>,
+    effects: SharedChain,
+    tap: TapWriter,
+    crossfade: f64,
+    fade_length: f64,
+    output_device: Option<String>,
+    backend: Arc<dyn OutputBackend>,
+    subscribers: Subscribers,
+    concealed: Arc<AtomicU64>,
+}
 
-                        if let Some(audio_output) = &mut audio_output {
-                            audio_output.write(decoded, volume).unwrap()
-                        }
-                    }
-                    Err(symphonia::core::errors::Error::DecodeError(err)) => {
-                        eprintln!("Decode error: {}", err);
-                    }
-                    Err(err) => {
-                        eprintln!("Error has occurred in decoding packet: {}", err);
-                        break;
+/// Builds a [`Player`], choosing where its audio goes
+///
+/// ```no_run
+/// use std::sync::Arc;
+/// use n_audio::output::CaptureOutput;
+/// use n_audio::player::Player;
+///
+/// let capture = CaptureOutput::new(48000, 2);
+/// let player = Player::builder().backend(Arc::new(capture.clone())).build();
+/// ```
+#[derive(Debug)]
+pub struct PlayerBuilder {
+    volume: f32,
+    playback_speed: f32,
+    backend: Arc<dyn OutputBackend>,
+}
+
+impl Default for PlayerBuilder {
+    fn default() -> Self {
+        PlayerBuilder {
+            volume: 1.0,
+            playback_speed: 1.0,
+            backend: Arc::new(CpalAudioOutput),
+        }
+    }
+}
+
+impl PlayerBuilder {
+    pub fn volume(mut self, volume: f32) -> Self {
+        self.volume = volume;
+        self
+    }
+
+    pub fn playback_speed(mut self, playback_speed: f32) -> Self {
+        self.playback_speed = playback_speed;
+        self
+    }
+
+    /// Sets the backend the audio is written to, cpal by default
+    pub fn backend(mut self, backend: Arc<dyn OutputBackend>) -> Self {
+        self.backend = backend;
+        self
+    }
+
+    pub fn build(self) -> Player {
+        Player::with_backend(self.volume, self.playback_speed, self.backend)
+    }
+}
+
+/// The decoder of a track, the Opus one kept as it is for what it tells about the audio it conceals
+enum TrackDecoder {
+    Opus(Box<OpusDecoder>),
+    Other(Box<dyn Decoder>),
+}
+
+impl TrackDecoder {
+    fn get(&mut self) -> &mut dyn Decoder {
+        match self {
+            TrackDecoder::Opus(decoder) => decoder.as_mut(),
+            TrackDecoder::Other(decoder) => decoder.as_mut(),
+        }
+    }
+
+    fn codec_params(&self) -> &CodecParameters {
+        match self {
+            TrackDecoder::Opus(decoder) => decoder.codec_params(),
+            TrackDecoder::Other(decoder) => decoder.codec_params(),
+        }
+    }
+
+    /// Timestamp of the first frame of the last decoded audio, when it isn't the one of its packet
+    fn first_ts(&self) -> Option<TimeStamp> {
+        match self {
+            TrackDecoder::Opus(decoder) => Some(decoder.first_ts()),
+            TrackDecoder::Other(_) => None,
+        }
+    }
+}
+
+/// A track opened by the track thread, with its decoder ready to go
+struct OpenTrack {
+    format: Box<dyn FormatReader>,
+    decoder: TrackDecoder,
+    track_id: u32,
+    time_base: TimeBase,
+    /// `None` when neither the format nor the `MusicTrack` knew how long the track is
+    duration: Option<u64>,
+    /// Whether `duration` is exact or was estimated by the `MusicTrack`
+    length_kind: LengthKind,
+    replay_gain: ReplayGain,
+    /// What the decoded samples are multiplied by, see `OpenTrack::set_gain`
+    gain: f32,
+    /// Frames decoded ahead of time during a crossfade, played before decoding any other packet
+    pending: Vec<Vec<f32>>,
+    pending_spec: Option<SignalSpec>,
+    /// Timestamp of the first pending frame
+    pending_ts: TimeStamp,
+    /// Frames before this timestamp are decoded but thrown away, see `OpenTrack::seek`
+    skip_to: TimeStamp,
+}
+
+impl OpenTrack {
+    /// The spec of the decoded audio, when the codec tells it beforehand
+    fn spec(&self) -> Option<SignalSpec> {
+        let params = self.decoder.codec_params();
+        Some(SignalSpec::new(params.sample_rate?, params.channels?))
+    }
+
+    /// `length_kind` tells whether the length the format gives, if any, is exact
+    fn new(
+        mut format: Box<dyn FormatReader>,
+        length_kind: LengthKind,
+        concealed: &Arc<AtomicU64>,
+    ) -> Result<Self, NError> {
+        let TrackParams {
+            track_id,
+            time_base,
+            duration,
+            codec_params,
+        } = TrackParams::of(format.as_ref())?;
+
+        let options = DecoderOptions::default();
+        // The Opus decoder is made here so that it counts the frames it conceals for the player
+        let decoder = if codec_params.codec == CODEC_TYPE_OPUS {
+            let decoder = OpusDecoder::try_new(&codec_params, &options).map_err(NError::Decoder)?;
+            TrackDecoder::Opus(Box::new(decoder.count_concealed(concealed.clone())))
+        } else {
+            TrackDecoder::Other(
+                CODEC_REGISTRY
+                    .make(&codec_params, &options)
+                    .map_err(NError::Decoder)?,
+            )
+        };
+        let replay_gain = gain::of_format(format.as_mut());
+
+        Ok(OpenTrack {
+            format,
+            decoder,
+            track_id,
+            time_base,
+            duration,
+            length_kind,
+            replay_gain,
+            gain: 1.0,
+            pending: vec![],
+            pending_spec: None,
+            pending_ts: 0,
+            skip_to: 0,
+        })
+    }
+
+    /// Chooses the gain applied to the decoded samples from the gain tags of the track
+    fn set_gain(&mut self, mode: GainMode, preamp: f32) {
+        self.gain = gain::factor(&self.replay_gain, mode, preamp);
+    }
+
+    fn time_at(&self, ts: TimeStamp) -> TrackTime {
+        let position = self.time_base.calc_time(ts);
+        let position = position.seconds as f64 + position.frac;
+        match self.duration {
+            Some(duration) => {
+                let length = self.time_base.calc_time(duration);
+                TrackTime {
+                    position,
+                    length: length.seconds as f64 + length.frac,
+                    length_kind: self.length_kind,
+                }
+            }
+            None => TrackTime {
+                position,
+                length: 0.0,
+                length_kind: LengthKind::Unknown,
+            },
+        }
+    }
+
+    /// Seeks to the packet with the frame at `time`, returning the timestamp of that frame
+    /// The frames before it in the packet are dropped once decoded, so that playback starts exactly at `time`
+    fn seek(&mut self, time: Time) -> Result<TimeStamp, SymphoniaError> {
+        let seeked = self.format.seek(
+            SeekMode::Accurate,
+            SeekTo::Time {
+                time,
+                track_id: Some(self.track_id),
+            },
+        )?;
+        // The decoder still has the state of the packets before the seek
+        self.decoder.get().reset();
+        self.skip_to = seeked.required_ts;
+        Ok(seeked.required_ts)
+    }
+
+    fn pending_frames(&self) -> usize {
+        self.pending.first().map_or(0, Vec::len)
+    }
+
+    fn clear_pending(&mut self) {
+        self.pending.clear();
+        self.pending_spec = None;
+    }
+
+    /// Puts the next frames of the track inside `buf`, returning their timestamp
+    /// Returns `None` when the track is over
+    fn decode_into(&mut self, buf: &mut AudioBuffer<f32>) -> Result<Option<TimeStamp>, NError> {
+        if let Some(spec) = self.pending_spec.take() {
+            let frames = self.pending_frames();
+            if frames > 0 {
+                if buf.capacity() < frames || *buf.spec() != spec {
+                    *buf = AudioBuffer::new(frames as u64, spec);
+                }
+                buf.clear();
+                buf.render_reserved(Some(frames));
+                for (ch, pending) in self.pending.iter_mut().enumerate() {
+                    buf.chan_mut(ch).copy_from_slice(pending);
+                    pending.clear();
+                }
+                return Ok(Some(self.pending_ts));
+            }
+        }
+
+        self.decode_packet(buf)
+    }
+
+    /// Reads and decodes the next packet of the track inside `buf`, returning its timestamp
+    fn decode_packet(&mut self, buf: &mut AudioBuffer<f32>) -> Result<Option<TimeStamp>, NError> {
+        loop {
+            let packet = match self.format.next_packet() {
+                Ok(packet) => packet,
+                // That's how the end of the track looks like
+                Err(SymphoniaError::IoError(err)) if err.kind() == io::ErrorKind::UnexpectedEof => {
+                    return Ok(self.flush(buf))
+                }
+                Err(SymphoniaError::ResetRequired) => return Ok(self.flush(buf)),
+                Err(err) => return Err(NError::Decode(err)),
+            };
+
+            if packet.track_id() != self.track_id {
+                continue;
+            }
+
+            while !self.format.metadata().is_latest() {
+                self.format.metadata().pop();
+            }
+
+            match self.decoder.get().decode(&packet) {
+                Ok(decoded) => {
+                    copy_decoded(decoded, buf);
+                    // Remove encoder delay and padding, as marked by the format reader
+                    buf.trim(packet.trim_start() as usize, packet.trim_end() as usize);
+                    let ts = self.decoder.first_ts().unwrap_or(packet.ts());
+                    if let Some(ts) = self.finish(buf, ts) {
+                        return Ok(Some(ts));
                     }
                 }
+                // A corrupted packet can be skipped
+                Err(SymphoniaError::DecodeError(err)) => {
+                    eprintln!("Decode error: {}", err);
+                }
+                Err(err) => return Err(NError::Decode(err)),
             }
         }
-        if !exit {
-            tx_e.send(Message::End).expect("Can't send End message");
+    }
+
+    /// Conceals what was lost at the end of an Opus track inside `buf`, returning its timestamp if there's any
+    fn flush(&mut self, buf: &mut AudioBuffer<f32>) -> Option<TimeStamp> {
+        let TrackDecoder::Opus(decoder) = &mut self.decoder else {
+            return None;
+        };
+        let flushed = decoder.flush();
+        if flushed.frames() == 0 {
+            return None;
+        }
+        copy_decoded(flushed, buf);
+        let ts = decoder.first_ts();
+        self.finish(buf, ts)
+    }
+
+    /// Drops the frames of `buf` before `skip_to` and applies the gain, returning the timestamp of what's left
+    /// Returns `None` when nothing is left
+    fn finish(&self, buf: &mut AudioBuffer<f32>, ts: TimeStamp) -> Option<TimeStamp> {
+        if ts + buf.frames() as u64 <= self.skip_to {
+            return None;
+        }
+        buf.trim(self.skip_to.saturating_sub(ts) as usize, 0);
+        if self.gain != 1.0 {
+            let gain = self.gain;
+            buf.transform(|sample| sample * gain);
+        }
+        Some(ts.max(self.skip_to))
+    }
+
+    /// Decodes packets until at least `frames` frames are pending or the track ends
+    /// Returns whether the pending frames can be mixed with a signal of the given spec
+    fn fill_pending(
+        &mut self,
+        frames: usize,
+        spec: SignalSpec,
+        scratch: &mut AudioBuffer<f32>,
+    ) -> bool {
+        while self.pending_frames() < frames {
+            if self
+                .pending_spec
+                .is_some_and(|pending_spec| pending_spec != spec)
+            {
+                return false;
+            }
+            match self.decode_packet(scratch) {
+                Ok(Some(ts)) if self.pending_frames() == 0 => self.pending_ts = ts,
+                Ok(Some(_)) => {}
+                // Errors are reported once the track becomes the current one
+                _ => break,
+            }
+            self.pending_spec = Some(*scratch.spec());
+            self.pending
+                .resize_with(scratch.spec().channels.count(), Vec::new);
+            for (ch, pending) in self.pending.iter_mut().enumerate() {
+                pending.extend_from_slice(scratch.chan(ch));
+            }
+        }
+        self.pending_spec == Some(spec)
+    }
+}
+
+/// What the track thread needs to know about the default track of a format
+struct TrackParams {
+    track_id: u32,
+    time_base: TimeBase,
+    /// `None` when neither the format nor the `MusicTrack` knew how long the track is
+    duration: Option<u64>,
+    codec_params: CodecParameters,
+}
+
+impl TrackParams {
+    fn of(format: &dyn FormatReader) -> Result<Self, NError> {
+        let track = format.default_track().ok_or(NError::NoPlayableTrack)?;
+        let time_base = track
+            .codec_params
+            .time_base
+            .ok_or(NError::MissingParameter("time base"))?;
+        let duration = track
+            .codec_params
+            .n_frames
+            .map(|frames| track.codec_params.start_ts + frames);
+
+        Ok(TrackParams {
+            track_id: track.id,
+            time_base,
+            duration,
+            codec_params: track.codec_params.clone(),
+        })
+    }
+}
+
+/// Copies `decoded` inside `buf`, making room for it
+fn copy_decoded(decoded: AudioBufferRef, buf: &mut AudioBuffer<f32>) {
+    if buf.capacity() < decoded.capacity() || buf.spec() != decoded.spec() {
+        *buf = decoded.make_equivalent();
+    }
+    decoded.convert(buf);
+}
+
+/// Checks that the track thread would be able to play `format`, without creating a decoder for it
+pub(crate) fn check_playable(format: &dyn FormatReader) -> Result<(), NError> {
+    let params = TrackParams::of(format)?;
+    match CODEC_REGISTRY.get_codec(params.codec_params.codec) {
+        Some(_) => Ok(()),
+        None => Err(NError::Decoder(SymphoniaError::Unsupported("codec"))),
+    }
+}
+
+/// Fades out `buf` and fades in the start of `next` over its frames, using equal-power curves
+///
+/// `left` is how many frames are left in the current track from the start of `buf`, `fade` is the length of the crossfade in frames
+fn mix_crossfade(
+    buf: &mut AudioBuffer<f32>,
+    next: &mut OpenTrack,
+    scratch: &mut AudioBuffer<f32>,
+    left: f64,
+    fade: f64,
+) {
+    let frames = buf.frames();
+    let start = ((left - fade).max(0.0).ceil() as usize).min(frames);
+    if start == frames || !next.fill_pending(frames - start, *buf.spec(), scratch) {
+        return;
+    }
+
+    let mixed = (frames - start).min(next.pending_frames());
+    for ch in 0..buf.spec().channels.count() {
+        let incoming = next.pending[ch].drain(..mixed);
+        for (i, (sample, incoming)) in buf.chan_mut(ch)[start..]
+            .iter_mut()
+            .zip(incoming)
+            .enumerate()
+        {
+            let progress = (1.0 - (left - (start + i) as f64) / fade).clamp(0.0, 1.0);
+            let angle = progress * std::f64::consts::FRAC_PI_2;
+            *sample = *sample * angle.cos() as f32 + incoming * angle.sin() as f32;
         }
     }
+    next.pending_ts += mixed as u64;
 }
 
 impl Default for Player {
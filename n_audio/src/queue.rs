@@ -1,6 +1,6 @@
 use crate::music_track::MusicTrack;
-use crate::player::Player;
-use crate::{remove_ext, strip_absolute_path, NError};
+use crate::player::{check_playable, Player};
+use crate::{remove_ext, strip_absolute_path, LengthKind, NError};
 use rand::prelude::SliceRandom;
 use rand::thread_rng;
 use std::fs::File;
@@ -9,6 +9,7 @@ use std::io::{BufRead, BufReader, Seek, SeekFrom, Write};
 use std::ops::{Deref, DerefMut};
 use std::path::PathBuf;
 use std::sync::Arc;
+use symphonia::core::formats::FormatReader;
 use tokio::sync::RwLock;
 
 pub struct QueuePlayer {
@@ -17,6 +18,10 @@ pub struct QueuePlayer {
     player: Player,
     index: usize,
     index_map: Vec<u64>,
+    /// Index of the track given to `Player::preload`, the one that plays after the current
+    preloaded: Option<usize>,
+    /// Set when the queue changed, so that `QueuePlayer::update` preloads the track that now comes next
+    preload_outdated: bool,
 }
 
 impl Default for QueuePlayer {
@@ -27,7 +32,11 @@ impl Default for QueuePlayer {
 
 impl QueuePlayer {
     pub fn new(path: String) -> Self {
-        let player = Player::new(1.0, 1.0);
+        Self::with_player(path, Player::new(1.0, 1.0))
+    }
+
+    /// Instance a `QueuePlayer` around an already made `Player`, e.g. one with a different output backend
+    pub fn with_player(path: String, player: Player) -> Self {
         let queue_file = Arc::new(RwLock::new(BufReader::new(tempfile::tempfile().unwrap())));
 
         QueuePlayer {
@@ -36,6 +45,8 @@ impl QueuePlayer {
             index: usize::MAX - 1,
             path,
             index_map: vec![],
+            preloaded: None,
+            preload_outdated: false,
         }
     }
 
@@ -112,21 +123,33 @@ impl QueuePlayer {
             .write_all(data.as_slice())
     }
 
-    #[inline]
     pub fn remove(&mut self, index: usize) {
         self.index_map.remove(index);
+        // The current track moved back with the others
+        if index < self.index && self.index < usize::MAX - 1 {
+            self.index -= 1;
+        }
+        self.forget_preload();
     }
 
-    #[inline]
     pub fn clear(&mut self) {
         self.queue_file.blocking_write().get_mut().rewind().unwrap();
         self.index_map.clear();
         self.index = usize::MAX - 1;
+        self.forget_preload();
     }
 
-    #[inline]
     pub fn shuffle(&mut self) {
         self.index_map.shuffle(&mut thread_rng());
+        self.forget_preload();
+    }
+
+    /// Makes the track thread forget the preloaded track, which may not be the next one anymore
+    fn forget_preload(&mut self) {
+        if self.preloaded.take().is_some() {
+            // Without a track thread there's nothing to preload for
+            self.preload_outdated = self.player.clear_preload().is_ok();
+        }
     }
 
     pub async fn current_track_name(&self) -> String {
@@ -145,21 +168,105 @@ impl QueuePlayer {
         name
     }
 
-    pub async fn play(&mut self) -> io::Result<()> {
-        let track = MusicTrack::new(self.get_path_for_file(self.index).await.to_str().unwrap())?;
-        let format = tokio::task::spawn_blocking(move || track.get_format()).await??;
+    async fn load_format(
+        &self,
+        index: usize,
+    ) -> Result<(Box<dyn FormatReader>, LengthKind), NError> {
+        let track = MusicTrack::new(self.get_path_for_file(index).await.to_str().unwrap())?;
+        tokio::task::spawn_blocking(move || track.get_format_with_length_kind())
+            .await
+            .map_err(io::Error::from)?
+    }
+
+    fn index_after(&self, index: usize) -> usize {
+        if index + 1 >= self.len() {
+            0
+        } else {
+            index + 1
+        }
+    }
+
+    /// Plays the track at the current index
+    /// Tracks that can't be played are skipped, reporting why with `Message::Error`; it only errors if none can be played
+    pub async fn play(&mut self) -> Result<(), NError> {
+        if self.is_empty() {
+            return Err(NError::NoTrack);
+        }
+        if self.index >= self.len() {
+            self.index = 0;
+        }
+
+        let mut attempts = self.len();
+        loop {
+            let result = match self.load_format(self.index).await {
+                Ok((format, length_kind)) => self.player.play_with_length_kind(format, length_kind),
+                Err(err) => Err(err),
+            };
+            match result {
+                Ok(()) => break,
+                Err(err) if attempts > 1 => {
+                    eprintln!("Skipping track {}: {err}", self.index);
+                    self.player.report_error(err);
+                    attempts -= 1;
+                    self.index = self.index_after(self.index);
+                }
+                Err(err) => return Err(err),
+            }
+        }
+
+        self.preload_next().await
+    }
+
+    /// Preloads the first playable track after the current one so that it can be played without gaps
+    async fn preload_next(&mut self) -> Result<(), NError> {
+        self.preloaded = None;
+        self.preload_outdated = false;
+        let mut index = self.index;
+        for _ in 1..self.len() {
+            index = self.index_after(index);
+            let (format, length_kind) = match self.load_format(index).await {
+                Ok(loaded) => loaded,
+                Err(err) => {
+                    self.player.report_error(err);
+                    continue;
+                }
+            };
+            if let Err(err) = check_playable(format.as_ref()) {
+                self.player.report_error(err);
+                continue;
+            }
+
+            self.preloaded = Some(index);
+            return self
+                .player
+                .preload_with_length_kind(format, length_kind)
+                .await
+                .map_err(|_| NError::Io(io::Error::from(io::ErrorKind::BrokenPipe)));
+        }
+        Ok(())
+    }
 
-        self.player.play(format);
+    /// Keeps the queue in sync with the track thread
+    /// Whenever the preloaded track started playing, the index is moved forward and the following track gets preloaded
+    pub async fn update(&mut self) -> Result<(), NError> {
+        if self.player.has_advanced() {
+            self.index = self
+                .preloaded
+                .unwrap_or_else(|| self.index_after(self.index));
+            self.preload_next().await?;
+        } else if self.preload_outdated {
+            self.preload_next().await?;
+        }
         Ok(())
     }
 
-    pub async fn play_index(&mut self, index: usize) -> io::Result<()> {
+    pub async fn play_index(&mut self, index: usize) -> Result<(), NError> {
         self.index = index;
 
         self.play().await
     }
 
-    pub async fn play_next(&mut self) -> io::Result<()> {
+    pub async fn play_next(&mut self) -> Result<(), NError> {
         self.index += 1;
 
         if self.index >= self.len() {
@@ -169,7 +276,7 @@ impl QueuePlayer {
         self.play().await
     }
 
-    pub async fn play_previous(&mut self) -> io::Result<()> {
+    pub async fn play_previous(&mut self) -> Result<(), NError> {
         if self.index == 0 {
             self.index = self.len();
         }
@@ -79,6 +79,15 @@ impl FormatReader for RawReader {
             .with_max_frames_per_packet(sample_rate as u64 / 50)
             .with_channels(chans);
 
+        // The samples are all that's left after the header, so the length follows from the size of the file
+        let frame_len = (std::mem::size_of::<f32>() as u64) * n_chans as u64;
+        let n_frames = source
+            .byte_len()
+            .map(|len| len.saturating_sub(16) / frame_len);
+        if let Some(n_frames) = n_frames {
+            codec_params.with_n_frames(n_frames);
+        }
+
         Ok(Self {
             source,
             track: Track {
@@ -88,7 +97,7 @@ impl FormatReader for RawReader {
             },
             meta: MetadataLog::default(),
             curr_ts: 0,
-            max_ts: None,
+            max_ts: n_frames.map(|frames| frames.saturating_sub(1)),
         })
     }
 
@@ -0,0 +1,259 @@
+//! Pitch-preserving time stretching
+
+use symphonia::core::audio::{AudioBuffer, Signal, SignalSpec};
+
+/// Time stretcher based on WSOLA (Waveform Similarity Overlap-Add)
+///
+/// Windowed frames of the input are taken every `hop * speed` samples and overlapped every `hop` samples,
+/// each one moved by a few samples to line up with the waveform of the previous frame,
+/// so that the tempo changes while the pitch stays the same
+pub struct TimeStretch {
+    speed: f64,
+    spec: Option<SignalSpec>,
+    frame: usize,
+    window: Vec<f32>,
+    /// Input samples not used yet, for each channel
+    input: Vec<Vec<f32>>,
+    /// Second half of the last windowed frame, to be overlapped with the next one
+    tail: Vec<Vec<f32>>,
+    /// Where the next frame should be taken from, relative to the start of `input`
+    position: f64,
+    /// Where the waveform that naturally follows the last frame starts, relative to the start of `input`
+    natural: Option<usize>,
+}
+
+impl TimeStretch {
+    pub fn new(speed: f32) -> Self {
+        TimeStretch {
+            speed: speed as f64,
+            spec: None,
+            frame: 0,
+            window: vec![],
+            input: vec![],
+            tail: vec![],
+            position: 0.0,
+            natural: None,
+        }
+    }
+
+    pub fn set_speed(&mut self, speed: f32) {
+        self.speed = speed as f64;
+    }
+
+    /// Drops everything buffered, used when the input isn't continuous anymore (e.g. after seeking)
+    pub fn reset(&mut self) {
+        for (input, tail) in self.input.iter_mut().zip(self.tail.iter_mut()) {
+            input.clear();
+            tail.fill(0.0);
+        }
+        self.position = 0.0;
+        self.natural = None;
+    }
+
+    fn configure(&mut self, spec: SignalSpec) {
+        // 40ms frames, overlapped by half
+        self.frame = ((spec.rate as usize / 25).max(64) / 2) * 2;
+        self.window = (0..self.frame)
+            .map(|i| {
+                let phase = 2.0 * std::f32::consts::PI * i as f32 / self.frame as f32;
+                0.5 - 0.5 * phase.cos()
+            })
+            .collect();
+        self.input = vec![vec![]; spec.channels.count()];
+        self.tail = vec![vec![0.0; self.frame / 2]; spec.channels.count()];
+        self.position = 0.0;
+        self.natural = None;
+        self.spec = Some(spec);
+    }
+
+    /// Stretches `input`, writing in `output` all the frames that can be produced so far
+    pub fn process(&mut self, input: &AudioBuffer<f32>, output: &mut AudioBuffer<f32>) {
+        let spec = *input.spec();
+        if self.spec != Some(spec) {
+            self.configure(spec);
+        }
+
+        for (ch, buffered) in self.input.iter_mut().enumerate() {
+            buffered.extend_from_slice(input.chan(ch));
+        }
+        self.stretch(spec, output);
+    }
+
+    /// Stretches what's left of the input into `output`, at the end of the track, returning whether there was any
+    /// Everything is dropped afterwards, as with `TimeStretch::reset`
+    pub fn flush(&mut self, output: &mut AudioBuffer<f32>) -> bool {
+        let Some(spec) = self.spec else {
+            return false;
+        };
+        let left = ((self.input[0].len() as f64 - self.position) / self.speed) as usize;
+        if left > 0 {
+            // Silence after the end lets the last frames be taken, only what the input is worth is kept
+            let padding = (self.frame as f64 * self.speed) as usize + 2 * self.frame;
+            for buffered in self.input.iter_mut() {
+                buffered.resize(buffered.len() + padding, 0.0);
+            }
+            self.stretch(spec, output);
+            output.truncate(left);
+        }
+        self.reset();
+        left > 0
+    }
+
+    /// The spec of the input, once there was any
+    pub fn spec(&self) -> Option<SignalSpec> {
+        self.spec
+    }
+
+    /// Writes in `output` all the frames that can be produced from the buffered input
+    fn stretch(&mut self, spec: SignalSpec, output: &mut AudioBuffer<f32>) {
+        let hop = self.frame / 2;
+        let tolerance = self.frame / 8;
+        let available = self.input[0].len();
+        let capacity = (available as f64 / self.speed) as usize + hop;
+        if output.capacity() < capacity || *output.spec() != spec {
+            *output = AudioBuffer::new(capacity as u64, spec);
+        }
+        output.clear();
+
+        loop {
+            let nominal = self.position.round() as usize;
+            let lower = nominal.saturating_sub(tolerance);
+            let upper = nominal + tolerance;
+            let natural = self.natural;
+
+            // Both the furthest candidate and the natural continuation of the last frame must be available
+            if (upper + self.frame).max(natural.map_or(0, |natural| natural + hop)) > available {
+                break;
+            }
+
+            let start = match natural {
+                Some(natural) => self.best_candidate(natural, lower, upper, hop),
+                None => nominal,
+            };
+
+            let written = output.frames();
+            output.render_reserved(Some(hop));
+            for (ch, (buffered, tail)) in self.input.iter().zip(self.tail.iter_mut()).enumerate() {
+                let frame = &buffered[start..start + self.frame];
+                let out = &mut output.chan_mut(ch)[written..written + hop];
+                for i in 0..hop {
+                    out[i] = tail[i] + frame[i] * self.window[i];
+                    tail[i] = frame[hop + i] * self.window[hop + i];
+                }
+            }
+
+            self.natural = Some(start + hop);
+            self.position += hop as f64 * self.speed;
+        }
+
+        // Drop the input that won't be looked at anymore
+        let used = (self.position as usize)
+            .saturating_sub(tolerance)
+            .min(self.natural.unwrap_or(0));
+        if used > 0 {
+            for buffered in self.input.iter_mut() {
+                buffered.drain(..used);
+            }
+            self.position -= used as f64;
+            self.natural = self.natural.map(|natural| natural - used);
+        }
+    }
+
+    /// Finds the frame start between `lower` and `upper` whose waveform is the most similar
+    /// to the one that naturally followed the last frame
+    fn best_candidate(&self, natural: usize, lower: usize, upper: usize, hop: usize) -> usize {
+        let mono = |i: usize| self.input.iter().map(|ch| ch[i]).sum::<f32>();
+
+        let mut best = lower;
+        let mut best_score = f32::MIN;
+        for candidate in lower..=upper {
+            let mut correlation = 0.0;
+            let mut energy = 0.0;
+            // Comparing one sample out of four is more than enough to find the right alignment
+            for i in (0..hop).step_by(4) {
+                let sample = mono(candidate + i);
+                correlation += mono(natural + i) * sample;
+                energy += sample * sample;
+            }
+            let score = if energy > 0.0 {
+                correlation / energy.sqrt()
+            } else {
+                0.0
+            };
+            if score > best_score {
+                best_score = score;
+                best = candidate;
+            }
+        }
+        best
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use symphonia::core::audio::Layout;
+
+    /// Stretches 2s of a stereo 440Hz sine at 44.1kHz, fed in packets of 1152 frames and flushed at the end
+    /// Returns how many frames went in, and the left channel of what came out
+    fn stretch_sine(speed: f32) -> (usize, Vec<f32>) {
+        let spec = SignalSpec::new_with_layout(44100, Layout::Stereo);
+        let mut stretch = TimeStretch::new(speed);
+        let mut output = AudioBuffer::unused();
+        let mut left = vec![];
+        let packets = 2 * 44100 / 1152;
+        for packet in 0..packets {
+            let mut input = AudioBuffer::<f32>::new(1152, spec);
+            input.render_reserved(Some(1152));
+            for ch in 0..2 {
+                for (i, sample) in input.chan_mut(ch).iter_mut().enumerate() {
+                    let t = (packet * 1152 + i) as f32 / 44100.0;
+                    *sample = (t * 440.0 * std::f32::consts::TAU).sin();
+                }
+            }
+            stretch.process(&input, &mut output);
+            left.extend_from_slice(output.chan(0));
+        }
+        assert!(stretch.flush(&mut output));
+        left.extend_from_slice(output.chan(0));
+        (packets * 1152, left)
+    }
+
+    /// Returns the frequency of a sine from the time between its first and last rising zero crossings
+    fn frequency(samples: &[f32], rate: u32) -> f64 {
+        let crossings: Vec<f64> = samples
+            .windows(2)
+            .enumerate()
+            .filter(|(_, pair)| pair[0] < 0.0 && pair[1] >= 0.0)
+            .map(|(i, pair)| i as f64 + (pair[0] / (pair[0] - pair[1])) as f64)
+            .collect();
+        let periods = (crossings.len() - 1) as f64;
+        periods * rate as f64 / (crossings[crossings.len() - 1] - crossings[0])
+    }
+
+    #[test]
+    fn output_length_follows_the_speed() {
+        for speed in [0.5, 2.0] {
+            let (input, output) = stretch_sine(speed);
+            let expected = input as f64 / speed as f64;
+            // Nothing is left behind once flushed
+            assert!(
+                (output.len() as f64 - expected).abs() <= 1.0,
+                "{} frames instead of {expected} at {speed}x",
+                output.len()
+            );
+        }
+    }
+
+    #[test]
+    fn stretching_keeps_the_pitch() {
+        for speed in [0.5, 2.0] {
+            let (_, output) = stretch_sine(speed);
+            let frequency = frequency(&output, 44100);
+            assert!(
+                (frequency - 440.0).abs() < 1.0,
+                "{frequency}Hz instead of 440Hz at {speed}x"
+            );
+        }
+    }
+}
@@ -0,0 +1,316 @@
+//! A tap on the audio the `Player` plays, and what's needed to turn it into spectrum bars and level meters
+//!
+//! ```no_run
+//! use n_audio::player::Player;
+//! use n_audio::visualizer::Analyzer;
+//!
+//! let player = Player::new(1.0, 1.0);
+//! let frames = Analyzer::new(player.tap(), 32).spawn(30);
+//! for frame in frames.iter() {
+//!     println!("{:?} {:?}", frame.spectrum, frame.levels);
+//! }
+//! ```
+
+use std::f32::consts::PI;
+use std::fmt::{self, Debug, Formatter};
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use flume::Receiver;
+use symphonia::core::dsp::complex::Complex;
+use symphonia::core::dsp::fft::Fft;
+
+/// How many samples the tap keeps, a bit more than a second of 8 channels at 48kHz
+const TAP_LEN: usize = 1 << 19;
+/// How many frames the spectrum is computed on
+const FFT_SIZE: usize = 2048;
+/// The spectrum bars start at the bottom of this range, in dBFS
+const SPECTRUM_FLOOR: f32 = -80.0;
+const LOWEST_FREQUENCY: f32 = 30.0;
+const HIGHEST_FREQUENCY: f32 = 16000.0;
+
+/// The samples shared between the track threads and the taps
+struct Ring {
+    samples: Box<[AtomicU32]>,
+    /// How many samples were ever written
+    written: AtomicU64,
+    /// How many of them the output has played, the taps don't read past it
+    played: AtomicU64,
+    /// Rate in the upper half, channels in the lower one, so that they're changed together
+    spec: AtomicU64,
+}
+
+/// Where the track threads write the audio going to the output
+#[derive(Clone)]
+pub(crate) struct TapWriter {
+    ring: Arc<Ring>,
+}
+
+impl Default for TapWriter {
+    fn default() -> Self {
+        TapWriter {
+            ring: Arc::new(Ring {
+                samples: (0..TAP_LEN).map(|_| AtomicU32::new(0)).collect(),
+                written: AtomicU64::new(0),
+                played: AtomicU64::new(0),
+                spec: AtomicU64::new(0),
+            }),
+        }
+    }
+}
+
+impl TapWriter {
+    /// Never blocks: when the readers fall behind the oldest samples are overwritten
+    /// The taps only read the samples once `TapWriter::played` says the output played them
+    pub fn write(&self, samples: &[f32], rate: u32, channels: usize) {
+        let ring = &self.ring;
+        ring.spec
+            .store(((rate as u64) << 32) | channels as u64, Ordering::Relaxed);
+        let start = ring.written.load(Ordering::Relaxed);
+        for (i, sample) in samples.iter().enumerate() {
+            let index = (start as usize + i) % TAP_LEN;
+            ring.samples[index].store(sample.to_bits(), Ordering::Relaxed);
+        }
+        ring.written
+            .store(start + samples.len() as u64, Ordering::Release);
+    }
+
+    /// Marks what was written as played, except the last `delay` samples, still buffered by the output
+    pub fn played(&self, delay: usize) {
+        let written = self.ring.written.load(Ordering::Relaxed);
+        self.ring
+            .played
+            .store(written.saturating_sub(delay as u64), Ordering::Release);
+    }
+
+    pub fn reader(&self) -> AudioTap {
+        AudioTap {
+            ring: self.ring.clone(),
+            read: self.ring.played.load(Ordering::Acquire),
+        }
+    }
+}
+
+/// Reads the interleaved samples the `Player` sends to the output as they're played, before the volume is applied
+/// Every tap has its own position, so any number of them can read the same audio
+pub struct AudioTap {
+    ring: Arc<Ring>,
+    read: u64,
+}
+
+impl AudioTap {
+    /// Returns the rate and the number of channels of the samples, `(0, 0)` before anything is played
+    pub fn spec(&self) -> (u32, usize) {
+        let spec = self.ring.spec.load(Ordering::Relaxed);
+        ((spec >> 32) as u32, (spec & u32::MAX as u64) as usize)
+    }
+
+    /// Appends the samples played since the last read to `out`, returning how many there were
+    /// If the tap fell too far behind only the latest samples are read
+    pub fn read(&mut self, out: &mut Vec<f32>) -> usize {
+        let played = self.ring.played.load(Ordering::Acquire);
+        let written = self.ring.written.load(Ordering::Acquire);
+        // Restart on a whole frame, or the channels would be mixed up
+        let channels = self.spec().1.max(1) as u64;
+        let kept = TAP_LEN as u64 / channels * channels;
+        let start = self.read.max(written.saturating_sub(kept)).min(played);
+        out.extend((start..played).map(|i| {
+            f32::from_bits(self.ring.samples[i as usize % TAP_LEN].load(Ordering::Relaxed))
+        }));
+        self.read = played;
+        (played - start) as usize
+    }
+}
+
+// The samples would be too many to print
+impl Debug for TapWriter {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TapWriter")
+            .field("written", &self.ring.written)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Debug for AudioTap {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AudioTap")
+            .field("spec", &self.spec())
+            .field("read", &self.read)
+            .finish_non_exhaustive()
+    }
+}
+
+/// The levels of a channel, both between `0.0` and `1.0` (full scale)
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct Level {
+    pub rms: f32,
+    pub peak: f32,
+}
+
+/// What the audio looked like since the previous frame
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Frame {
+    /// Height of the spectrum bars from the lowest frequency, between `0.0` (-80 dBFS or less) and `1.0` (0 dBFS)
+    pub spectrum: Vec<f32>,
+    /// Levels of every channel
+    pub levels: Vec<Level>,
+}
+
+/// Turns what an [`AudioTap`] reads into spectrum bars and levels
+pub struct Analyzer {
+    tap: AudioTap,
+    bands: usize,
+    fft: Fft,
+    window: Vec<f32>,
+    /// The last `FFT_SIZE` frames, mixed down to mono
+    history: Vec<f32>,
+    samples: Vec<f32>,
+    spectrum: Vec<Complex>,
+}
+
+impl Analyzer {
+    /// `bands` is how many spectrum bars there are, spaced logarithmically from 30Hz to 16kHz
+    pub fn new(tap: AudioTap, bands: usize) -> Self {
+        // Hann window
+        let window = (0..FFT_SIZE)
+            .map(|i| 0.5 - 0.5 * (2.0 * PI * i as f32 / FFT_SIZE as f32).cos())
+            .collect();
+        Analyzer {
+            tap,
+            bands,
+            fft: Fft::new(FFT_SIZE),
+            window,
+            history: vec![0.0; FFT_SIZE],
+            samples: vec![],
+            spectrum: vec![Complex::default(); FFT_SIZE],
+        }
+    }
+
+    /// Analyzes what was played since the last call
+    /// When nothing was (e.g. the `Player` is paused) the frame is silent
+    pub fn analyze(&mut self) -> Frame {
+        self.samples.clear();
+        self.tap.read(&mut self.samples);
+        let (rate, channels) = self.tap.spec();
+        if channels == 0 || self.samples.len() < channels {
+            self.history.fill(0.0);
+            return Frame {
+                spectrum: vec![0.0; self.bands],
+                levels: vec![Level::default(); channels],
+            };
+        }
+
+        let mut levels = vec![Level::default(); channels];
+        for frame in self.samples.chunks_exact(channels) {
+            for (level, sample) in levels.iter_mut().zip(frame) {
+                level.rms += sample * sample;
+                level.peak = level.peak.max(sample.abs());
+            }
+        }
+        let frames = self.samples.len() / channels;
+        for level in &mut levels {
+            level.rms = (level.rms / frames as f32).sqrt();
+        }
+
+        let mono = self
+            .samples
+            .chunks_exact(channels)
+            .map(|frame| frame.iter().sum::<f32>() / channels as f32);
+        let keep = FFT_SIZE.saturating_sub(frames);
+        self.history.copy_within(FFT_SIZE - keep.., 0);
+        self.history.truncate(keep);
+        self.history
+            .extend(mono.skip(frames.saturating_sub(FFT_SIZE)));
+
+        Frame {
+            spectrum: self.spectrum(rate),
+            levels,
+        }
+    }
+
+    fn spectrum(&mut self, rate: u32) -> Vec<f32> {
+        for ((bin, sample), window) in self
+            .spectrum
+            .iter_mut()
+            .zip(&self.history)
+            .zip(&self.window)
+        {
+            *bin = Complex::new(sample * window, 0.0);
+        }
+        self.fft.fft_inplace(&mut self.spectrum);
+
+        // A full scale sine wave has a magnitude of a quarter of the size, because of the window
+        let scale = 4.0 / FFT_SIZE as f32;
+        let bin_width = rate as f32 / FFT_SIZE as f32;
+        let highest = HIGHEST_FREQUENCY.min(rate as f32 / 2.0);
+        let ratio = highest / LOWEST_FREQUENCY;
+        (0..self.bands)
+            .map(|band| {
+                let low = LOWEST_FREQUENCY * ratio.powf(band as f32 / self.bands as f32);
+                let high = LOWEST_FREQUENCY * ratio.powf((band + 1) as f32 / self.bands as f32);
+                let first = ((low / bin_width) as usize).clamp(1, FFT_SIZE / 2 - 1);
+                let last = ((high / bin_width) as usize).clamp(first + 1, FFT_SIZE / 2);
+                let magnitude = self.spectrum[first..last]
+                    .iter()
+                    .map(|bin| (bin.re * bin.re + bin.im * bin.im).sqrt())
+                    .fold(0.0, f32::max);
+                let db = 20.0 * (magnitude * scale).max(f32::MIN_POSITIVE).log10();
+                ((db - SPECTRUM_FLOOR) / -SPECTRUM_FLOOR).clamp(0.0, 1.0)
+            })
+            .collect()
+    }
+
+    /// Analyzes the audio `fps` times per second on its own thread, until the receiver is dropped
+    pub fn spawn(mut self, fps: u32) -> Receiver<Frame> {
+        let (tx, rx) = flume::bounded(1);
+        let interval = Duration::from_secs_f64(1.0 / fps.max(1) as f64);
+        thread::spawn(move || loop {
+            thread::sleep(interval);
+            // A slow receiver only misses frames
+            if let Err(flume::TrySendError::Disconnected(_)) = tx.try_send(self.analyze()) {
+                break;
+            }
+        });
+        rx
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn taps_only_read_what_was_played() {
+        let writer = TapWriter::default();
+        let mut tap = writer.reader();
+        let mut out = vec![];
+
+        writer.write(&[0.5; 8], 48000, 2);
+        assert_eq!(tap.read(&mut out), 0);
+        // The output still has 3 frames to play
+        writer.played(6);
+        assert_eq!(tap.read(&mut out), 2);
+        writer.played(0);
+        assert_eq!(tap.read(&mut out), 6);
+        assert_eq!(out, [0.5; 8]);
+    }
+
+    #[test]
+    fn tap_that_fell_behind_restarts_on_a_frame() {
+        let writer = TapWriter::default();
+        let mut tap = writer.reader();
+        let mut out = vec![];
+
+        // 5.1 frames, more than the tap keeps
+        let frame = [0.0, 1.0, 2.0, 3.0, 4.0, 5.0];
+        let samples: Vec<f32> = frame.repeat(TAP_LEN / 6 + 10);
+        writer.write(&samples, 48000, 6);
+        writer.played(0);
+        tap.read(&mut out);
+
+        assert!(out.len() <= TAP_LEN);
+        assert!(out.chunks(6).all(|read| read == frame));
+    }
+}
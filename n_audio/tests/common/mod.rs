@@ -0,0 +1,144 @@
+//! Fixture writers shared by the integration tests
+
+// Each test only uses some of them
+#![allow(dead_code)]
+
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::ops::Range;
+use std::path::Path;
+
+use audiopus::coder::Encoder;
+use audiopus::{Application, Channels, SampleRate};
+use flume::Receiver;
+use n_audio::output::{AudioOutput, CaptureOutput, OutputBackend, Result};
+
+pub const RATE: u32 = 48000;
+/// Sample values are their index divided by this, so that they're exact in an `f32`
+pub const SCALE: f32 = (1 << 20) as f32;
+/// Frames in a 20ms Opus packet
+pub const OPUS_FRAME: usize = 960;
+
+/// Captures the audio, but only opens once the test lets it, so that the track thread can't start before that
+#[derive(Debug)]
+pub struct HeldCapture {
+    pub capture: CaptureOutput,
+    pub rx_open: Receiver<()>,
+}
+
+impl OutputBackend for HeldCapture {
+    fn open(&self, device_name: Option<&str>) -> Result<Box<dyn AudioOutput>> {
+        let _ = self.rx_open.recv();
+        self.capture.open(device_name)
+    }
+}
+
+/// Writes a stereo rawf32 file whose samples are their frame index
+pub fn write_raw(path: &Path, frames: usize) {
+    write_raw_range(path, 0..frames);
+}
+
+/// Writes a stereo rawf32 file whose samples are the indices in `frames`, as if it was cut out of a longer one
+pub fn write_raw_range(path: &Path, frames: Range<usize>) {
+    let mut file = BufWriter::new(File::create(path).unwrap());
+    file.write_all(b"SbirdRaw").unwrap();
+    file.write_all(&RATE.to_le_bytes()).unwrap();
+    file.write_all(&2u32.to_le_bytes()).unwrap();
+    for frame in frames {
+        let sample = frame as f32 / SCALE;
+        file.write_all(&sample.to_le_bytes()).unwrap();
+        file.write_all(&sample.to_le_bytes()).unwrap();
+    }
+}
+
+/// How `write_dca` lays out its file
+#[derive(Default)]
+pub struct DcaFixture<'a> {
+    /// Writes the headerless DCA0 instead of DCA1
+    pub legacy: bool,
+    /// Packets replaced by the given bytes, by their index
+    pub broken: &'a [(usize, &'a [u8])],
+    /// Base64 image written as the cover in the DCA1 header
+    pub cover: Option<&'a str>,
+}
+
+/// Writes a stereo DCA file of `packets` Opus packets, encoded with in-band FEC
+pub fn write_dca(path: &Path, packets: usize, fixture: DcaFixture) {
+    let info = match fixture.cover {
+        Some(cover) => format!(r#","info":{{"cover":"{cover}"}}"#),
+        None => String::new(),
+    };
+    let metadata = format!(
+        r#"{{"dca":{{"version":1,"tool":{{"name":"n_audio","version":"test"}}}},"opus":{{"mode":"music","sample_rate":48000,"frame_size":960,"vbr":true,"channels":2}}{info}}}"#
+    );
+    let metadata = metadata.as_bytes();
+    let mut file = BufWriter::new(File::create(path).unwrap());
+    if !fixture.legacy {
+        file.write_all(b"DCA1").unwrap();
+        file.write_all(&(metadata.len() as u32).to_le_bytes())
+            .unwrap();
+        file.write_all(metadata).unwrap();
+    }
+
+    let mut encoder =
+        Encoder::new(SampleRate::Hz48000, Channels::Stereo, Application::Audio).unwrap();
+    encoder.set_inband_fec(true).unwrap();
+    encoder.set_packet_loss_perc(10).unwrap();
+    let mut packet = [0; 4000];
+    for index in 0..packets {
+        let input: Vec<f32> = (0..OPUS_FRAME * 2)
+            .map(|i| ((index * OPUS_FRAME + i / 2) as f32 * 0.05).sin() * 0.5)
+            .collect();
+        let len = encoder.encode_float(&input, &mut packet).unwrap();
+        let data = match fixture.broken.iter().find(|(broken, _)| *broken == index) {
+            Some((_, data)) => *data,
+            None => &packet[..len],
+        };
+        file.write_all(&(data.len() as u16).to_le_bytes()).unwrap();
+        file.write_all(data).unwrap();
+    }
+}
+
+/// Writes a stereo 32-bit float WAV file with the tags in `info`, given by their RIFF INFO id
+pub fn write_wav(path: &Path, frames: usize, info: &[(&[u8; 4], &str)]) {
+    let mut list = b"INFO".to_vec();
+    for (id, text) in info {
+        let mut text = text.as_bytes().to_vec();
+        let len = text.len() as u32;
+        // Chunks are padded to an even size
+        if text.len() % 2 == 1 {
+            text.push(0);
+        }
+        list.extend_from_slice(*id);
+        list.extend_from_slice(&len.to_le_bytes());
+        list.extend_from_slice(&text);
+    }
+    let data_len = (frames * 2 * 4) as u32;
+
+    let mut file = BufWriter::new(File::create(path).unwrap());
+    file.write_all(b"RIFF").unwrap();
+    let riff_len = 4 + (8 + 16) + (8 + list.len() as u32) + (8 + data_len);
+    file.write_all(&riff_len.to_le_bytes()).unwrap();
+    file.write_all(b"WAVE").unwrap();
+
+    file.write_all(b"fmt ").unwrap();
+    file.write_all(&16u32.to_le_bytes()).unwrap();
+    file.write_all(&3u16.to_le_bytes()).unwrap();
+    file.write_all(&2u16.to_le_bytes()).unwrap();
+    file.write_all(&RATE.to_le_bytes()).unwrap();
+    file.write_all(&(RATE * 8).to_le_bytes()).unwrap();
+    file.write_all(&8u16.to_le_bytes()).unwrap();
+    file.write_all(&32u16.to_le_bytes()).unwrap();
+
+    file.write_all(b"LIST").unwrap();
+    file.write_all(&(list.len() as u32).to_le_bytes()).unwrap();
+    file.write_all(&list).unwrap();
+
+    file.write_all(b"data").unwrap();
+    file.write_all(&data_len.to_le_bytes()).unwrap();
+    for frame in 0..frames {
+        let sample = (frame as f32 * 0.05).sin() * 0.5;
+        file.write_all(&sample.to_le_bytes()).unwrap();
+        file.write_all(&sample.to_le_bytes()).unwrap();
+    }
+}
@@ -0,0 +1,101 @@
+//! Checks that lost and corrupt Opus packets are concealed instead of dropped
+
+use std::path::Path;
+use std::sync::Arc;
+
+use n_audio::output::CaptureOutput;
+use n_audio::player::Player;
+use n_audio::PlayerEvent;
+
+use common::{write_dca, DcaFixture, OPUS_FRAME, RATE};
+
+mod common;
+
+/// Plays `path` until the end, returning the interleaved samples and how many frames were concealed
+async fn play(path: &Path) -> (Vec<f32>, u64) {
+    let capture = CaptureOutput::new(RATE, 2);
+    let mut player = Player::builder().backend(Arc::new(capture.clone())).build();
+    player.set_fade_length(0.0).await.unwrap();
+    let events = player.subscribe();
+    player.play_from_path(path.to_str().unwrap()).unwrap();
+    while let Ok(event) = events.recv_async().await {
+        if let PlayerEvent::TrackEnded = event {
+            break;
+        }
+    }
+    (capture.samples(), player.stats().concealed_frames)
+}
+
+#[tokio::test]
+async fn lost_packets_are_concealed() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("lossy.dca");
+    let packets = 50;
+    // An empty packet, and a code 3 packet cut before its frame count
+    write_dca(
+        &path,
+        packets,
+        DcaFixture {
+            broken: &[(10, &[]), (20, &[0xff])],
+            ..Default::default()
+        },
+    );
+
+    let (samples, concealed) = play(&path).await;
+
+    assert_eq!(samples.len() / 2, packets * OPUS_FRAME);
+    assert_eq!(concealed, 2 * OPUS_FRAME as u64);
+}
+
+#[tokio::test]
+async fn consecutive_lost_packets_are_concealed() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("lossy.dca");
+    let packets = 50;
+    write_dca(
+        &path,
+        packets,
+        DcaFixture {
+            broken: &[(10, &[]), (11, &[]), (12, &[0xff])],
+            ..Default::default()
+        },
+    );
+
+    let (samples, concealed) = play(&path).await;
+
+    assert_eq!(samples.len() / 2, packets * OPUS_FRAME);
+    assert_eq!(concealed, 3 * OPUS_FRAME as u64);
+}
+
+#[tokio::test]
+async fn intact_stream_conceals_nothing() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("intact.dca");
+    let packets = 50;
+    write_dca(&path, packets, DcaFixture::default());
+
+    let (samples, concealed) = play(&path).await;
+
+    assert_eq!(samples.len() / 2, packets * OPUS_FRAME);
+    assert_eq!(concealed, 0);
+}
+
+#[tokio::test]
+async fn lost_last_packet_is_concealed() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("lossy.dca");
+    let packets = 50;
+    write_dca(
+        &path,
+        packets,
+        DcaFixture {
+            broken: &[(packets - 1, &[])],
+            ..Default::default()
+        },
+    );
+
+    let (samples, concealed) = play(&path).await;
+
+    assert_eq!(samples.len() / 2, packets * OPUS_FRAME);
+    assert_eq!(concealed, OPUS_FRAME as u64);
+}
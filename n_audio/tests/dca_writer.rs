@@ -0,0 +1,128 @@
+//! Checks that tracks written with `DcaWriter` are read back whole, with their tags
+
+use std::fs::File;
+use std::io::ErrorKind;
+use std::path::Path;
+
+use n_audio::dca::{DcaReader, DcaWriter};
+use n_audio::music_track::MusicTrack;
+use symphonia::core::errors::Error as SymphoniaError;
+use symphonia::core::formats::{FormatOptions, FormatReader};
+use symphonia::core::io::MediaSourceStream;
+use symphonia::core::meta::StandardTagKey;
+
+use common::{write_dca, write_wav, DcaFixture, OPUS_FRAME, RATE};
+
+mod common;
+
+/// Reads `path` with `DcaReader`, returning how many frames its packets hold
+fn read_dca(path: &Path) -> (DcaReader, u64) {
+    let source = MediaSourceStream::new(Box::new(File::open(path).unwrap()), Default::default());
+    let mut reader = DcaReader::try_new(source, &FormatOptions::default()).unwrap();
+    let mut frames = 0;
+    loop {
+        match reader.next_packet() {
+            Ok(packet) => frames += packet.dur(),
+            Err(SymphoniaError::IoError(err)) if err.kind() == ErrorKind::UnexpectedEof => break,
+            Err(err) => panic!("{err}"),
+        }
+    }
+    (reader, frames)
+}
+
+fn tag(reader: &mut DcaReader, key: StandardTagKey) -> Option<String> {
+    let metadata = reader.metadata();
+    let revision = metadata.current()?;
+    revision
+        .tags()
+        .iter()
+        .find(|tag| tag.std_key == Some(key))
+        .map(|tag| tag.value.to_string())
+}
+
+#[test]
+fn round_trip_keeps_samples_and_tags() {
+    let dir = tempfile::tempdir().unwrap();
+    let wav = dir.path().join("source.wav");
+    let dca = dir.path().join("encoded.dca");
+    // Not a whole number of packets, the last one is padded
+    let frames = RATE as usize + 123;
+    write_wav(
+        &wav,
+        frames,
+        &[
+            (b"INAM", "Title"),
+            (b"IART", "Artist"),
+            (b"IPRD", "Album"),
+            (b"IGNR", "Genre"),
+            (b"ICMT", "Comment"),
+        ],
+    );
+
+    let track = MusicTrack::new(wav.to_str().unwrap()).unwrap();
+    let written = DcaWriter::new()
+        .with_bitrate(64000)
+        .write(&track, File::create(&dca).unwrap())
+        .unwrap();
+
+    let padded = frames.div_ceil(OPUS_FRAME) * OPUS_FRAME;
+    assert_eq!(written, padded as u64);
+    let (mut reader, read) = read_dca(&dca);
+    assert_eq!(read, padded as u64);
+
+    assert_eq!(
+        tag(&mut reader, StandardTagKey::TrackTitle).unwrap(),
+        "Title"
+    );
+    assert_eq!(tag(&mut reader, StandardTagKey::Artist).unwrap(), "Artist");
+    assert_eq!(tag(&mut reader, StandardTagKey::Album).unwrap(), "Album");
+    assert_eq!(tag(&mut reader, StandardTagKey::Genre).unwrap(), "Genre");
+    assert_eq!(
+        tag(&mut reader, StandardTagKey::Comment).unwrap(),
+        "Comment"
+    );
+}
+
+#[test]
+fn front_cover_is_kept_and_no_title_is_made_up() {
+    let dir = tempfile::tempdir().unwrap();
+    let source = dir.path().join("source.dca");
+    let dca = dir.path().join("encoded.dca");
+    // The signature starting every PNG image
+    let png = b"\x89PNG\r\n\x1a\n";
+    write_dca(
+        &source,
+        10,
+        DcaFixture {
+            cover: Some("iVBORw0KGgo="),
+            ..Default::default()
+        },
+    );
+
+    let track = MusicTrack::new(source.to_str().unwrap()).unwrap();
+    DcaWriter::new()
+        .write(&track, File::create(&dca).unwrap())
+        .unwrap();
+
+    let (mut reader, _) = read_dca(&dca);
+    // Not the name of the file
+    assert_eq!(tag(&mut reader, StandardTagKey::TrackTitle), None);
+    let metadata = reader.metadata();
+    let revision = metadata.current().unwrap();
+    assert_eq!(revision.visuals().len(), 1);
+    assert_eq!(&*revision.visuals()[0].data, png);
+}
+
+#[test]
+fn invalid_frame_size_is_an_error() {
+    let dir = tempfile::tempdir().unwrap();
+    let wav = dir.path().join("source.wav");
+    write_wav(&wav, OPUS_FRAME, &[]);
+
+    let track = MusicTrack::new(wav.to_str().unwrap()).unwrap();
+    let result = DcaWriter::new()
+        .with_frame_size(1000)
+        .write(&track, Vec::new());
+
+    assert!(result.is_err());
+}
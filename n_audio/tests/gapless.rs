@@ -0,0 +1,92 @@
+//! Checks that a preloaded track starts right where the previous one ended
+
+use std::path::Path;
+use std::sync::Arc;
+
+use n_audio::music_track::MusicTrack;
+use n_audio::output::CaptureOutput;
+use n_audio::player::Player;
+use n_audio::PlayerEvent;
+
+use common::{write_raw_range, HeldCapture, RATE, SCALE};
+
+mod common;
+
+/// Plays `first` with `second` preloaded until both ended, crossfading them over `crossfade` seconds
+/// Returns the interleaved samples and the first position given for `second`
+async fn play_both(first: &Path, second: &Path, crossfade: f64) -> (Vec<f32>, Option<f64>) {
+    let capture = CaptureOutput::new(RATE, 2);
+    let (tx_open, rx_open) = flume::unbounded();
+    let backend = HeldCapture {
+        capture: capture.clone(),
+        rx_open,
+    };
+    let mut player = Player::builder().backend(Arc::new(backend)).build();
+    player.set_fade_length(0.0).await.unwrap();
+    player.set_crossfade(crossfade).await.unwrap();
+    let events = player.subscribe();
+    player.play_from_path(first.to_str().unwrap()).unwrap();
+    let next = MusicTrack::new(second.to_str().unwrap()).unwrap();
+    player.preload(next.get_format().unwrap()).await.unwrap();
+    // The preload is the first message handled, before anything is decoded
+    tx_open.send(()).unwrap();
+
+    let mut ended = 0;
+    let mut position = None;
+    while let Ok(event) = events.recv_async().await {
+        match event {
+            PlayerEvent::TrackEnded => {
+                ended += 1;
+                if ended == 2 {
+                    break;
+                }
+            }
+            PlayerEvent::Position(time) if ended == 1 && position.is_none() => {
+                position = Some(time.position)
+            }
+            _ => {}
+        }
+    }
+    assert!(player.has_advanced());
+    (capture.samples(), position)
+}
+
+#[tokio::test]
+async fn no_silence_at_the_seam() {
+    let dir = tempfile::tempdir().unwrap();
+    let first = dir.path().join("first.rawf32");
+    let second = dir.path().join("second.rawf32");
+    // Not a whole number of packets, so the seam falls inside the output buffer
+    let seam = RATE as usize * 2 + 123;
+    let frames = seam + RATE as usize;
+    write_raw_range(&first, 0..seam);
+    write_raw_range(&second, seam..frames);
+
+    let (samples, _) = play_both(&first, &second, 0.0).await;
+
+    assert_eq!(samples.len() / 2, frames);
+    for (frame, samples) in samples.chunks(2).enumerate() {
+        assert_eq!(samples[0] * SCALE, frame as f32, "frame {frame}");
+        assert_eq!(samples[1] * SCALE, frame as f32, "frame {frame}");
+    }
+}
+
+#[tokio::test]
+async fn crossfaded_track_continues_from_what_was_mixed() {
+    let dir = tempfile::tempdir().unwrap();
+    let first = dir.path().join("first.rawf32");
+    let second = dir.path().join("second.rawf32");
+    write_raw_range(&first, 0..RATE as usize * 2);
+    write_raw_range(&second, 0..RATE as usize * 2);
+
+    // Not a whole number of packets, so that the handover falls inside a packet of the second track
+    let crossfade = 0.51;
+    let (_, position) = play_both(&first, &second, crossfade).await;
+
+    // The second track is played from the first frame that wasn't mixed into the end of the first one
+    let position = position.unwrap();
+    assert!(
+        (position - crossfade).abs() < 0.001,
+        "the second track starts at {position}s"
+    );
+}
@@ -0,0 +1,59 @@
+//! Checks that the queue keeps the track thread's preloaded track in sync when it changes
+
+use std::sync::Arc;
+
+use n_audio::output::CaptureOutput;
+use n_audio::player::Player;
+use n_audio::queue::QueuePlayer;
+use n_audio::PlayerEvent;
+
+use common::{write_raw_range, HeldCapture, RATE, SCALE};
+
+mod common;
+
+#[tokio::test]
+async fn removed_track_isnt_played_after_the_current_one() {
+    let dir = tempfile::tempdir().unwrap();
+    let frames = RATE as usize;
+    // Only playing "third" right after "first" keeps the samples counting up
+    write_raw_range(&dir.path().join("first.rawf32"), 0..frames);
+    write_raw_range(&dir.path().join("second.rawf32"), 10 * frames..11 * frames);
+    write_raw_range(&dir.path().join("third.rawf32"), frames..2 * frames);
+
+    let capture = CaptureOutput::new(RATE, 2);
+    let (tx_open, rx_open) = flume::unbounded();
+    let backend = HeldCapture {
+        capture: capture.clone(),
+        rx_open,
+    };
+    let player = Player::builder().backend(Arc::new(backend)).build();
+    let mut queue = QueuePlayer::with_player(dir.path().to_str().unwrap().to_string(), player);
+    queue
+        .add_all(["first.rawf32", "second.rawf32", "third.rawf32"])
+        .await
+        .unwrap();
+    queue.set_fade_length(0.0).await.unwrap();
+    let events = queue.subscribe();
+
+    // "second" is preloaded, and removed before the track thread starts
+    queue.play_index(0).await.unwrap();
+    queue.remove(1);
+    queue.update().await.unwrap();
+    tx_open.send(()).unwrap();
+
+    let mut ended = 0;
+    while let Ok(event) = events.recv_async().await {
+        if let PlayerEvent::TrackEnded = event {
+            ended += 1;
+            if ended == 2 {
+                break;
+            }
+        }
+    }
+
+    let samples = capture.samples();
+    assert_eq!(samples.len() / 2, 2 * frames);
+    for (frame, samples) in samples.chunks(2).enumerate() {
+        assert_eq!(samples[0] * SCALE, frame as f32, "frame {frame}");
+    }
+}
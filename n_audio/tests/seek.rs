@@ -0,0 +1,216 @@
+//! Checks that seeking lands on the exact frame that was asked for
+
+use std::fs::File;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use audiopus::coder::Decoder;
+use audiopus::{Channels, SampleRate};
+use flume::{Receiver, Sender};
+use n_audio::dca::DcaReader;
+use n_audio::music_track::MusicTrack;
+use n_audio::output::{AudioOutput, OutputBackend, Result};
+use n_audio::player::Player;
+use n_audio::LengthKind;
+use symphonia::core::audio::{Layout, SignalSpec};
+use symphonia::core::formats::{FormatOptions, FormatReader};
+use symphonia::core::io::MediaSourceStream;
+
+use common::{write_dca, write_raw, DcaFixture, OPUS_FRAME, RATE, SCALE};
+
+mod common;
+
+/// Output that hands every write to the test, and waits for the test to let it go on
+/// It can be opened once, so that the test knows the track ended when the output is dropped
+#[derive(Debug)]
+struct Gate {
+    spec: SignalSpec,
+    tx: Mutex<Option<Sender<Vec<f32>>>>,
+    rx_ack: Receiver<()>,
+}
+
+impl OutputBackend for Gate {
+    fn open(&self, _device_name: Option<&str>) -> Result<Box<dyn AudioOutput>> {
+        Ok(Box::new(GateOutput {
+            spec: self.spec,
+            tx: self.tx.lock().unwrap().take().unwrap(),
+            rx_ack: self.rx_ack.clone(),
+        }))
+    }
+}
+
+struct GateOutput {
+    spec: SignalSpec,
+    tx: Sender<Vec<f32>>,
+    rx_ack: Receiver<()>,
+}
+
+impl AudioOutput for GateOutput {
+    fn spec(&self) -> SignalSpec {
+        self.spec
+    }
+
+    fn write(&mut self, samples: &[f32]) -> Result<()> {
+        if self.tx.send(samples.to_vec()).is_ok() {
+            let _ = self.rx_ack.recv();
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) {}
+}
+
+/// Plays `path`, seeks to `secs` right after the first write and returns the interleaved samples written after the seek
+/// The audio fades in for `fade` seconds after the seek
+async fn samples_after_seek(path: &Path, secs: f64, fade: f64) -> Vec<f32> {
+    let (tx, rx) = flume::unbounded();
+    let (tx_ack, rx_ack) = flume::unbounded();
+    let gate = Gate {
+        spec: SignalSpec::new_with_layout(RATE, Layout::Stereo),
+        tx: Mutex::new(Some(tx)),
+        rx_ack,
+    };
+    let mut player = Player::builder().backend(Arc::new(gate)).build();
+    player.set_fade_length(fade).await.unwrap();
+    player.play_from_path(path.to_str().unwrap()).unwrap();
+
+    // The track thread is waiting inside the first write, so the seek is handled before anything else is decoded
+    rx.recv_async().await.unwrap();
+    player
+        .seek_to(secs.trunc() as u64, secs.fract())
+        .await
+        .unwrap();
+    tx_ack.send(()).unwrap();
+
+    let mut samples = vec![];
+    while let Ok(written) = rx.recv_async().await {
+        samples.extend(written);
+        tx_ack.send(()).unwrap();
+    }
+    samples
+}
+
+/// Decodes `count` packets of the DCA file at `path` from the one at `first`,
+/// with a decoder starting afresh there like the player's after a seek
+fn decode_dca(path: &Path, first: usize, count: usize) -> Vec<f32> {
+    let source = MediaSourceStream::new(Box::new(File::open(path).unwrap()), Default::default());
+    let mut reader = DcaReader::try_new(source, &FormatOptions::default()).unwrap();
+    let mut decoder = Decoder::new(SampleRate::Hz48000, Channels::Stereo).unwrap();
+    let mut samples = vec![];
+    for index in 0..first + count {
+        let packet = reader.next_packet().unwrap();
+        if index < first {
+            continue;
+        }
+        let mut out = [0.0; OPUS_FRAME * 2];
+        let input = packet.buf().try_into().unwrap();
+        let output = (&mut out[..]).try_into().unwrap();
+        let frames = decoder.decode_float(Some(input), output, false).unwrap();
+        samples.extend_from_slice(&out[..frames * 2]);
+    }
+    samples
+}
+
+/// Checks that the first frames played after seeking to `target` are the ones the packet holding it decodes to, from `target` on
+fn assert_dca_seek_lands_on(path: &Path, samples: &[f32], target: usize) {
+    let decoded = decode_dca(path, target / OPUS_FRAME, 2);
+    let expected = &decoded[target % OPUS_FRAME * 2..];
+    assert!(samples.len() >= expected.len());
+    for (i, (sample, expected)) in samples.iter().zip(expected).enumerate() {
+        assert!(
+            (sample - expected).abs() < 1e-4,
+            "sample {i} is {sample} instead of {expected}"
+        );
+    }
+}
+
+#[tokio::test]
+async fn raw_seek_is_sample_accurate() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("index.rawf32");
+    let frames = RATE as usize * 3;
+    write_raw(&path, frames);
+
+    // 1.2578125s, exact in binary and not on a packet boundary
+    let target = 60375;
+    let samples = samples_after_seek(&path, target as f64 / RATE as f64, 0.0).await;
+
+    assert_eq!(samples[0] * SCALE, target as f32);
+    assert_eq!(samples[1] * SCALE, target as f32);
+    assert_eq!(samples.len() / 2, frames - target);
+}
+
+#[tokio::test]
+async fn raw_seek_back_to_start() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("index.rawf32");
+    let frames = RATE as usize;
+    write_raw(&path, frames);
+
+    let samples = samples_after_seek(&path, 0.0, 0.0).await;
+
+    assert_eq!(samples[0], 0.0);
+    assert_eq!(samples.len() / 2, frames);
+}
+
+#[tokio::test]
+async fn dca_seek_is_sample_accurate() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("sine.dca");
+    let packets = 150;
+    write_dca(&path, packets, DcaFixture::default());
+
+    // In the middle of the 63rd packet, its first 855 frames are dropped
+    let target = 60375;
+    let samples = samples_after_seek(&path, target as f64 / RATE as f64, 0.0).await;
+
+    assert_dca_seek_lands_on(&path, &samples, target);
+    assert_eq!(samples.len() / 2, packets * OPUS_FRAME - target);
+}
+
+#[tokio::test]
+async fn dca0_has_a_length_and_seeks() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("legacy.dca");
+    let packets = 150;
+    write_dca(
+        &path,
+        packets,
+        DcaFixture {
+            legacy: true,
+            ..Default::default()
+        },
+    );
+
+    let length = MusicTrack::new(path.to_str().unwrap())
+        .unwrap()
+        .get_length()
+        .unwrap();
+    assert_eq!(length.length_kind, LengthKind::Exact);
+    assert_eq!(length.length, (packets * OPUS_FRAME) as f64 / RATE as f64);
+
+    let target = 60375;
+    let samples = samples_after_seek(&path, target as f64 / RATE as f64, 0.0).await;
+
+    assert_dca_seek_lands_on(&path, &samples, target);
+    assert_eq!(samples.len() / 2, packets * OPUS_FRAME - target);
+}
+
+#[tokio::test]
+async fn seek_fades_in() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("index.rawf32");
+    let frames = RATE as usize * 3;
+    write_raw(&path, frames);
+
+    let target = 60375;
+    let fade = 480;
+    let samples = samples_after_seek(&path, target as f64 / RATE as f64, 0.01).await;
+
+    // The gain goes up by the same step every frame, and the frames are still the right ones
+    let gain = |frame: usize| samples[frame * 2] * SCALE / (target + frame) as f32;
+    assert!(gain(0) < 0.01);
+    assert!((gain(fade / 2 - 1) - 0.5).abs() < 0.01);
+    assert_eq!(samples[fade * 2] * SCALE, (target + fade) as f32);
+    assert_eq!(samples.len() / 2, frames - target);
+}
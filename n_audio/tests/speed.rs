@@ -0,0 +1,43 @@
+//! Checks that changing the playback speed keeps all of the track
+
+use std::sync::Arc;
+
+use n_audio::output::CaptureOutput;
+use n_audio::player::Player;
+use n_audio::{PlayerEvent, SpeedMode};
+
+use common::{write_raw, RATE};
+
+mod common;
+
+#[tokio::test]
+async fn stretched_track_is_played_to_the_end() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("track.rawf32");
+    let frames = RATE as usize * 2;
+    write_raw(&path, frames);
+
+    let capture = CaptureOutput::new(RATE, 2);
+    let mut player = Player::builder().backend(Arc::new(capture.clone())).build();
+    player.set_fade_length(0.0).await.unwrap();
+    player
+        .set_speed_mode(SpeedMode::PreservePitch)
+        .await
+        .unwrap();
+    player.set_playback_speed(2.0).await.unwrap();
+    let events = player.subscribe();
+    player.play_from_path(path.to_str().unwrap()).unwrap();
+    while let Ok(event) = events.recv_async().await {
+        if let PlayerEvent::TrackEnded = event {
+            break;
+        }
+    }
+
+    let played = capture.samples().len() / 2;
+    // What the stretcher buffered at the end is played too
+    assert!(
+        played.abs_diff(frames / 2) <= 1,
+        "{played} frames instead of {}",
+        frames / 2
+    );
+}
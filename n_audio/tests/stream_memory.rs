@@ -0,0 +1,58 @@
+//! Checks that the memory used to read a track doesn't grow with the size of the file
+
+use std::fs;
+
+use n_audio::music_track::MusicTrack;
+
+use common::write_raw;
+
+mod common;
+
+/// Size of the file that gets read, in MiB
+const SIZE_MIB: u64 = 64;
+
+/// How much the peak memory may grow while reading it, in MiB
+const GROWTH_LIMIT_MIB: u64 = 16;
+
+/// Peak resident memory of the process, in KiB (only available on Linux)
+fn peak_memory() -> Option<u64> {
+    let status = fs::read_to_string("/proc/self/status").ok()?;
+    status
+        .lines()
+        .find(|line| line.starts_with("VmHWM:"))?
+        .split_whitespace()
+        .nth(1)?
+        .parse()
+        .ok()
+}
+
+#[test]
+fn large_file_is_streamed() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("large.rawf32");
+    // Stereo frames of 32-bit samples
+    write_raw(&path, (SIZE_MIB * 1024 * 1024 / 8) as usize);
+
+    let before = peak_memory();
+
+    let track = MusicTrack::new(path.to_str().unwrap()).unwrap();
+    let mut format = track.get_format().unwrap();
+    let mut bytes = 0;
+    while let Ok(packet) = format.next_packet() {
+        bytes += packet.buf().len() as u64;
+    }
+
+    let after = peak_memory();
+
+    assert_eq!(bytes / 1024 / 1024, SIZE_MIB);
+    match (before, after) {
+        (Some(before), Some(after)) => {
+            let grown = after.saturating_sub(before) / 1024;
+            assert!(
+                grown < GROWTH_LIMIT_MIB,
+                "memory grew by {grown} MiB while reading, the file doesn't look like it was streamed"
+            );
+        }
+        _ => eprintln!("Peak memory isn't available on this platform"),
+    }
+}
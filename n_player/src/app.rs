@@ -3,21 +3,25 @@ use crate::bus_server::linux::MPRISBridge;
 #[cfg(not(target_os = "linux"))]
 use crate::bus_server::DummyServer;
 use crate::localization::{get_locale_denominator, localize};
+use crate::loudness::analyzer;
 use crate::runner::{run, Runner, RunnerMessage, RunnerSeek};
 use crate::settings::Settings;
 use crate::{
-    add_all_tracks_to_player, bus_server, get_image, AppData, Localization, MainWindow,
-    SettingsData, Theme, TrackData, WindowSize,
+    add_all_tracks_to_player, bus_server, get_image, AppData, EqualizerPreset, Localization,
+    MainWindow, Normalization, SettingsData, Theme, TrackData, WindowSize, EQUALIZER_PRESETS,
 };
 use flume::{Receiver, Sender};
 use image::imageops::FilterType;
 use image::ImageFormat;
 #[cfg(target_os = "linux")]
 use mpris_server::Server;
+use n_audio::device::output_devices;
+use n_audio::equalizer::Band;
 use n_audio::music_track::MusicTrack;
 use n_audio::queue::QueuePlayer;
 use n_audio::remove_ext;
-use slint::{ComponentHandle, VecModel};
+use n_audio::visualizer::Analyzer;
+use slint::{ComponentHandle, Model, SharedString, VecModel};
 use std::cell::RefCell;
 use std::io::Cursor;
 use std::path::PathBuf;
@@ -26,6 +30,11 @@ use std::time::Duration;
 use tempfile::NamedTempFile;
 use tokio::sync::RwLock;
 
+/// How many bars the spectrum in the control panel has
+const SPECTRUM_BANDS: usize = 24;
+/// How many times per second the spectrum is updated
+const SPECTRUM_FPS: u32 = 30;
+
 pub async fn run_app() {
     let settings = Arc::new(RefCell::new(Settings::read_saved().await));
 
@@ -36,6 +45,27 @@ pub async fn run_app() {
     add_all_tracks_to_player(&mut player, settings.borrow().path.clone()).await;
     let len = player.len();
 
+    player
+        .set_crossfade(settings.borrow().crossfade)
+        .await
+        .unwrap();
+    player
+        .set_output_device(settings.borrow().output_device.clone())
+        .await
+        .unwrap();
+    player
+        .set_gain_mode(settings.borrow().normalization.into())
+        .await
+        .unwrap();
+    player
+        .set_preamp(settings.borrow().preamp as f32)
+        .await
+        .unwrap();
+    player
+        .set_equalizer(settings.borrow().equalizer_bands())
+        .await
+        .unwrap();
+
     let runner = Arc::new(RwLock::new(Runner::new(player)));
 
     let r = runner.clone();
@@ -60,8 +90,9 @@ pub async fn run_app() {
         let runner_future = tokio::task::spawn(run(r.clone(), rx));
         let bus_future = tokio::task::spawn(bus_server::run(server, r.clone(), tmp));
         let loader_future = tokio::task::spawn(loader(r.clone(), tx_l));
+        let analyzer_future = tokio::task::spawn(analyzer(r.clone()));
 
-        let _ = tokio::join!(runner_future, bus_future, loader_future);
+        let _ = tokio::join!(runner_future, bus_future, loader_future, analyzer_future);
     });
 
     let mut tracks = vec![];
@@ -69,6 +100,7 @@ pub async fn run_app() {
         let track_path = runner.read().await.get_path_for_file(i).await;
         tracks.push(TrackData {
             artist: Default::default(),
+            album: Default::default(),
             cover: Default::default(),
             time: Default::default(),
             title: remove_ext(track_path).into(),
@@ -87,6 +119,32 @@ pub async fn run_app() {
     settings_data.set_height(settings.borrow().window_size.height as f32);
     settings_data.set_save_window_size(settings.borrow().save_window_size);
     settings_data.set_current_path(settings.borrow().path.clone().into());
+    settings_data.set_crossfade(settings.borrow().crossfade as f32);
+    settings_data.set_normalization(i32::from(settings.borrow().normalization));
+    settings_data.set_preamp(settings.borrow().preamp as f32);
+    settings_data.set_equalizer_enabled(settings.borrow().equalizer_enabled);
+    settings_data.set_equalizer_gains(VecModel::from_slice(&settings.borrow().equalizer));
+    settings_data.set_builtin_equalizer_presets(EQUALIZER_PRESETS.len() as i32);
+    show_equalizer_presets(&settings_data, &settings.borrow());
+    let output_devices = output_devices()
+        .into_iter()
+        .map(|device| device.name)
+        .collect::<Vec<String>>();
+    let mut device_names = vec![main_window.global::<Localization>().get_default_device()];
+    device_names.extend(
+        output_devices
+            .iter()
+            .map(|name| SharedString::from(name.as_str())),
+    );
+    settings_data.set_output_devices(VecModel::from_slice(&device_names));
+    settings_data.set_output_device(
+        settings
+            .borrow()
+            .output_device
+            .as_ref()
+            .and_then(|name| output_devices.iter().position(|device| device == name))
+            .map_or(0, |index| index as i32 + 1),
+    );
 
     app_data.on_open_link(move |link| open::that(link.as_str()).unwrap());
     let s = settings.clone();
@@ -145,6 +203,118 @@ pub async fn run_app() {
     settings_data.on_set_path_callback(move |path| {
         s.borrow_mut().path = path.clone().into();
     });
+    let s = settings.clone();
+    let t = tx.clone();
+    settings_data.on_set_crossfade(move |crossfade| {
+        s.borrow_mut().crossfade = crossfade as f64;
+        t.send(RunnerMessage::SetCrossfade(crossfade as f64))
+            .unwrap();
+    });
+    let s = settings.clone();
+    let t = tx.clone();
+    settings_data.on_set_normalization(move |index| {
+        if let Ok(normalization) = Normalization::try_from(index) {
+            s.borrow_mut().normalization = normalization;
+            t.send(RunnerMessage::SetGainMode(normalization.into()))
+                .unwrap();
+        }
+    });
+    let s = settings.clone();
+    let t = tx.clone();
+    settings_data.on_set_preamp(move |preamp| {
+        s.borrow_mut().preamp = preamp as f64;
+        t.send(RunnerMessage::SetPreamp(preamp as f64)).unwrap();
+    });
+    let s = settings.clone();
+    let t = tx.clone();
+    settings_data.on_set_equalizer_enabled(move |enabled| {
+        s.borrow_mut().equalizer_enabled = enabled;
+        t.send(RunnerMessage::SetEqualizer(s.borrow().equalizer_bands()))
+            .unwrap();
+    });
+    let s = settings.clone();
+    let t = tx.clone();
+    let window = main_window.as_weak();
+    settings_data.on_set_equalizer_gain(move |index, gain| {
+        let index = index as usize;
+        s.borrow_mut().equalizer[index] = gain;
+        if s.borrow().equalizer_enabled {
+            t.send(RunnerMessage::SetEqualizerBand(
+                index,
+                Band::graphic(index, gain),
+            ))
+            .unwrap();
+        }
+        let window = window.unwrap();
+        show_equalizer_presets(&window.global::<SettingsData>(), &s.borrow());
+    });
+    let s = settings.clone();
+    let t = tx.clone();
+    let window = main_window.as_weak();
+    settings_data.on_apply_equalizer_preset(move |index| {
+        let Some(preset) = s
+            .borrow()
+            .all_equalizer_presets()
+            .get(index as usize)
+            .cloned()
+        else {
+            return;
+        };
+        s.borrow_mut().equalizer = preset.gains;
+        t.send(RunnerMessage::SetEqualizer(s.borrow().equalizer_bands()))
+            .unwrap();
+        // A new model makes the sliders take the new gains
+        let window = window.unwrap();
+        window
+            .global::<SettingsData>()
+            .set_equalizer_gains(VecModel::from_slice(&preset.gains));
+    });
+    let s = settings.clone();
+    let window = main_window.as_weak();
+    settings_data.on_save_equalizer_preset(move |name| {
+        let name = name.trim().to_string();
+        if name.is_empty() {
+            return;
+        }
+        let gains = s.borrow().equalizer;
+        let mut settings = s.borrow_mut();
+        match settings
+            .equalizer_presets
+            .iter_mut()
+            .find(|preset| preset.name == name)
+        {
+            Some(preset) => preset.gains = gains,
+            None => settings
+                .equalizer_presets
+                .push(EqualizerPreset { name, gains }),
+        }
+        let window = window.unwrap();
+        show_equalizer_presets(&window.global::<SettingsData>(), &settings);
+    });
+    let s = settings.clone();
+    let window = main_window.as_weak();
+    settings_data.on_delete_equalizer_preset(move |index| {
+        let Some(index) = (index as usize).checked_sub(EQUALIZER_PRESETS.len()) else {
+            return;
+        };
+        let mut settings = s.borrow_mut();
+        if index < settings.equalizer_presets.len() {
+            settings.equalizer_presets.remove(index);
+        }
+        let window = window.unwrap();
+        show_equalizer_presets(&window.global::<SettingsData>(), &settings);
+    });
+    let s = settings.clone();
+    let t = tx.clone();
+    settings_data.on_set_output_device(move |index| {
+        let device = if index > 0 {
+            output_devices.get(index as usize - 1).cloned()
+        } else {
+            None
+        };
+        s.borrow_mut().output_device = device.clone();
+        t.send(RunnerMessage::SetOutputDevice(device)).unwrap();
+    });
     let t = tx.clone();
     app_data.on_clicked(move |i| t.send(RunnerMessage::PlayTrack(i as usize)).unwrap());
     let t = tx.clone();
@@ -159,19 +329,30 @@ pub async fn run_app() {
             .unwrap()
     });
     let t = tx.clone();
+    app_data.on_toggle_loop(move || t.send(RunnerMessage::ToggleLoop).unwrap());
+    let t = tx.clone();
     app_data.on_set_volume(move |volume| t.send(RunnerMessage::SetVolume(volume as f64)).unwrap());
+    let t = tx.clone();
+    app_data.on_dismiss_error(move || t.send(RunnerMessage::DismissError).unwrap());
     let (tx_searching, rx_searching) = flume::unbounded();
     app_data.on_searching(move |searching| tx_searching.send(searching.to_string()).unwrap());
     let window = main_window.as_weak();
     let r = runner.clone();
+    let events = runner.write().await.subscribe();
     let updater = tokio::task::spawn(async move {
+        // The player state is updated as soon as something happens, the interval is for loading and searching
         let mut interval = tokio::time::interval(Duration::from_millis(250));
         let mut searching = String::new();
         let mut old_index = usize::MAX;
         let mut loaded = 0;
         let threshold = num_cpus::get() * 4;
         loop {
-            interval.tick().await;
+            tokio::select! {
+                _ = interval.tick() => {}
+                _ = events.recv_async() => {}
+            }
+            // Everything that happened in the meantime gets shown at once
+            while events.try_recv().is_ok() {}
             let guard = r.read().await;
             let mut index = guard.index();
             if index > len {
@@ -183,6 +364,8 @@ pub async fn run_app() {
             let time_float = time.position;
             let volume = guard.volume();
             let position = time.format_pos();
+            let (loop_start, loop_end) = guard.loop_points();
+            let error = guard.error().unwrap_or_default();
 
             let mut new_loaded = false;
             while let Ok(track_data) = rx_l.try_recv() {
@@ -225,6 +408,7 @@ pub async fn run_app() {
                         let search = searching.to_lowercase();
                         track.title.to_lowercase().contains(&search)
                             || track.artist.to_lowercase().contains(&search)
+                            || track.album.to_lowercase().contains(&search)
                     })
                     .collect();
             }
@@ -236,8 +420,11 @@ pub async fn run_app() {
                     app_data.set_position_time(position.into());
                     app_data.set_time(time_float as f32);
                     app_data.set_length(length as f32);
+                    app_data.set_loop_start(loop_start.map_or(-1.0, |start| start as f32));
+                    app_data.set_loop_end(loop_end.map_or(-1.0, |end| end as f32));
                     app_data.set_playback(playback);
                     app_data.set_volume(volume as f32);
+                    app_data.set_error(error.into());
 
                     if let Some(playing_track) = playing_track {
                         app_data.set_playing_track(playing_track);
@@ -260,6 +447,35 @@ pub async fn run_app() {
         }
     });
 
+    let window = main_window.as_weak();
+    let frames = Analyzer::new(runner.read().await.tap(), SPECTRUM_BANDS).spawn(SPECTRUM_FPS);
+    let visualizer = tokio::task::spawn(async move {
+        let mut was_silent = false;
+        while let Ok(frame) = frames.recv_async().await {
+            // Nothing changes while nothing is playing
+            let is_silent = frame.spectrum.iter().all(|&level| level == 0.0);
+            if is_silent && was_silent {
+                continue;
+            }
+            was_silent = is_silent;
+
+            window
+                .upgrade_in_event_loop(move |window| {
+                    let app_data = window.global::<AppData>();
+                    let spectrum = app_data.get_spectrum();
+                    // Changing the rows keeps the bars from being created again every frame
+                    if spectrum.row_count() == frame.spectrum.len() {
+                        for (index, level) in frame.spectrum.into_iter().enumerate() {
+                            spectrum.set_row_data(index, level);
+                        }
+                    } else {
+                        app_data.set_spectrum(VecModel::from_slice(&frame.spectrum));
+                    }
+                })
+                .unwrap();
+        }
+    });
+
     tokio::task::block_in_place(|| main_window.run().unwrap());
     settings.borrow_mut().volume = runner.read().await.volume();
     if settings.borrow().save_window_size {
@@ -271,9 +487,26 @@ pub async fn run_app() {
     }
 
     updater.abort();
+    visualizer.abort();
     future.abort();
     settings.borrow_mut().save().await;
 }
+/// Shows the names of the presets, selecting the one with the current gains if there's any
+fn show_equalizer_presets(settings_data: &SettingsData, settings: &Settings) {
+    let presets = settings.all_equalizer_presets();
+    let names = presets
+        .iter()
+        .map(|preset| SharedString::from(preset.name.as_str()))
+        .collect::<Vec<SharedString>>();
+    settings_data.set_equalizer_presets(VecModel::from_slice(&names));
+    settings_data.set_equalizer_preset(
+        presets
+            .iter()
+            .position(|preset| preset.gains == settings.equalizer)
+            .map_or(-1, |index| index as i32),
+    );
+}
+
 async fn loader_task(
     runner: Arc<RwLock<Runner>>,
     tx: Sender<Option<TrackData>>,
@@ -289,10 +522,13 @@ async fn loader_task(
             }
             let path = runner.read().await.get_path_for_file(index).await;
             if let Ok(track) = MusicTrack::new(path.to_string_lossy().to_string()) {
-                if let Ok(Ok(meta)) = tokio::task::spawn_blocking(move || track.get_meta()).await {
+                if let Ok(Ok(mut meta)) =
+                    tokio::task::spawn_blocking(move || track.get_meta()).await
+                {
                     let p = path.clone();
+                    let pictures = std::mem::take(&mut meta.pictures);
                     let image_path = if let Ok(mut image) =
-                        tokio::task::spawn_blocking(move || get_image(p)).await
+                        tokio::task::spawn_blocking(move || get_image(p, pictures)).await
                     {
                         if !image.is_empty() {
                             if let Err(e) = image::load_from_memory(&image)
@@ -333,12 +569,8 @@ async fn loader_task(
                     if let Err(e) = tx
                         .send_async(Some(TrackData {
                             artist: meta.artist.into(),
-                            time: format!(
-                                "{:02}:{:02}",
-                                (meta.time.length / 60.0).floor() as u64,
-                                meta.time.length.floor() as u64 % 60
-                            )
-                            .into(),
+                            album: meta.album.unwrap_or_default().into(),
+                            time: meta.time.format_len().into(),
                             cover: if image_path.exists() {
                                 slint::Image::load_from_path(&image_path).unwrap()
                             } else {
@@ -16,6 +16,9 @@ use std::sync::Arc;
 use tempfile::NamedTempFile;
 use tokio::sync::RwLock;
 
+const MINIMUM_RATE: PlaybackRate = 0.5;
+const MAXIMUM_RATE: PlaybackRate = 2.0;
+
 impl<T: PlayerInterface + 'static> BusServer for Server<T> {
     async fn properties_changed<P: IntoIterator<Item = Property>>(
         &self,
@@ -34,13 +37,14 @@ impl<T: PlayerInterface + 'static> BusServer for Server<T> {
 
                     meta.set_title(metadata.title);
                     meta.set_artist(metadata.artists);
-                    meta.set_length(Some(Time::from_secs(metadata.length as i64)));
+                    meta.set_length(metadata.length.map(|length| Time::from_secs(length as i64)));
                     meta.set_art_url(metadata.image_path);
                     meta.set_trackid(Some(ObjectPath::from_string_unchecked(metadata.id)));
 
                     mpris_server::Property::Metadata(meta)
                 }
                 Property::Volume(volume) => mpris_server::Property::Volume(volume),
+                Property::Rate(rate) => mpris_server::Property::Rate(rate),
             }),
         )
         .await
@@ -187,10 +191,19 @@ impl PlayerInterface for MPRISBridge {
     }
 
     async fn rate(&self) -> fdo::Result<PlaybackRate> {
-        Ok(1.0)
-    }
-
-    async fn set_rate(&self, _rate: PlaybackRate) -> zbus::Result<()> {
+        Ok(self.runner.read().await.playback_speed())
+    }
+
+    async fn set_rate(&self, rate: PlaybackRate) -> zbus::Result<()> {
+        // A rate of 0.0 is not a valid speed, clients should pause instead
+        if rate > 0.0 {
+            self.tx
+                .send_async(RunnerMessage::SetPlaybackSpeed(
+                    rate.clamp(MINIMUM_RATE, MAXIMUM_RATE),
+                ))
+                .await
+                .unwrap();
+        }
         Ok(())
     }
 
@@ -211,7 +224,8 @@ impl PlayerInterface for MPRISBridge {
         let track = MusicTrack::new(path_buf.to_str().unwrap())
             .expect("can't get track for currently playing song");
         let meta = track.get_meta();
-        let image = get_image(path_buf);
+        let pictures = meta.as_ref().map_or(vec![], |meta| meta.pictures.clone());
+        let image = get_image(path_buf, pictures);
         let mut tmp = NamedTempFile::new().expect("can't create tmp file for mpris bridge");
         let image_path = if image.is_empty() {
             None
@@ -234,9 +248,11 @@ impl PlayerInterface for MPRISBridge {
             } else {
                 Some(vec![meta.artist])
             });
-            metadata.set_length(Some(Time::from_millis(
-                (meta.time.length * 1000.0).floor() as i64
-            )));
+            metadata.set_length(
+                meta.time
+                    .is_length_known()
+                    .then(|| Time::from_millis((meta.time.length * 1000.0).floor() as i64)),
+            );
             metadata.set_trackid(Some(ObjectPath::from_static_str_unchecked("/n_music")));
             metadata.set_art_url(image_path);
         }
@@ -265,11 +281,11 @@ impl PlayerInterface for MPRISBridge {
     }
 
     async fn minimum_rate(&self) -> fdo::Result<PlaybackRate> {
-        Ok(1.0)
+        Ok(MINIMUM_RATE)
     }
 
     async fn maximum_rate(&self) -> fdo::Result<PlaybackRate> {
-        Ok(1.0)
+        Ok(MAXIMUM_RATE)
     }
 
     async fn can_go_next(&self) -> fdo::Result<bool> {
@@ -6,7 +6,6 @@ use std::io::{Seek, Write};
 use std::mem;
 use std::path::PathBuf;
 use std::sync::Arc;
-use std::time::Duration;
 use tempfile::NamedTempFile;
 use tokio::sync::RwLock;
 
@@ -28,12 +27,13 @@ pub enum Property {
     Playing(bool),
     Metadata(Metadata),
     Volume(f64),
+    Rate(f64),
 }
 
 pub struct Metadata {
     pub title: Option<String>,
     pub artists: Option<Vec<String>>,
-    pub length: f64,
+    pub length: Option<f64>,
     pub id: String,
     pub image_path: Option<String>,
 }
@@ -46,15 +46,17 @@ pub trait BusServer {
 }
 
 pub async fn run<B: BusServer>(server: B, runner: Arc<RwLock<Runner>>, mut tmp: NamedTempFile) {
-    let mut interval = tokio::time::interval(Duration::from_millis(250));
+    let events = runner.write().await.subscribe();
     let mut properties = vec![];
     let mut playback = false;
     let mut volume = 1.0;
+    let mut rate = 1.0;
     let mut index = runner.read().await.index();
     let path = runner.read().await.path();
 
-    loop {
-        interval.tick().await;
+    // The state is compared to the one last sent whenever the player does something
+    while events.recv_async().await.is_ok() {
+        while events.try_recv().is_ok() {}
         let guard = runner.read().await;
 
         if playback != guard.playback() {
@@ -65,6 +67,10 @@ pub async fn run<B: BusServer>(server: B, runner: Arc<RwLock<Runner>>, mut tmp:
             volume = guard.volume();
             properties.push(Property::Volume(volume))
         }
+        if rate != guard.playback_speed() {
+            rate = guard.playback_speed();
+            properties.push(Property::Rate(rate));
+        }
 
         if index != guard.index() {
             index = guard.index();
@@ -75,7 +81,8 @@ pub async fn run<B: BusServer>(server: B, runner: Arc<RwLock<Runner>>, mut tmp:
             let track = MusicTrack::new(path_buf.to_str().unwrap())
                 .expect("can't get track for currently playing song");
             let meta = track.get_meta();
-            let image = tokio::task::spawn_blocking(|| get_image(path_buf))
+            let pictures = meta.as_ref().map_or(vec![], |meta| meta.pictures.clone());
+            let image = tokio::task::spawn_blocking(|| get_image(path_buf, pictures))
                 .await
                 .unwrap();
             let image_path = if image.is_empty() {
@@ -99,7 +106,7 @@ pub async fn run<B: BusServer>(server: B, runner: Arc<RwLock<Runner>>, mut tmp:
                     } else {
                         Some(vec![meta.artist])
                     },
-                    length: meta.time.length,
+                    length: meta.time.is_length_known().then_some(meta.time.length),
                     image_path,
                 }));
             }
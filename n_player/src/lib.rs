@@ -1,7 +1,8 @@
 use bitcode::{Decode, Encode};
-use multitag::data::Picture;
+use multitag::data::Picture as TagPicture;
 use multitag::Tag;
 use n_audio::queue::QueuePlayer;
+use n_audio::{GainMode, Picture};
 use slint::private_unstable_api::re_exports::ColorScheme;
 use std::ffi::OsStr;
 use std::fmt::Debug;
@@ -12,6 +13,7 @@ slint::include_modules!();
 pub mod app;
 pub mod bus_server;
 pub mod localization;
+pub mod loudness;
 pub mod runner;
 pub mod settings;
 
@@ -32,14 +34,15 @@ fn android_main(app: slint::android::AndroidApp) {
         });
 }
 
-pub fn get_image<P: AsRef<Path> + Debug>(path: P) -> Vec<u8> {
+/// Returns the cover of the track at `path`, looking among `pictures` (as read by `MusicTrack::get_meta`) when multitag finds none
+pub fn get_image<P: AsRef<Path> + Debug>(path: P, mut pictures: Vec<Picture>) -> Vec<u8> {
     if let Ok(tag) = Tag::read_from_path(path.as_ref()) {
         if let Some(album) = tag.get_album_info() {
             if let Some(cover) = album.cover {
                 return cover.data;
             } else {
                 if let Tag::OpusTag { inner } = tag {
-                    let cover = inner.pictures().first().cloned().map(Picture::from);
+                    let cover = inner.pictures().first().cloned().map(TagPicture::from);
                     if let Some(cover) = cover {
                         return cover.data;
                     }
@@ -52,6 +55,14 @@ pub fn get_image<P: AsRef<Path> + Debug>(path: P) -> Vec<u8> {
         }
     }
 
+    // Formats multitag can't read (e.g. DCA) still have their pictures read by symphonia
+    if let Some(index) = pictures.iter().position(|picture| picture.front_cover) {
+        return pictures.swap_remove(index).data;
+    }
+    if let Some(picture) = pictures.pop() {
+        return picture.data;
+    }
+
     vec![]
 }
 
@@ -162,3 +173,79 @@ impl TryFrom<i32> for Theme {
         }
     }
 }
+
+/// Which gain is used to normalize the volume of the tracks, saved in the settings
+#[derive(Copy, Clone, Debug, Default, Decode, Encode)]
+pub enum Normalization {
+    #[default]
+    Off,
+    Track,
+    Album,
+}
+
+impl From<Normalization> for GainMode {
+    fn from(value: Normalization) -> Self {
+        match value {
+            Normalization::Off => GainMode::Off,
+            Normalization::Track => GainMode::Track,
+            Normalization::Album => GainMode::Album,
+        }
+    }
+}
+
+impl From<Normalization> for i32 {
+    fn from(value: Normalization) -> Self {
+        match value {
+            Normalization::Off => 0,
+            Normalization::Track => 1,
+            Normalization::Album => 2,
+        }
+    }
+}
+
+impl TryFrom<i32> for Normalization {
+    type Error = String;
+
+    fn try_from(value: i32) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Self::Off),
+            1 => Ok(Self::Track),
+            2 => Ok(Self::Album),
+            _ => Err(format!("{value} is not a valid normalization")),
+        }
+    }
+}
+
+/// A named set of gains for the 10 bands of the equalizer
+#[derive(Clone, Debug, PartialEq, Decode, Encode)]
+pub struct EqualizerPreset {
+    pub name: String,
+    pub gains: [f32; 10],
+}
+
+/// Presets that come with the app, the ones made by the user are saved in the settings
+pub const EQUALIZER_PRESETS: [(&str, [f32; 10]); 8] = [
+    ("Flat", [0.0; 10]),
+    (
+        "Bass Boost",
+        [6.0, 5.0, 4.0, 2.0, 0.5, 0.0, 0.0, 0.0, 0.0, 0.0],
+    ),
+    (
+        "Treble Boost",
+        [0.0, 0.0, 0.0, 0.0, 0.0, 0.5, 2.0, 4.0, 5.0, 6.0],
+    ),
+    (
+        "Vocal",
+        [-2.0, -2.0, -1.0, 0.0, 2.0, 4.0, 4.0, 2.0, 0.0, -1.0],
+    ),
+    ("Rock", [4.0, 3.0, 2.0, 0.0, -1.0, -1.0, 1.0, 2.0, 3.0, 4.0]),
+    ("Pop", [-1.0, 0.0, 2.0, 3.0, 4.0, 3.0, 1.0, 0.0, -1.0, -1.0]),
+    (
+        "Classical",
+        [3.0, 2.0, 1.0, 0.0, 0.0, 0.0, -1.0, 0.0, 2.0, 3.0],
+    ),
+    (
+        "Loudness",
+        [6.0, 4.0, 2.0, 0.0, -1.0, 0.0, 0.0, 2.0, 4.0, 5.0],
+    ),
+];
@@ -17,6 +17,22 @@ pub struct Locale {
     theme_dark: Option<String>,
     credits: Option<String>,
     license: Option<String>,
+    crossfade: Option<String>,
+    output_device: Option<String>,
+    default_device: Option<String>,
+    playback_error: Option<String>,
+    dismiss: Option<String>,
+    normalization: Option<String>,
+    normalization_off: Option<String>,
+    normalization_track: Option<String>,
+    normalization_album: Option<String>,
+    preamp: Option<String>,
+    equalizer: Option<String>,
+    equalizer_preset: Option<String>,
+    save_preset: Option<String>,
+    preset_name: Option<String>,
+    save: Option<String>,
+    delete: Option<String>,
 }
 
 pub fn localize(denominator: Option<String>, localization: Localization) {
@@ -116,6 +132,118 @@ pub fn localize(denominator: Option<String>, localization: Localization) {
             .unwrap_or(english.license.as_ref().unwrap())
             .into(),
     );
+    localization.set_crossfade(
+        locale
+            .crossfade
+            .as_ref()
+            .unwrap_or(english.crossfade.as_ref().unwrap())
+            .into(),
+    );
+    localization.set_output_device(
+        locale
+            .output_device
+            .as_ref()
+            .unwrap_or(english.output_device.as_ref().unwrap())
+            .into(),
+    );
+    localization.set_default_device(
+        locale
+            .default_device
+            .as_ref()
+            .unwrap_or(english.default_device.as_ref().unwrap())
+            .into(),
+    );
+    localization.set_playback_error(
+        locale
+            .playback_error
+            .as_ref()
+            .unwrap_or(english.playback_error.as_ref().unwrap())
+            .into(),
+    );
+    localization.set_dismiss(
+        locale
+            .dismiss
+            .as_ref()
+            .unwrap_or(english.dismiss.as_ref().unwrap())
+            .into(),
+    );
+    localization.set_normalization(
+        locale
+            .normalization
+            .as_ref()
+            .unwrap_or(english.normalization.as_ref().unwrap())
+            .into(),
+    );
+    localization.set_normalization_off(
+        locale
+            .normalization_off
+            .as_ref()
+            .unwrap_or(english.normalization_off.as_ref().unwrap())
+            .into(),
+    );
+    localization.set_normalization_track(
+        locale
+            .normalization_track
+            .as_ref()
+            .unwrap_or(english.normalization_track.as_ref().unwrap())
+            .into(),
+    );
+    localization.set_normalization_album(
+        locale
+            .normalization_album
+            .as_ref()
+            .unwrap_or(english.normalization_album.as_ref().unwrap())
+            .into(),
+    );
+    localization.set_preamp(
+        locale
+            .preamp
+            .as_ref()
+            .unwrap_or(english.preamp.as_ref().unwrap())
+            .into(),
+    );
+    localization.set_equalizer(
+        locale
+            .equalizer
+            .as_ref()
+            .unwrap_or(english.equalizer.as_ref().unwrap())
+            .into(),
+    );
+    localization.set_equalizer_preset(
+        locale
+            .equalizer_preset
+            .as_ref()
+            .unwrap_or(english.equalizer_preset.as_ref().unwrap())
+            .into(),
+    );
+    localization.set_save_preset(
+        locale
+            .save_preset
+            .as_ref()
+            .unwrap_or(english.save_preset.as_ref().unwrap())
+            .into(),
+    );
+    localization.set_preset_name(
+        locale
+            .preset_name
+            .as_ref()
+            .unwrap_or(english.preset_name.as_ref().unwrap())
+            .into(),
+    );
+    localization.set_save(
+        locale
+            .save
+            .as_ref()
+            .unwrap_or(english.save.as_ref().unwrap())
+            .into(),
+    );
+    localization.set_delete(
+        locale
+            .delete
+            .as_ref()
+            .unwrap_or(english.delete.as_ref().unwrap())
+            .into(),
+    );
 }
 
 pub fn get_locale_name(denominator: Option<&str>) -> &str {
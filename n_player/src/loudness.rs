@@ -0,0 +1,224 @@
+use crate::runner::Runner;
+use crate::settings::Settings;
+use bitcode::{Decode, Encode};
+use n_audio::loudness;
+use n_audio::music_track::MusicTrack;
+use n_audio::{NError, ReplayGain};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::UNIX_EPOCH;
+use tokio::sync::RwLock;
+
+/// Gains computed for the tracks missing gain tags, saved next to the settings so that they're computed only once
+#[derive(Debug, Default, Decode, Encode)]
+pub struct GainCache {
+    tracks: HashMap<String, CachedGain>,
+}
+
+#[derive(Debug, Decode, Encode)]
+struct CachedGain {
+    stamp: Stamp,
+    track_gain: Option<f32>,
+    track_peak: Option<f32>,
+    album_gain: Option<f32>,
+    album_peak: Option<f32>,
+}
+
+/// When the file was last modified and how big it is, to know if it changed after its gain was computed
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Decode, Encode)]
+struct Stamp {
+    modified: u64,
+    size: u64,
+}
+
+impl Stamp {
+    async fn of(path: &str) -> Option<Self> {
+        let metadata = tokio::fs::metadata(path).await.ok()?;
+        let modified = metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+        Some(Stamp {
+            modified: modified.as_secs(),
+            size: metadata.len(),
+        })
+    }
+}
+
+impl GainCache {
+    fn file() -> PathBuf {
+        Settings::app_dir().join("gains")
+    }
+
+    pub async fn read_saved() -> Self {
+        if cfg!(target_os = "android") {
+            return Self::default();
+        }
+        match tokio::fs::read(Self::file()).await {
+            Ok(content) => bitcode::decode(&content).unwrap_or_default(),
+            Err(_) => Self::default(),
+        }
+    }
+
+    pub async fn save(&self) {
+        if cfg!(not(target_os = "android")) {
+            if let Err(e) = tokio::fs::write(Self::file(), bitcode::encode(self)).await {
+                eprintln!("error happened during gain cache writing: {e}");
+            }
+        }
+    }
+
+    fn get(&self, path: &str, stamp: Stamp) -> Option<ReplayGain> {
+        let cached = self
+            .tracks
+            .get(path)
+            .filter(|cached| cached.stamp == stamp)?;
+        Some(ReplayGain {
+            track_gain: cached.track_gain,
+            track_peak: cached.track_peak,
+            album_gain: cached.album_gain,
+            album_peak: cached.album_peak,
+        })
+    }
+
+    fn insert(&mut self, path: String, stamp: Stamp, gain: ReplayGain) {
+        self.tracks.insert(
+            path,
+            CachedGain {
+                stamp,
+                track_gain: gain.track_gain,
+                track_peak: gain.track_peak,
+                album_gain: gain.album_gain,
+                album_peak: gain.album_peak,
+            },
+        );
+    }
+}
+
+/// A track of the library, with the gains its tags already have
+struct Found {
+    path: String,
+    stamp: Stamp,
+    tagged: ReplayGain,
+}
+
+impl Found {
+    /// Whether the tags have every gain it needs, so that nothing has to be computed for it
+    fn is_tagged(&self, is_album: bool) -> bool {
+        self.tagged.track_gain.is_some() && (!is_album || self.tagged.album_gain.is_some())
+    }
+}
+
+/// Gives the tracks the gains computed in the previous runs, then computes the gains the tags miss
+/// Tracks are analyzed an album at a time, so that they get their album gain too
+pub async fn analyzer(runner: Arc<RwLock<Runner>>) {
+    let mut cache = GainCache::read_saved().await;
+    let len = runner.read().await.len();
+
+    // Tagged tracks are grouped too, as they count for the album gain of the others
+    let mut albums: HashMap<(String, String), Vec<Found>> = HashMap::new();
+    let mut singles = vec![];
+    for index in 0..len {
+        let path = runner.read().await.get_path_for_file(index).await;
+        let path = path.to_string_lossy().to_string();
+        let Some(stamp) = Stamp::of(&path).await else {
+            continue;
+        };
+        let Ok(track) = MusicTrack::new(path.clone()) else {
+            continue;
+        };
+        let Ok(Ok(meta)) = tokio::task::spawn_blocking(move || track.get_meta()).await else {
+            continue;
+        };
+        let found = Found {
+            path,
+            stamp,
+            tagged: meta.replay_gain,
+        };
+        match meta.album {
+            Some(album) => albums
+                .entry((meta.album_artist.unwrap_or(meta.artist), album))
+                .or_default()
+                .push(found),
+            None => singles.push(found),
+        }
+    }
+
+    let groups = albums
+        .into_values()
+        .map(|tracks| (tracks, true))
+        .chain(singles.into_iter().map(|track| (vec![track], false)));
+    for (tracks, is_album) in groups {
+        let cached: Vec<Option<ReplayGain>> = tracks
+            .iter()
+            .map(|track| cache.get(&track.path, track.stamp))
+            .collect();
+        let known = tracks
+            .iter()
+            .zip(&cached)
+            .all(|(track, cached)| cached.is_some() || track.is_tagged(is_album));
+        if known {
+            for (track, gain) in tracks.iter().zip(cached) {
+                let Some(gain) = gain else {
+                    continue;
+                };
+                if let Ok(music_track) = MusicTrack::new(track.path.clone()) {
+                    music_track.set_replay_gain(gain);
+                }
+            }
+            continue;
+        }
+
+        let paths: Vec<String> = tracks.iter().map(|track| track.path.clone()).collect();
+        let gains = tokio::task::spawn_blocking(move || analyze(&paths, is_album)).await;
+        let gains = match gains {
+            Ok(Ok(gains)) => gains,
+            Ok(Err(e)) => {
+                eprintln!("error happened during loudness analysis: {e}");
+                continue;
+            }
+            Err(_) => continue,
+        };
+
+        for (track, gain) in tracks.into_iter().zip(gains) {
+            // A track that couldn't be analyzed is cached without gains, so that it's only tried again once it changed
+            let gain = gain.map_or_else(ReplayGain::default, |gain| untagged(gain, track.tagged));
+            if let Ok(music_track) = MusicTrack::new(track.path.clone()) {
+                music_track.set_replay_gain(gain);
+            }
+            cache.insert(track.path, track.stamp, gain);
+        }
+        cache.save().await;
+    }
+}
+
+/// Keeps only the computed gains the tags don't have, the tagged ones are left as they are
+fn untagged(computed: ReplayGain, tagged: ReplayGain) -> ReplayGain {
+    ReplayGain {
+        track_gain: computed.track_gain.filter(|_| tagged.track_gain.is_none()),
+        track_peak: computed.track_peak.filter(|_| tagged.track_peak.is_none()),
+        album_gain: computed.album_gain.filter(|_| tagged.album_gain.is_none()),
+        album_peak: computed.album_peak.filter(|_| tagged.album_peak.is_none()),
+    }
+}
+
+/// Returns the gains of the tracks at `paths`, `None` for the ones that couldn't be analyzed
+fn analyze(paths: &[String], is_album: bool) -> Result<Vec<Option<ReplayGain>>, NError> {
+    // A track that can't be opened is skipped, the others still get their gains
+    let (indices, tracks): (Vec<usize>, Vec<MusicTrack>) = paths
+        .iter()
+        .enumerate()
+        .filter_map(|(index, path)| Some((index, MusicTrack::new(path.clone()).ok()?)))
+        .unzip();
+    let mut gains = vec![None; paths.len()];
+    if !is_album {
+        for (index, track) in indices.into_iter().zip(&tracks) {
+            gains[index] = Some(loudness::analyze_track(track)?.replay_gain());
+        }
+        return Ok(gains);
+    }
+
+    let loudness = loudness::analyze_album(&tracks)?;
+    for (position, index) in indices.into_iter().enumerate() {
+        gains[index] = loudness.replay_gain(position);
+    }
+    Ok(gains)
+}
@@ -1,25 +1,27 @@
-use flume::Receiver;
+use flume::{Receiver, Sender};
+use n_audio::equalizer::Band;
 use n_audio::queue::QueuePlayer;
-use n_audio::TrackTime;
+use n_audio::visualizer::AudioTap;
+use n_audio::{GainMode, LoopRegion, NError, PlayerEvent, Time, TrackTime};
 use std::fs::File;
 use std::io::BufReader;
 use std::path::PathBuf;
 use std::sync::Arc;
-use std::time::Duration;
 use tokio::sync::RwLock;
 
 pub async fn run(runner: Arc<RwLock<Runner>>, rx: Receiver<RunnerMessage>) {
-    let mut interval = tokio::time::interval(Duration::from_millis(500));
+    let events = runner.read().await.player.subscribe();
     loop {
         tokio::select! {
-            _ = interval.tick() => {
-                runner.write().await.update().await;
+            event = events.recv_async() => {
+                if let Ok(event) = event {
+                    runner.write().await.handle_event(event).await;
+                }
             }
             message = rx.recv_async() => {
                 if let Ok(message) = message {
                     runner.write().await.parse_command(message).await;
                 }
-                runner.write().await.update().await;
             }
         }
     }
@@ -33,8 +35,18 @@ pub enum RunnerMessage {
     Pause,
     Play,
     SetVolume(f64),
+    SetCrossfade(f64),
+    SetPlaybackSpeed(f64),
+    SetOutputDevice(Option<String>),
+    SetGainMode(GainMode),
+    SetPreamp(f64),
+    SetEqualizer(Vec<Band>),
+    SetEqualizerBand(usize, Band),
     PlayTrack(usize),
     Seek(RunnerSeek),
+    /// Marks the start of the loop, then its end, then clears it
+    ToggleLoop,
+    DismissError,
 }
 
 #[derive(Debug)]
@@ -46,6 +58,14 @@ pub enum RunnerSeek {
 pub struct Runner {
     player: QueuePlayer,
     current_time: TrackTime,
+    /// Where the loop starts, while its end hasn't been marked yet
+    loop_start: Option<f64>,
+    ab_loop: Option<LoopRegion>,
+    /// The last error that happened while playing, until it's dismissed
+    error: Option<String>,
+    /// Whether the last error was about the audio output, without which no other track would play either
+    output_failed: bool,
+    listeners: Vec<Sender<PlayerEvent>>,
 }
 
 impl Runner {
@@ -53,19 +73,58 @@ impl Runner {
         Self {
             player,
             current_time: TrackTime::default(),
+            loop_start: None,
+            ab_loop: None,
+            error: None,
+            output_failed: false,
+            listeners: vec![],
         }
     }
 
-    pub async fn update(&mut self) {
-        if let Some(time) = self.player.get_time() {
-            self.current_time = time;
-        }
+    /// Returns a stream of the player events, each sent once the runner is up to date with it
+    pub fn subscribe(&mut self) -> Receiver<PlayerEvent> {
+        let (tx, rx) = flume::unbounded();
+        self.listeners.push(tx);
+        rx
+    }
+
+    /// Returns a tap on the audio being played, for the visualizer
+    pub fn tap(&self) -> AudioTap {
+        self.player.tap()
+    }
 
-        if self.player.has_ended() {
-            if let Err(err) = self.player.play_next().await {
-                eprintln!("error happened: {err}");
+    async fn handle_event(&mut self, event: PlayerEvent) {
+        match &event {
+            PlayerEvent::Position(time) => self.current_time = *time,
+            PlayerEvent::TrackStarted => {
+                self.output_failed = false;
+                self.loop_start = None;
+                self.ab_loop = None;
+                if let Err(err) = self.player.update().await {
+                    self.set_error(&err);
+                }
             }
+            PlayerEvent::TrackEnded => {
+                if self.player.has_ended() && !self.output_failed {
+                    if let Err(err) = self.player.play_next().await {
+                        self.set_error(&err);
+                    }
+                }
+            }
+            PlayerEvent::LoopChanged(region) => self.ab_loop = *region,
+            PlayerEvent::Error(err) => {
+                self.output_failed = matches!(**err, NError::Output(_));
+                self.set_error(err);
+            }
+            _ => {}
         }
+
+        self.listeners.retain(|tx| tx.send(event.clone()).is_ok());
+    }
+
+    fn set_error(&mut self, err: &NError) {
+        eprintln!("error happened: {err}");
+        self.error = Some(err.to_string());
     }
 
     async fn parse_command(&mut self, message: RunnerMessage) {
@@ -74,7 +133,7 @@ impl Runner {
             RunnerMessage::PlayNext => {
                 self.player.end_current().await.unwrap();
                 if let Err(err) = self.player.play_next().await {
-                    eprintln!("error happened: {err}");
+                    self.set_error(&err);
                 }
             }
             RunnerMessage::PlayPrevious => {
@@ -83,7 +142,7 @@ impl Runner {
                 } else {
                     self.player.end_current().await.unwrap();
                     if let Err(err) = self.player.play_previous().await {
-                        eprintln!("error happened: {err}");
+                        self.set_error(&err);
                     }
                 }
             }
@@ -95,7 +154,7 @@ impl Runner {
                 }
                 if !self.player.is_playing() {
                     if let Err(err) = self.player.play_next().await {
-                        eprintln!("error happened: {err}");
+                        self.set_error(&err);
                     }
                 }
             }
@@ -106,17 +165,38 @@ impl Runner {
                 self.player.unpause().await.unwrap();
                 if !self.player.is_playing() {
                     if let Err(err) = self.player.play_next().await {
-                        eprintln!("error happened: {err}");
+                        self.set_error(&err);
                     }
                 }
             }
             RunnerMessage::SetVolume(volume) => {
                 self.player.set_volume(volume as f32).await.unwrap();
             }
+            RunnerMessage::SetCrossfade(crossfade) => {
+                self.player.set_crossfade(crossfade).await.unwrap();
+            }
+            RunnerMessage::SetPlaybackSpeed(speed) => {
+                self.player.set_playback_speed(speed as f32).await.unwrap();
+            }
+            RunnerMessage::SetOutputDevice(device) => {
+                self.player.set_output_device(device).await.unwrap();
+            }
+            RunnerMessage::SetGainMode(mode) => {
+                self.player.set_gain_mode(mode).await.unwrap();
+            }
+            RunnerMessage::SetPreamp(preamp) => {
+                self.player.set_preamp(preamp as f32).await.unwrap();
+            }
+            RunnerMessage::SetEqualizer(bands) => {
+                self.player.set_equalizer(bands).await.unwrap();
+            }
+            RunnerMessage::SetEqualizerBand(index, band) => {
+                self.player.set_equalizer_band(index, band).await.unwrap();
+            }
             RunnerMessage::PlayTrack(index) => {
                 self.player.end_current().await.unwrap();
                 if let Err(err) = self.player.play_index(index).await {
-                    eprintln!("error happened: {err}");
+                    self.set_error(&err);
                 }
             }
             RunnerMessage::Seek(seek) => {
@@ -128,6 +208,25 @@ impl Runner {
                     eprintln!("error happened while asking to seek: {e}");
                 }
             }
+            RunnerMessage::ToggleLoop => {
+                let position = self.current_time.position;
+                if self.ab_loop.is_some() {
+                    self.ab_loop = None;
+                    self.player.clear_loop().await.unwrap();
+                } else if let Some(start) = self.loop_start.take() {
+                    // The end can be marked before the start
+                    let (start, end) = (start.min(position), start.max(position));
+                    if end > start {
+                        self.player
+                            .set_loop(Time::from(start), Time::from(end), None)
+                            .await
+                            .unwrap();
+                    }
+                } else {
+                    self.loop_start = Some(position);
+                }
+            }
+            RunnerMessage::DismissError => self.error = None,
         }
     }
 
@@ -139,10 +238,33 @@ impl Runner {
         self.player.get_volume() as f64
     }
 
+    pub fn crossfade(&self) -> f64 {
+        self.player.get_crossfade()
+    }
+
+    pub fn playback_speed(&self) -> f64 {
+        self.player.get_playback_speed() as f64
+    }
+
+    pub fn error(&self) -> Option<String> {
+        self.error.clone()
+    }
+
     pub fn time(&self) -> TrackTime {
         self.current_time
     }
 
+    /// Returns the start and the end of the loop in seconds, the end is `None` while it's being marked
+    pub fn loop_points(&self) -> (Option<f64>, Option<f64>) {
+        match self.ab_loop {
+            Some(region) => (
+                Some(region.start.seconds as f64 + region.start.frac),
+                Some(region.end.seconds as f64 + region.end.frac),
+            ),
+            None => (self.loop_start, None),
+        }
+    }
+
     pub fn path(&self) -> String {
         self.player.path()
     }
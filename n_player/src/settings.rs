@@ -1,5 +1,6 @@
-use crate::{Theme, WindowSize};
+use crate::{EqualizerPreset, Normalization, Theme, WindowSize, EQUALIZER_PRESETS};
 use bitcode::{Decode, Encode};
+use n_audio::equalizer::Band;
 use std::fs;
 use std::path::PathBuf;
 
@@ -11,6 +12,15 @@ pub struct Settings {
     pub window_size: WindowSize,
     pub save_window_size: bool,
     pub locale: Option<String>,
+    pub crossfade: f64,
+    pub output_device: Option<String>,
+    pub normalization: Normalization,
+    pub preamp: f64,
+    pub equalizer_enabled: bool,
+    /// Gains in dB of the bands, from the lowest frequency
+    pub equalizer: [f32; 10],
+    /// Presets saved by the user
+    pub equalizer_presets: Vec<EqualizerPreset>,
 }
 
 impl Settings {
@@ -22,16 +32,29 @@ impl Settings {
         };
         if storage_file.exists() && storage_file.is_file() {
             let storage_content = tokio::fs::read(storage_file).await.unwrap();
-            if let Ok(storage) = bitcode::decode(&storage_content) {
-                storage
-            } else {
-                Self::default()
-            }
+            Self::decode(&storage_content).unwrap_or_default()
         } else {
             Self::default()
         }
     }
 
+    /// Decodes the saved settings, migrating the ones saved by older versions
+    fn decode(content: &[u8]) -> Option<Self> {
+        if let Ok(settings) = bitcode::decode(content) {
+            return Some(settings);
+        }
+        if let Ok(settings) = bitcode::decode::<SettingsV4>(content) {
+            return Some(settings.into());
+        }
+        if let Ok(settings) = bitcode::decode::<SettingsV3>(content) {
+            return Some(settings.into());
+        }
+        if let Ok(settings) = bitcode::decode::<SettingsV2>(content) {
+            return Some(settings.into());
+        }
+        bitcode::decode::<SettingsV1>(content).ok().map(Self::from)
+    }
+
     pub fn app_dir() -> PathBuf {
         let base_dirs = directories::BaseDirs::new().unwrap();
         let local_data_dir = base_dirs.data_local_dir();
@@ -57,6 +80,27 @@ impl Settings {
         PathBuf::new()
     }
 
+    /// Returns the presets that come with the app followed by the ones saved by the user
+    pub fn all_equalizer_presets(&self) -> Vec<EqualizerPreset> {
+        EQUALIZER_PRESETS
+            .iter()
+            .map(|(name, gains)| EqualizerPreset {
+                name: name.to_string(),
+                gains: *gains,
+            })
+            .chain(self.equalizer_presets.iter().cloned())
+            .collect()
+    }
+
+    /// Returns the bands the player should use, none if the equalizer is off
+    pub fn equalizer_bands(&self) -> Vec<Band> {
+        if self.equalizer_enabled {
+            Band::graphic_bands(&self.equalizer)
+        } else {
+            vec![]
+        }
+    }
+
     pub async fn save(&self) {
         if cfg!(not(target_os = "android")) {
             let storage_file = Self::app_dir().join("config");
@@ -76,6 +120,176 @@ impl Default for Settings {
             window_size: WindowSize::default(),
             save_window_size: false,
             locale: None,
+            crossfade: 0.0,
+            output_device: None,
+            normalization: Normalization::default(),
+            preamp: 0.0,
+            equalizer_enabled: false,
+            equalizer: [0.0; 10],
+            equalizer_presets: vec![],
         }
     }
 }
+
+/// The layouts older versions saved the settings with, each one being the previous with the fields added after it
+/// When a field is added to `Settings`, its current layout goes here so that the saved settings aren't lost
+#[derive(Decode, Encode)]
+struct SettingsV1 {
+    path: String,
+    volume: f64,
+    theme: Theme,
+    window_size: WindowSize,
+    save_window_size: bool,
+    locale: Option<String>,
+}
+
+#[derive(Decode, Encode)]
+struct SettingsV2 {
+    v1: SettingsV1,
+    crossfade: f64,
+}
+
+#[derive(Decode, Encode)]
+struct SettingsV3 {
+    v2: SettingsV2,
+    output_device: Option<String>,
+}
+
+#[derive(Decode, Encode)]
+struct SettingsV4 {
+    v3: SettingsV3,
+    normalization: Normalization,
+    preamp: f64,
+}
+
+impl From<SettingsV1> for Settings {
+    fn from(value: SettingsV1) -> Self {
+        Self {
+            path: value.path,
+            volume: value.volume,
+            theme: value.theme,
+            window_size: value.window_size,
+            save_window_size: value.save_window_size,
+            locale: value.locale,
+            ..Default::default()
+        }
+    }
+}
+
+impl From<SettingsV2> for Settings {
+    fn from(value: SettingsV2) -> Self {
+        Self {
+            crossfade: value.crossfade,
+            ..value.v1.into()
+        }
+    }
+}
+
+impl From<SettingsV3> for Settings {
+    fn from(value: SettingsV3) -> Self {
+        Self {
+            output_device: value.output_device,
+            ..value.v2.into()
+        }
+    }
+}
+
+impl From<SettingsV4> for Settings {
+    fn from(value: SettingsV4) -> Self {
+        Self {
+            normalization: value.normalization,
+            preamp: value.preamp,
+            ..value.v3.into()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn v1() -> SettingsV1 {
+        SettingsV1 {
+            path: String::from("/music"),
+            volume: 0.5,
+            theme: Theme::Dark,
+            window_size: WindowSize {
+                width: 800,
+                height: 600,
+            },
+            save_window_size: true,
+            locale: Some(String::from("it")),
+        }
+    }
+
+    fn v2() -> SettingsV2 {
+        SettingsV2 {
+            v1: v1(),
+            crossfade: 3.0,
+        }
+    }
+
+    fn v3() -> SettingsV3 {
+        SettingsV3 {
+            v2: v2(),
+            output_device: Some(String::from("speakers")),
+        }
+    }
+
+    fn v4() -> SettingsV4 {
+        SettingsV4 {
+            v3: v3(),
+            normalization: Normalization::Album,
+            preamp: -6.0,
+        }
+    }
+
+    fn assert_v1_kept(settings: &Settings) {
+        assert_eq!(settings.path, "/music");
+        assert_eq!(settings.volume, 0.5);
+        assert!(matches!(settings.theme, Theme::Dark));
+        assert_eq!(settings.window_size.width, 800);
+        assert_eq!(settings.window_size.height, 600);
+        assert!(settings.save_window_size);
+        assert_eq!(settings.locale.as_deref(), Some("it"));
+    }
+
+    #[test]
+    fn first_layout_is_migrated() {
+        let settings = Settings::decode(&bitcode::encode(&v1())).unwrap();
+
+        assert_v1_kept(&settings);
+        assert_eq!(settings.crossfade, 0.0);
+    }
+
+    #[test]
+    fn layout_with_the_crossfade_is_migrated() {
+        let settings = Settings::decode(&bitcode::encode(&v2())).unwrap();
+
+        assert_v1_kept(&settings);
+        assert_eq!(settings.crossfade, 3.0);
+        assert!(settings.output_device.is_none());
+    }
+
+    #[test]
+    fn layout_with_the_output_device_is_migrated() {
+        let settings = Settings::decode(&bitcode::encode(&v3())).unwrap();
+
+        assert_v1_kept(&settings);
+        assert_eq!(settings.crossfade, 3.0);
+        assert_eq!(settings.output_device.as_deref(), Some("speakers"));
+        assert!(matches!(settings.normalization, Normalization::Off));
+    }
+
+    #[test]
+    fn layout_with_the_normalization_is_migrated() {
+        let settings = Settings::decode(&bitcode::encode(&v4())).unwrap();
+
+        assert_v1_kept(&settings);
+        assert_eq!(settings.crossfade, 3.0);
+        assert_eq!(settings.output_device.as_deref(), Some("speakers"));
+        assert!(matches!(settings.normalization, Normalization::Album));
+        assert_eq!(settings.preamp, -6.0);
+        assert!(!settings.equalizer_enabled);
+    }
+}